@@ -1,38 +1,71 @@
+use std::fmt::Write;
+
 fn main() {
     let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
 
-    println!("cargo:rerun-if-changed=./support.rs");
-    let o = std::process::Command::new("rustc")
-        .args(&[
-            "-O",
-            "./support.rs",
-            "-o",
-            out_dir.join("libsupport.a").to_str().unwrap(),
-            "--print",
-            "native-static-libs",
-        ])
-        .output()
-        .unwrap();
+    println!("cargo:rerun-if-changed=./runtime/src/lib.rs");
+    println!("cargo:rerun-if-env-changed=SCRATCHC_TARGETS");
+
+    // The runtime is a crate of its own for the interpreter, but compiled
+    // projects link it as a static library, built for the machine scratchc
+    // runs on and any others it should be able to cross compile to.
+    let host = std::env::var("TARGET").unwrap();
+    let mut triples = vec![host.clone()];
+    if let Ok(targets) = std::env::var("SCRATCHC_TARGETS") {
+        for triple in targets.split(',') {
+            if !triple.is_empty() && !triples.iter().any(|t| t == triple) {
+                triples.push(triple.to_owned());
+            }
+        }
+    }
 
-    if !o.status.success() {
-        panic!("{}", String::from_utf8(o.stderr).unwrap());
+    let mut libraries = "&[\n".to_owned();
+    for triple in &triples {
+        let lib = out_dir.join(format!("libsupport-{}.a", triple));
+        let o = std::process::Command::new("rustc")
+            .args([
+                "-O",
+                "./runtime/src/lib.rs",
+                "--crate-type",
+                "staticlib",
+                "--crate-name",
+                "support",
+                "--edition",
+                "2018",
+                "--target",
+                triple,
+                "-o",
+                lib.to_str().unwrap(),
+                "--print",
+                "native-static-libs",
+            ])
+            .output()
+            .unwrap();
+
+        if !o.status.success() {
+            panic!("{}", String::from_utf8(o.stderr).unwrap());
+        }
+
+        let stderr = String::from_utf8(o.stderr).unwrap();
+        let static_libs: Vec<String> = stderr
+            .lines()
+            .find(|l| l.starts_with("note: native-static-libs:"))
+            .unwrap()
+            .split(": ")
+            .nth(2)
+            .unwrap()
+            .split(' ')
+            .map(|s| s.to_owned())
+            .collect();
+
+        writeln!(
+            libraries,
+            "    ({:?}, include_bytes!({:?}), &{:?}),",
+            triple, lib, static_libs
+        )
+        .unwrap();
     }
+    libraries.push(']');
 
-    let stderr = String::from_utf8(o.stderr).unwrap();
-    let static_libs: Vec<String> = stderr
-        .lines()
-        .find(|l| l.starts_with("note: native-static-libs:"))
-        .unwrap()
-        .split(": ")
-        .nth(2)
-        .unwrap()
-        .split(' ')
-        .map(|s| s.to_owned())
-        .collect();
-
-    std::fs::write(
-        out_dir.join("libsupport_libs.rs"),
-        format!("&{:?}", static_libs),
-    )
-    .unwrap();
+    std::fs::write(out_dir.join("libsupport.rs"), libraries).unwrap();
 }
@@ -0,0 +1,2717 @@
+//! The runtime compiled projects call into, for speech, costumes, sounds and
+//! scheduling. It is linked into them as a static library, and scratchc uses
+//! it as a crate, for `--interpret` and to fold constants exactly the way they
+//! would run.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+type ScriptFn = unsafe extern "C" fn() -> ();
+
+/// A script to start: a compiled one, or one that `scratchc --interpret`
+/// runs, which it tells apart by the number it's passed.
+#[derive(Clone, Copy)]
+enum Script {
+    Compiled(ScriptFn),
+    Interpreted(unsafe extern "C" fn(usize), usize),
+}
+
+impl Script {
+    unsafe fn run(self) {
+        match self {
+            Script::Compiled(f) => f(),
+            Script::Interpreted(f, i) => f(i),
+        }
+    }
+}
+
+static THREADS: Mutex<Vec<std::thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+static DISPLAY: AtomicBool = AtomicBool::new(false);
+
+/// Start a script at the current script's virtual time. `done` gets the
+/// virtual time it finished at.
+fn spawn(script: Script, done: Option<mpsc::Sender<Duration>>) {
+    let id = scheduler::enqueue();
+    let time = scheduler::time();
+    let t = std::thread::Builder::new()
+        .stack_size(guard::STACK_SIZE)
+        .spawn(move || {
+            scheduler::set_time(time);
+            scheduler::start(id);
+            unsafe { script.run() };
+            scheduler::release();
+            if let Some(done) = done {
+                let _ = done.send(scheduler::time());
+            }
+        })
+        .unwrap();
+    THREADS.lock().unwrap().push(t);
+}
+
+/// Scripts run on their own threads, but like in Scratch only one runs at a
+/// time. A script keeps running until it yields at the end of a loop
+/// iteration or waits, and then the scripts take turns in order.
+mod scheduler {
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::sync::{Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    struct Turns {
+        running: bool,
+        waiting: VecDeque<u64>,
+    }
+
+    // Startup counts as running, so that scripts started together take their
+    // first turns in the order they were started.
+    static TURNS: Mutex<Turns> = Mutex::new(Turns {
+        running: true,
+        waiting: VecDeque::new(),
+    });
+    static TURN: Condvar = Condvar::new();
+    static TURN_START: Mutex<Option<Instant>> = Mutex::new(None);
+    static NEXT_ID: Mutex<u64> = Mutex::new(0);
+
+    // Scratch lets warp mode run for half a second before yielding anyway.
+    const WARP_TIME: Duration = Duration::from_millis(500);
+
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+        static WARP: Cell<(u32, Option<Instant>)> = const { Cell::new((0, None)) };
+        /// How far into the project the script is, counting only the time it
+        /// waited for, so that it's the same on every run however busy the
+        /// machine is. Threads that don't run scripts go by the clock.
+        static TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+    }
+
+    /// The current script's virtual time.
+    pub fn time() -> Duration {
+        TIME.with(|t| t.get()).unwrap_or_else(super::elapsed)
+    }
+
+    pub fn set_time(time: Duration) {
+        TIME.with(|t| t.set(Some(time)));
+    }
+
+    /// Move the current script's virtual time on by how long it waited.
+    pub fn advance(by: Duration) {
+        set_time(time() + by);
+    }
+
+    /// Queue up a new script, returning the id its thread should start with.
+    pub fn enqueue() -> u64 {
+        let mut next = NEXT_ID.lock().unwrap();
+        *next += 1;
+        TURNS.lock().unwrap().waiting.push_back(*next);
+        *next
+    }
+
+    pub fn start(id: u64) {
+        ID.with(|i| i.set(id));
+        wait_turn(id, TURNS.lock().unwrap());
+    }
+
+    fn wait_turn(id: u64, mut turns: std::sync::MutexGuard<Turns>) {
+        while turns.running || turns.waiting.front() != Some(&id) {
+            turns = TURN.wait(turns).unwrap();
+        }
+        turns.waiting.pop_front();
+        turns.running = true;
+        *TURN_START.lock().unwrap() = Some(Instant::now());
+        super::guard::resume();
+        super::profile::resume();
+    }
+
+    /// When the current turn started, if a script is running.
+    pub fn turn_start() -> Option<Instant> {
+        *TURN_START.lock().unwrap()
+    }
+
+    pub fn acquire() {
+        let id = ID.with(|id| id.get());
+        let mut turns = TURNS.lock().unwrap();
+        turns.waiting.push_back(id);
+        wait_turn(id, turns);
+    }
+
+    /// Go to the back of the queue, in one step so that no other script can
+    /// take two turns in a row.
+    fn requeue() {
+        super::profile::pause();
+        let id = ID.with(|id| id.get());
+        *TURN_START.lock().unwrap() = None;
+        let mut turns = TURNS.lock().unwrap();
+        turns.running = false;
+        turns.waiting.push_back(id);
+        TURN.notify_all();
+        wait_turn(id, turns);
+    }
+
+    pub fn release() {
+        super::profile::pause();
+        *TURN_START.lock().unwrap() = None;
+        TURNS.lock().unwrap().running = false;
+        TURN.notify_all();
+    }
+
+    /// Let other scripts run while this one blocks.
+    pub fn unlocked<T>(f: impl FnOnce() -> T) -> T {
+        release();
+        let r = f();
+        acquire();
+        r
+    }
+
+    pub fn enter_warp() {
+        WARP.with(|w| {
+            let (depth, start) = w.get();
+            w.set((depth + 1, start.or_else(|| Some(Instant::now()))));
+        });
+    }
+
+    pub fn exit_warp() {
+        WARP.with(|w| match w.get() {
+            (1, _) => w.set((0, None)),
+            (depth, start) => w.set((depth - 1, start)),
+        });
+    }
+
+    pub fn yield_now() {
+        if should_yield() {
+            take_turns();
+        }
+    }
+
+    /// Whether a loop should give up its turn at the end of an iteration:
+    /// always, unless it's in warp mode and hasn't used up its time.
+    pub fn should_yield() -> bool {
+        WARP.with(|w| match w.get() {
+            (depth, Some(start)) if depth > 0 => {
+                if start.elapsed() < WARP_TIME {
+                    return false;
+                }
+                w.set((depth, Some(Instant::now())));
+                true
+            }
+            _ => true,
+        })
+    }
+
+    pub fn take_turns() {
+        if super::display::active() {
+            // Every loop redraws, so it runs once per frame.
+            unlocked(|| {
+                let frame = super::display::FRAME.as_nanos();
+                let elapsed = super::elapsed().as_nanos();
+                let next = (elapsed / frame + 1) * frame;
+                std::thread::sleep(Duration::from_nanos((next - elapsed) as u64));
+            });
+        } else {
+            requeue();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_yield() {
+    scheduler::yield_now();
+}
+
+#[no_mangle]
+pub extern "C" fn support_should_yield() -> i32 {
+    scheduler::should_yield() as i32
+}
+
+/// Give up the turn, after `support_should_yield` said to.
+#[no_mangle]
+pub extern "C" fn support_take_turns() {
+    scheduler::take_turns();
+}
+
+#[no_mangle]
+pub extern "C" fn support_enter_warp() {
+    scheduler::enter_warp();
+}
+
+#[no_mangle]
+pub extern "C" fn support_exit_warp() {
+    scheduler::exit_warp();
+}
+
+/// Safeguards that turn runaway projects into errors that say what went wrong:
+/// a limit on how deeply custom blocks nest, instead of overflowing the stack,
+/// and a watchdog that reports scripts holding on to their turn.
+mod guard {
+    use std::cell::RefCell;
+    use std::os::raw::c_char;
+    use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicU32, Ordering};
+    use std::time::Duration;
+
+    /// Script threads get big stacks so that the default depth fits.
+    pub const STACK_SIZE: usize = 64 << 20;
+
+    static MAX_DEPTH: AtomicU32 = AtomicU32::new(100_000);
+
+    thread_local! {
+        static CALLS: RefCell<Vec<(i32, *const c_char)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // The custom block the running script is in, for the watchdog. Only one
+    // script runs at a time, so this follows whoever has the turn.
+    static CURRENT_TARGET: AtomicI32 = AtomicI32::new(-1);
+    static CURRENT_PROC: AtomicPtr<c_char> = AtomicPtr::new(std::ptr::null_mut());
+
+    pub fn set_max_depth(depth: u32) {
+        MAX_DEPTH.store(depth, Ordering::Relaxed);
+    }
+
+    fn set_current(call: Option<&(i32, *const c_char)>) {
+        let (t, name) = call.cloned().unwrap_or((-1, std::ptr::null()));
+        CURRENT_TARGET.store(t, Ordering::Relaxed);
+        CURRENT_PROC.store(name as *mut c_char, Ordering::Relaxed);
+    }
+
+    fn describe(t: i32, name: *const c_char) -> String {
+        let target = super::with_target(t, |t| t.name.clone());
+        format!(
+            "custom block {:?} in {}",
+            unsafe { super::string(name) },
+            target
+        )
+    }
+
+    pub fn enter(t: i32, name: *const c_char) {
+        super::profile::enter(t, name);
+        let depth = CALLS.with(|calls| {
+            let mut calls = calls.borrow_mut();
+            calls.push((t, name));
+            set_current(calls.last());
+            calls.len()
+        });
+        let max = MAX_DEPTH.load(Ordering::Relaxed) as usize;
+        if max != 0 && depth > max {
+            eprintln!(
+                "scratchc: {} is nested more than {} deep",
+                describe(t, name),
+                max
+            );
+            std::process::exit(1);
+        }
+    }
+
+    pub fn exit() {
+        super::profile::exit();
+        CALLS.with(|calls| {
+            let mut calls = calls.borrow_mut();
+            calls.pop();
+            set_current(calls.last());
+        });
+    }
+
+    /// A script got its turn back.
+    pub fn resume() {
+        CALLS.with(|calls| set_current(calls.borrow().last()));
+    }
+
+    /// Report, once per turn, scripts that run for longer than `limit`
+    /// without yielding.
+    pub fn watchdog(limit: Duration) {
+        std::thread::spawn(move || {
+            let mut reported = None;
+            loop {
+                std::thread::sleep(Duration::from_millis(100));
+                let start = match super::scheduler::turn_start() {
+                    Some(start) if reported != Some(start) => start,
+                    _ => continue,
+                };
+                if start.elapsed() < limit {
+                    continue;
+                }
+                reported = Some(start);
+                let name = CURRENT_PROC.load(Ordering::Relaxed);
+                let place = if name.is_null() {
+                    String::new()
+                } else {
+                    format!(
+                        " (in {})",
+                        describe(CURRENT_TARGET.load(Ordering::Relaxed), name)
+                    )
+                };
+                eprintln!(
+                    "scratchc: a script has run for more than {} seconds without yielding{}",
+                    limit.as_secs_f64(),
+                    place
+                );
+            }
+        });
+    }
+}
+
+/// Counts and times blocks and custom blocks, in projects compiled with
+/// `--profile`. Time only counts while a script has its turn, and goes to the
+/// block that last started on its thread.
+mod profile {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fmt::Write;
+    use std::os::raw::c_char;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum Frame {
+        /// A script, by its label.
+        Script(&'static str),
+        /// A custom block, by its target and proccode.
+        Procedure(i32, &'static str),
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct Block {
+        target: i32,
+        id: &'static str,
+        opcode: &'static str,
+    }
+
+    #[derive(Default)]
+    struct Stats {
+        count: u64,
+        time: Duration,
+    }
+
+    #[derive(Default)]
+    struct Profile {
+        blocks: HashMap<Block, Stats>,
+        procedures: HashMap<(i32, &'static str), Stats>,
+        stacks: HashMap<(Vec<Frame>, Block), Duration>,
+    }
+
+    #[derive(Default)]
+    struct Thread {
+        frames: Vec<Frame>,
+        block: Option<Block>,
+        /// The blocks that called the custom blocks in `frames`, which get
+        /// the time after they return.
+        callers: Vec<Option<Block>>,
+        /// When the block's time started counting, if the script has its turn.
+        since: Option<Instant>,
+    }
+
+    static ON: AtomicBool = AtomicBool::new(false);
+    static PROFILE: Mutex<Option<Profile>> = Mutex::new(None);
+
+    thread_local! {
+        static THREAD: RefCell<Thread> = RefCell::new(Thread::default());
+    }
+
+    /// Give the time since the last charge to the running block.
+    fn charge(thread: &mut Thread, profile: &mut Profile) {
+        let now = Instant::now();
+        if let (Some(block), Some(since)) = (thread.block, thread.since) {
+            let time = now - since;
+            profile.blocks.get_mut(&block).unwrap().time += time;
+            // Recursive custom blocks only count once.
+            let mut seen = vec![];
+            for frame in &thread.frames {
+                if let Frame::Procedure(t, name) = *frame {
+                    if !seen.contains(&(t, name)) {
+                        seen.push((t, name));
+                        profile.procedures.get_mut(&(t, name)).unwrap().time += time;
+                    }
+                }
+            }
+            *profile
+                .stacks
+                .entry((thread.frames.clone(), block))
+                .or_default() += time;
+        }
+        thread.since = Some(now);
+    }
+
+    fn with(f: impl FnOnce(&mut Thread, &mut Profile)) {
+        if !ON.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut profile = PROFILE.lock().unwrap();
+        THREAD.with(|thread| f(&mut thread.borrow_mut(), profile.as_mut().unwrap()));
+    }
+
+    /// A block is about to run. Scripts pass their label, and custom blocks
+    /// an empty string.
+    pub fn block(t: i32, script: &'static str, id: &'static str, opcode: &'static str) {
+        if !ON.load(Ordering::Relaxed) {
+            *PROFILE.lock().unwrap() = Some(Profile::default());
+            unsafe {
+                super::atexit(report);
+            }
+            ON.store(true, Ordering::Relaxed);
+        }
+        with(|thread, profile| {
+            charge(thread, profile);
+            if !script.is_empty() {
+                thread.frames.clear();
+                thread.frames.push(Frame::Script(script));
+                thread.callers.clear();
+            }
+            let block = Block {
+                target: t,
+                id,
+                opcode,
+            };
+            thread.block = Some(block);
+            profile.blocks.entry(block).or_default().count += 1;
+        });
+    }
+
+    pub fn enter(t: i32, name: *const c_char) {
+        with(|thread, profile| {
+            charge(thread, profile);
+            let name = unsafe { super::string(name) };
+            thread.frames.push(Frame::Procedure(t, name));
+            // Until its `define` block starts.
+            thread.callers.push(thread.block.take());
+            profile.procedures.entry((t, name)).or_default().count += 1;
+        });
+    }
+
+    pub fn exit() {
+        with(|thread, profile| {
+            charge(thread, profile);
+            thread.frames.pop();
+            thread.block = thread.callers.pop().flatten();
+        });
+    }
+
+    /// The running script gave up its turn.
+    pub fn pause() {
+        with(|thread, profile| {
+            charge(thread, profile);
+            thread.since = None;
+        });
+    }
+
+    pub fn resume() {
+        with(|thread, _| {
+            if thread.block.is_some() {
+                thread.since = Some(Instant::now());
+            }
+        });
+    }
+
+    fn milliseconds(time: Duration) -> String {
+        format!("{:.3}", time.as_secs_f64() * 1000.0)
+    }
+
+    /// Rows of name, count and time, from the most time to the least.
+    fn table(out: &mut String, heading: &str, mut rows: Vec<(String, u64, Duration)>) {
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        let width = rows
+            .iter()
+            .map(|row| row.0.chars().count())
+            .chain(std::iter::once(heading.len()))
+            .max()
+            .unwrap();
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>12}",
+            heading,
+            "count",
+            "ms",
+            width = width
+        )
+        .unwrap();
+        for (name, count, time) in rows {
+            writeln!(
+                out,
+                "{:<width$}  {:>10}  {:>12}",
+                name,
+                count,
+                milliseconds(time),
+                width = width
+            )
+            .unwrap();
+        }
+    }
+
+    /// Frames can't have semicolons in the collapsed stack format.
+    fn frame(name: &str) -> String {
+        name.replace(';', ",")
+    }
+
+    extern "C" fn report() {
+        let profile = match PROFILE.lock().unwrap().take() {
+            Some(profile) => profile,
+            None => return,
+        };
+        let name = |t: i32| super::with_target(t, |t| t.name.clone());
+
+        let mut out = String::new();
+        table(
+            &mut out,
+            "custom block",
+            profile
+                .procedures
+                .iter()
+                .map(|((t, proc), stats)| {
+                    (format!("{}: {}", name(*t), proc), stats.count, stats.time)
+                })
+                .collect(),
+        );
+        out.push('\n');
+        table(
+            &mut out,
+            "block",
+            profile
+                .blocks
+                .iter()
+                .map(|(block, stats)| {
+                    (
+                        format!("{}: {} {}", name(block.target), block.opcode, block.id),
+                        stats.count,
+                        stats.time,
+                    )
+                })
+                .collect(),
+        );
+
+        // Collapsed stacks, in nanoseconds, for flame graphs.
+        let mut stacks: Vec<_> = profile
+            .stacks
+            .iter()
+            .map(|((frames, block), time)| {
+                let mut line = String::new();
+                for f in frames {
+                    match *f {
+                        Frame::Script(label) => line.push_str(&frame(label)),
+                        Frame::Procedure(t, proc) => {
+                            line.push_str(&frame(&format!("{}: {}", name(t), proc)))
+                        }
+                    }
+                    line.push(';');
+                }
+                line.push_str(&frame(&format!("{} {}", block.opcode, block.id)));
+                write!(line, " {}", time.as_nanos()).unwrap();
+                line
+            })
+            .collect();
+        stacks.sort();
+
+        let path = std::env::var("SCRATCHC_PROFILE")
+            .ok()
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| "profile.folded".to_owned());
+        let mut folded = stacks.join("\n");
+        folded.push('\n');
+        match std::fs::write(&path, folded) {
+            Ok(()) => writeln!(out, "\ncollapsed stacks written to {}", path).unwrap(),
+            Err(e) => writeln!(out, "\ncan't write collapsed stacks to {}: {}", path, e).unwrap(),
+        }
+        eprint!("{}", out);
+    }
+}
+
+/// # Safety
+///
+/// `script`, `block` and `opcode` must be valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn support_profile_block(
+    t: i32,
+    script: *const c_char,
+    block: *const c_char,
+    opcode: *const c_char,
+) {
+    unsafe { profile::block(t, string(script), string(block), string(opcode)) };
+}
+
+/// # Safety
+///
+/// `name` must be a valid C string that outlives the call.
+#[no_mangle]
+pub unsafe extern "C" fn support_enter_procedure(t: i32, name: *const c_char) {
+    guard::enter(t, name);
+}
+
+#[no_mangle]
+pub extern "C" fn support_exit_procedure() {
+    guard::exit();
+}
+
+static TRACE: Mutex<Option<std::io::LineWriter<std::fs::File>>> = Mutex::new(None);
+
+thread_local! {
+    // The hat block ID of the script running on this thread.
+    static SCRIPT: std::cell::Cell<*const c_char> = const { std::cell::Cell::new(std::ptr::null()) };
+}
+
+/// Log a block about to run, as a line of tab separated sprite name, script,
+/// block ID and opcode. Custom blocks pass an empty script, for the one that
+/// called them.
+///
+/// # Safety
+///
+/// `script`, `block` and `opcode` must be valid C strings, and `script` must
+/// outlive the script's run.
+#[no_mangle]
+pub unsafe extern "C" fn support_trace(
+    t: i32,
+    script: *const c_char,
+    block: *const c_char,
+    opcode: *const c_char,
+) {
+    let script = unsafe {
+        if *script == 0 {
+            SCRIPT.with(|s| s.get())
+        } else {
+            SCRIPT.with(|s| s.set(script));
+            script
+        }
+    };
+    let line = unsafe {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            with_target(t, |t| t.name.clone()),
+            string(script),
+            string(block),
+            string(opcode)
+        )
+    };
+    match TRACE.lock().unwrap().as_mut() {
+        Some(file) => std::io::Write::write_all(file, line.as_bytes()).unwrap(),
+        None => eprint!("{}", line),
+    }
+}
+
+unsafe fn string<'a>(s: *const c_char) -> &'a str {
+    CStr::from_ptr(s).to_str().unwrap()
+}
+
+thread_local! {
+    /// Strings made for generated code while a statement runs, which it frees
+    /// once the statement is done with them.
+    static TEMPORARIES: RefCell<Vec<CString>> = const { RefCell::new(Vec::new()) };
+}
+
+fn temporary(s: String) -> *const c_char {
+    let s = CString::new(s).unwrap();
+    let p = s.as_ptr();
+    TEMPORARIES.with(|t| t.borrow_mut().push(s));
+    p
+}
+
+#[no_mangle]
+pub extern "C" fn support_free_temporaries() {
+    TEMPORARIES.with(|t| t.borrow_mut().clear());
+}
+
+/// Copy a string for a variable or an argument to keep, until it's released.
+///
+/// # Safety
+///
+/// `s` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_own(s: *const c_char) -> *const c_char {
+    unsafe { CStr::from_ptr(s) }.to_owned().into_raw()
+}
+
+/// # Safety
+///
+/// `s` must have come from `support_own`, and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn support_release(s: *const c_char) {
+    drop(unsafe { CString::from_raw(s as *mut c_char) });
+}
+
+/// Like `clamp`, but NaN becomes `min` instead of staying NaN.
+#[allow(clippy::manual_clamp)]
+fn limit(n: f64, min: f64, max: f64) -> f64 {
+    n.max(min).min(max)
+}
+
+/// JavaScript's `Number(s)`, which is NaN for anything that isn't a number.
+pub fn js_number(s: &str) -> f64 {
+    let s = s.trim();
+    if s.is_empty() {
+        return 0.0;
+    }
+    let (sign, digits) = match s.as_bytes()[0] {
+        b'-' => (-1.0, &s[1..]),
+        b'+' => (1.0, &s[1..]),
+        _ => (1.0, s),
+    };
+    if digits == "Infinity" {
+        return sign * f64::INFINITY;
+    }
+    if s.len() > 2 && (s.starts_with("0x") || s.starts_with("0X")) {
+        return i64::from_str_radix(&s[2..], 16)
+            .map(|n| n as f64)
+            .unwrap_or(f64::NAN);
+    }
+    // Rust also accepts "inf" and "nan", JavaScript doesn't.
+    if digits
+        .bytes()
+        .any(|b| b.is_ascii_alphabetic() && b != b'e' && b != b'E')
+    {
+        return f64::NAN;
+    }
+    s.parse().unwrap_or(f64::NAN)
+}
+
+pub fn number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_owned()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity" } else { "-Infinity" }.to_owned()
+    } else {
+        format!("{}", n)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_number_to_string(n: f64) -> *const c_char {
+    temporary(number_to_string(n))
+}
+
+pub fn string_to_number(s: &str) -> f64 {
+    let n = js_number(s);
+    if n.is_nan() {
+        0.0
+    } else {
+        n
+    }
+}
+
+/// # Safety
+///
+/// `s` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_string_to_number(s: *const c_char) -> f64 {
+    string_to_number(string(s))
+}
+
+pub fn string_to_bool(s: &str) -> bool {
+    !(s.is_empty() || s == "0" || s.eq_ignore_ascii_case("false"))
+}
+
+/// # Safety
+///
+/// `s` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_string_to_bool(s: *const c_char) -> i32 {
+    string_to_bool(string(s)) as i32
+}
+
+pub fn number_to_bool(n: f64) -> bool {
+    n != 0.0 && !n.is_nan()
+}
+
+pub fn bool_to_string(b: bool) -> &'static str {
+    if b {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+/// Whether text means exactly the same as the number it parses to, so that
+/// it can be kept as a number without anyone noticing.
+pub fn is_exact_number(s: &str) -> bool {
+    match s.parse::<f64>() {
+        Ok(n) if n.is_finite() => number_to_string(n) == s,
+        _ => false,
+    }
+}
+
+/// How many times `repeat` runs its body: the count is rounded like
+/// `Math.round`, and when that's below 1, or NaN, it doesn't run at all.
+pub fn repeat_count(n: f64) -> u32 {
+    // `as` saturates, and turns NaN into 0.
+    (n + 0.5).floor() as u32
+}
+
+/// Scratch's comparison: numeric when both sides are numbers, otherwise case
+/// insensitive.
+pub fn compare(a: &str, b: &str) -> std::cmp::Ordering {
+    let number = |s: &str| {
+        if s.trim().is_empty() {
+            f64::NAN
+        } else {
+            js_number(s)
+        }
+    };
+    let (n1, n2) = (number(a), number(b));
+    if n1.is_nan() || n2.is_nan() {
+        a.to_lowercase().cmp(&b.to_lowercase())
+    } else {
+        n1.partial_cmp(&n2).unwrap()
+    }
+}
+
+/// # Safety
+///
+/// `a` and `b` must be valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn support_compare(a: *const c_char, b: *const c_char) -> i32 {
+    compare(string(a), string(b)) as i32
+}
+
+/// `compare` for two numbers, which only has to compare them as text when
+/// one is NaN.
+pub fn compare_numbers(a: f64, b: f64) -> std::cmp::Ordering {
+    a.partial_cmp(&b)
+        .unwrap_or_else(|| compare(&number_to_string(a), &number_to_string(b)))
+}
+
+#[no_mangle]
+pub extern "C" fn support_compare_numbers(a: f64, b: f64) -> i32 {
+    compare_numbers(a, b) as i32
+}
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// A number in [0, 1) from a xorshift generator seeded by the clock.
+fn random() -> f64 {
+    let mut x = SEED.load(Ordering::Relaxed);
+    if x == 0 {
+        x = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+            | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    SEED.store(x, Ordering::Relaxed);
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+extern "C" {
+    fn atexit(f: extern "C" fn()) -> i32;
+    fn isatty(fd: i32) -> i32;
+}
+
+fn stty(args: &[&str], tty: std::process::Stdio) -> Option<String> {
+    let o = std::process::Command::new("stty")
+        .args(args)
+        .stdin(tty)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if o.status.success() {
+        Some(String::from_utf8(o.stdout).ok()?.trim().to_owned())
+    } else {
+        None
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_init() {
+    if let Ok(display) = std::env::var("SCRATCHC_DISPLAY") {
+        match display.as_str() {
+            "terminal" => display::start(),
+            "" | "none" => {}
+            _ => panic!("unknown SCRATCHC_DISPLAY {:?}", display),
+        }
+    }
+    if let Ok(speech) = std::env::var("SCRATCHC_SPEECH") {
+        match speech.as_str() {
+            "speaker" => SPEAKERS.store(true, Ordering::Relaxed),
+            "" | "plain" => {}
+            _ => panic!("unknown SCRATCHC_SPEECH {:?}", speech),
+        }
+    }
+    if let Ok(depth) = std::env::var("SCRATCHC_MAX_DEPTH") {
+        match depth.parse() {
+            Ok(depth) => guard::set_max_depth(depth),
+            Err(_) => panic!("invalid SCRATCHC_MAX_DEPTH {:?}", depth),
+        }
+    }
+    if let Ok(secs) = std::env::var("SCRATCHC_WATCHDOG") {
+        match secs.parse() {
+            Ok(secs) if secs > 0.0 => guard::watchdog(Duration::from_secs_f64(secs)),
+            _ => panic!("invalid SCRATCHC_WATCHDOG {:?}", secs),
+        }
+    }
+    *START.lock().unwrap() = Some(Instant::now());
+    // Scripts started by the program itself start at the beginning.
+    scheduler::set_time(Duration::ZERO);
+    let path = |name| std::env::var(name).ok().filter(|p: &String| !p.is_empty());
+    if let Some(path) = path("SCRATCHC_TRACE") {
+        let file = std::fs::File::create(&path)
+            .unwrap_or_else(|e| panic!("can't write SCRATCHC_TRACE {:?}: {}", path, e));
+        *TRACE.lock().unwrap() = Some(std::io::LineWriter::new(file));
+    }
+    audio::start(path("SCRATCHC_AUDIO"), path("SCRATCHC_MIDI"));
+}
+
+/// # Safety
+///
+/// `f` must be safe to run on a thread of its own.
+#[no_mangle]
+pub unsafe extern "C" fn support_spawn_script(f: ScriptFn) {
+    spawn(Script::Compiled(f), None);
+}
+
+/// # Safety
+///
+/// `f` must be safe to run on a thread of its own with `i`.
+#[no_mangle]
+pub unsafe extern "C" fn support_spawn_interpreted(f: unsafe extern "C" fn(usize), i: usize) {
+    spawn(Script::Interpreted(f, i), None);
+}
+
+#[no_mangle]
+pub extern "C" fn support_detach_scripts() {
+    THREADS.lock().unwrap().clear();
+}
+
+#[no_mangle]
+pub extern "C" fn support_join_scripts() {
+    scheduler::release();
+    loop {
+        let t = THREADS.lock().unwrap().pop();
+        match t {
+            Some(t) => t.join().unwrap(),
+            // Key presses can still start scripts.
+            None if input::open() && !KEY_HATS.lock().unwrap().is_empty() => {
+                std::thread::sleep(display::FRAME)
+            }
+            None => break,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_sleep(s: f64) {
+    let s = Duration::from_secs_f64(if s > 0.0 { s } else { 0.0 });
+    scheduler::unlocked(|| std::thread::sleep(s));
+    scheduler::advance(s);
+}
+
+struct Costume {
+    name: CString,
+    width: usize,
+    height: usize,
+    pixels: &'static [u8],
+    rotation_center: (f64, f64),
+    resolution: f64,
+}
+
+struct Sound {
+    samples: &'static [u8],
+    sample_count: usize,
+    rate: u32,
+    name: String,
+}
+
+struct Target {
+    name: String,
+    is_stage: bool,
+    layer: i32,
+    x: f64,
+    y: f64,
+    size: f64,
+    direction: f64,
+    visible: bool,
+    rotation_style: i32,
+    costume: usize,
+    costumes: Vec<Costume>,
+    effects: [f64; 7],
+    bubble: Option<(Speech, String)>,
+    sounds: Vec<Sound>,
+    volume: f64,
+    sound_effects: [f64; 2],
+    instrument: usize,
+}
+
+const COLOR: usize = 0;
+const FISHEYE: usize = 1;
+const WHIRL: usize = 2;
+const PIXELATE: usize = 3;
+const MOSAIC: usize = 4;
+const BRIGHTNESS: usize = 5;
+const GHOST: usize = 6;
+
+const PITCH: usize = 0;
+const PAN: usize = 1;
+
+static TARGETS: Mutex<Vec<Target>> = Mutex::new(Vec::new());
+
+fn with_target<T>(t: i32, f: impl FnOnce(&mut Target) -> T) -> T {
+    f(&mut TARGETS.lock().unwrap()[t as usize])
+}
+
+/// # Safety
+///
+/// `name` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_add_target(
+    name: *const c_char,
+    is_stage: i32,
+    layer: i32,
+    x: f64,
+    y: f64,
+    size: f64,
+    direction: f64,
+    visible: i32,
+    rotation_style: i32,
+    costume: i32,
+    volume: f64,
+) {
+    let t = TARGETS.lock().unwrap().len();
+    audio::record(audio::Event::Mix {
+        target: t,
+        volume,
+        pitch: 0.0,
+        pan: 0.0,
+    });
+    TARGETS.lock().unwrap().push(Target {
+        name: unsafe { string(name) }.to_owned(),
+        is_stage: is_stage != 0,
+        layer,
+        x,
+        y,
+        size,
+        direction,
+        visible: visible != 0,
+        rotation_style,
+        costume: costume as usize,
+        costumes: Vec::new(),
+        effects: [0.0; 7],
+        bubble: None,
+        sounds: Vec::new(),
+        volume,
+        sound_effects: [0.0; 2],
+        instrument: 0,
+    });
+}
+
+/// # Safety
+///
+/// `name` must be a valid C string, and `pixels` must point to
+/// `width * height * 4` bytes that are never freed.
+#[no_mangle]
+pub unsafe extern "C" fn support_add_costume(
+    t: i32,
+    name: *const c_char,
+    width: i32,
+    height: i32,
+    pixels: *const u8,
+    rotation_center_x: f64,
+    rotation_center_y: f64,
+    resolution: f64,
+) {
+    let costume = Costume {
+        name: unsafe { CStr::from_ptr(name) }.to_owned(),
+        width: width as usize,
+        height: height as usize,
+        pixels: unsafe { std::slice::from_raw_parts(pixels, (width * height * 4) as usize) },
+        rotation_center: (rotation_center_x, rotation_center_y),
+        resolution,
+    };
+    with_target(t, |t| t.costumes.push(costume));
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Speech {
+    Say,
+    Think,
+}
+
+static SPEAKERS: AtomicBool = AtomicBool::new(false);
+
+/// All speech goes through here, either to a bubble on the stage or as one
+/// line on stdout.
+fn say(t: i32, speech: Speech, message: String) {
+    if display::active() {
+        with_target(t, |t| {
+            t.bubble = if message.is_empty() {
+                None
+            } else {
+                Some((speech, message))
+            }
+        });
+    } else if SPEAKERS.load(Ordering::Relaxed) {
+        // An empty message only removes the bubble, so there is nothing to
+        // say.
+        if !message.is_empty() {
+            let name = with_target(t, |t| t.name.clone());
+            let verb = match speech {
+                Speech::Say => "says",
+                Speech::Think => "thinks",
+            };
+            println!("[{} {}] {}", name, verb, message);
+        }
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn speech(think: i32) -> Speech {
+    if think != 0 {
+        Speech::Think
+    } else {
+        Speech::Say
+    }
+}
+
+/// # Safety
+///
+/// `s` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_say_string(t: i32, s: *const c_char, think: i32) {
+    say(t, speech(think), unsafe { string(s) }.to_owned());
+}
+
+#[no_mangle]
+pub extern "C" fn support_say_float(t: i32, f: f64, think: i32) {
+    say(t, speech(think), number_to_string(f));
+}
+
+// Scratch hides floating point error in reported positions.
+fn limit_precision(n: f64) -> f64 {
+    let rounded = n.round();
+    if (n - rounded).abs() < 1e-9 {
+        rounded
+    } else {
+        n
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_x_position(t: i32) -> f64 {
+    with_target(t, |t| limit_precision(t.x))
+}
+
+#[no_mangle]
+pub extern "C" fn support_y_position(t: i32) -> f64 {
+    with_target(t, |t| limit_precision(t.y))
+}
+
+#[no_mangle]
+pub extern "C" fn support_direction(t: i32) -> f64 {
+    with_target(t, |t| t.direction)
+}
+
+#[no_mangle]
+pub extern "C" fn support_go_to(t: i32, x: f64, y: f64) {
+    with_target(t, |t| {
+        if !t.is_stage {
+            t.x = x;
+            t.y = y;
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_move_steps(t: i32, steps: f64) {
+    with_target(t, |t| {
+        if !t.is_stage {
+            let radians = (90.0 - t.direction).to_radians();
+            t.x += steps * radians.cos();
+            t.y += steps * radians.sin();
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_point_in_direction(t: i32, direction: f64) {
+    with_target(t, |t| {
+        if !t.is_stage && direction.is_finite() {
+            // Wrap into (-180, 180].
+            let d = (direction + 179.0).rem_euclid(360.0) - 179.0;
+            t.direction = d;
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_clear_bubble(t: i32) {
+    with_target(t, |t| t.bubble = None);
+}
+
+// JavaScript's Math.round, which rounds halves up rather than away from zero.
+fn js_round(n: f64) -> f64 {
+    (n + 0.5).floor()
+}
+
+fn set_costume(t: &mut Target, index: f64) {
+    let index = if index.is_finite() {
+        js_round(index)
+    } else {
+        0.0
+    };
+    let len = t.costumes.len() as f64;
+    t.costume = (index - (index / len).floor() * len) as usize;
+}
+
+fn switch_costume(t: &mut Target, costume: &str) {
+    if let Some(i) = t
+        .costumes
+        .iter()
+        .position(|c| c.name.to_bytes() == costume.as_bytes())
+    {
+        t.costume = i;
+        return;
+    }
+    let current = t.costume as f64;
+    match costume {
+        "next backdrop" => set_costume(t, current + 1.0),
+        "previous backdrop" => set_costume(t, current - 1.0),
+        "random backdrop" if t.costumes.len() > 1 => {
+            // Any backdrop other than the current one.
+            let n = (random() * (t.costumes.len() - 1) as f64) as usize;
+            t.costume = if n >= t.costume { n + 1 } else { n };
+        }
+        _ => {
+            let n = js_number(costume);
+            if !n.is_nan() && !costume.trim().is_empty() {
+                set_costume(t, n - 1.0);
+            }
+        }
+    }
+}
+
+/// # Safety
+///
+/// `costume` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_switch_costume(t: i32, costume: *const c_char) {
+    let costume = unsafe { string(costume) };
+    with_target(t, |t| switch_costume(t, costume));
+}
+
+#[no_mangle]
+pub extern "C" fn support_switch_costume_number(t: i32, costume: f64) {
+    with_target(t, |t| set_costume(t, costume - 1.0));
+}
+
+#[no_mangle]
+pub extern "C" fn support_next_costume(t: i32) {
+    with_target(t, |t| {
+        let next = t.costume as f64 + 1.0;
+        set_costume(t, next)
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_costume_number(t: i32) -> f64 {
+    with_target(t, |t| (t.costume + 1) as f64)
+}
+
+#[no_mangle]
+pub extern "C" fn support_costume_name(t: i32) -> *const c_char {
+    // Costumes are never removed, so the name outlives the lock.
+    with_target(t, |t| t.costumes[t.costume].name.as_ptr())
+}
+
+fn stage() -> i32 {
+    TARGETS
+        .lock()
+        .unwrap()
+        .iter()
+        .position(|t| t.is_stage)
+        .unwrap() as i32
+}
+
+static BACKDROP_HATS: Mutex<Vec<(String, Script)>> = Mutex::new(Vec::new());
+
+fn register_backdrop_hat(backdrop: *const c_char, script: Script) {
+    let backdrop = unsafe { string(backdrop) }.to_lowercase();
+    BACKDROP_HATS.lock().unwrap().push((backdrop, script));
+}
+
+/// # Safety
+///
+/// `backdrop` must be a valid C string, and `f` safe to run on a thread of its
+/// own.
+#[no_mangle]
+pub unsafe extern "C" fn support_register_backdrop_hat(backdrop: *const c_char, f: ScriptFn) {
+    register_backdrop_hat(backdrop, Script::Compiled(f));
+}
+
+/// # Safety
+///
+/// `backdrop` must be a valid C string, and `f` safe to run on a thread of its
+/// own with `i`.
+#[no_mangle]
+pub unsafe extern "C" fn support_register_backdrop_hat_interpreted(
+    backdrop: *const c_char,
+    f: unsafe extern "C" fn(usize),
+    i: usize,
+) {
+    register_backdrop_hat(backdrop, Script::Interpreted(f, i));
+}
+
+fn switch_backdrop(switch: impl FnOnce(&mut Target), wait: i32) {
+    let name = with_target(stage(), |t| {
+        switch(t);
+        t.costumes[t.costume].name.to_str().unwrap().to_lowercase()
+    });
+    let (done, finished) = mpsc::channel();
+    let mut started = 0;
+    for (backdrop, script) in BACKDROP_HATS.lock().unwrap().iter() {
+        if *backdrop == name {
+            spawn(*script, Some(done.clone()));
+            started += 1;
+        }
+    }
+    if wait != 0 {
+        // Carry on from whichever script finished last.
+        let mut time = scheduler::time();
+        scheduler::unlocked(|| {
+            for _ in 0..started {
+                time = time.max(finished.recv().unwrap());
+            }
+        });
+        scheduler::set_time(time);
+    }
+}
+
+/// # Safety
+///
+/// `backdrop` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_switch_backdrop(backdrop: *const c_char, wait: i32) {
+    let backdrop = unsafe { string(backdrop) };
+    switch_backdrop(|t| switch_costume(t, backdrop), wait);
+}
+
+#[no_mangle]
+pub extern "C" fn support_switch_backdrop_number(backdrop: f64, wait: i32) {
+    switch_backdrop(|t| set_costume(t, backdrop - 1.0), wait);
+}
+
+#[no_mangle]
+pub extern "C" fn support_next_backdrop() {
+    switch_backdrop(
+        |t| {
+            let next = t.costume as f64 + 1.0;
+            set_costume(t, next)
+        },
+        0,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn support_backdrop_number() -> f64 {
+    support_costume_number(stage())
+}
+
+#[no_mangle]
+pub extern "C" fn support_backdrop_name() -> *const c_char {
+    support_costume_name(stage())
+}
+
+#[no_mangle]
+pub extern "C" fn support_size(t: i32) -> f64 {
+    with_target(t, |t| js_round(t.size))
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_size(t: i32, size: f64) {
+    with_target(t, |t| {
+        if t.is_stage {
+            return;
+        }
+        let c = &t.costumes[t.costume];
+        let (w, h) = (
+            c.width as f64 / c.resolution,
+            c.height as f64 / c.resolution,
+        );
+        let mut scale = size / 100.0;
+        if w > 0.0 && h > 0.0 {
+            // Keep the sprite between 5 pixels and one and a half stages.
+            let min = (5.0 / w).max(5.0 / h).min(1.0);
+            let max = (1.5 * 480.0 / w).min(1.5 * 360.0 / h);
+            scale = scale.max(min).min(max);
+        }
+        t.size = scale * 100.0;
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_effect(t: i32, effect: i32) -> f64 {
+    with_target(t, |t| t.effects[effect as usize])
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_effect(t: i32, effect: i32, value: f64) {
+    let effect = effect as usize;
+    let value = match effect {
+        GHOST => limit(value, 0.0, 100.0),
+        BRIGHTNESS => limit(value, -100.0, 100.0),
+        _ => value,
+    };
+    with_target(t, |t| t.effects[effect] = value);
+}
+
+#[no_mangle]
+pub extern "C" fn support_clear_effects(t: i32) {
+    with_target(t, |t| t.effects = [0.0; 7]);
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_visible(t: i32, visible: i32) {
+    with_target(t, |t| t.visible = visible != 0);
+}
+
+/// Move a sprite to a new position in the layer order, counted from the back.
+fn move_layer(t: i32, position: impl FnOnce(usize, usize) -> usize) {
+    let mut targets = TARGETS.lock().unwrap();
+    if targets[t as usize].is_stage {
+        return;
+    }
+    let mut sprites: Vec<usize> = (0..targets.len())
+        .filter(|&i| !targets[i].is_stage && i != t as usize)
+        .collect();
+    sprites.sort_by_key(|&i| targets[i].layer);
+    let current = targets[t as usize].layer as usize - 1;
+    let len = sprites.len();
+    sprites.insert(position(current.min(len), len).min(len), t as usize);
+    for (layer, &i) in sprites.iter().enumerate() {
+        targets[i].layer = layer as i32 + 1;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn support_go_to_front_back(t: i32, front: i32) {
+    move_layer(t, |_, len| if front != 0 { len } else { 0 });
+}
+
+#[no_mangle]
+pub extern "C" fn support_go_forward_layers(t: i32, layers: f64) {
+    let layers = if layers.is_finite() {
+        js_round(layers)
+    } else {
+        0.0
+    };
+    move_layer(t, |current, _| (current as f64 + layers).max(0.0) as usize);
+}
+
+/// # Safety
+///
+/// `name` must be a valid C string, and `samples` must point to `len * 2` bytes
+/// that are never freed.
+#[no_mangle]
+pub unsafe extern "C" fn support_add_sound(
+    t: i32,
+    name: *const c_char,
+    samples: *const u8,
+    len: i32,
+    sample_count: i32,
+    rate: i32,
+) {
+    let sound = Sound {
+        name: unsafe { string(name) }.to_owned(),
+        samples: unsafe { std::slice::from_raw_parts(samples, len as usize * 2) },
+        sample_count: sample_count as usize,
+        rate: rate as u32,
+    };
+    with_target(t, |t| t.sounds.push(sound));
+}
+
+static START: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Time since the project started by the clock, which the display keeps
+/// frames in step with.
+fn elapsed() -> Duration {
+    START.lock().unwrap().unwrap().elapsed()
+}
+
+struct Voice {
+    id: u64,
+    target: usize,
+    sound: usize,
+    end: Instant,
+    stopped: bool,
+}
+
+static VOICES: Mutex<Vec<Voice>> = Mutex::new(Vec::new());
+static NEXT_VOICE: AtomicU64 = AtomicU64::new(0);
+
+fn playback_rate(pitch: f64) -> f64 {
+    2f64.powf(pitch / 120.0)
+}
+
+fn play_sound(t: i32, sound: impl FnOnce(&Target) -> Option<usize>, wait: i32) {
+    let (sound, duration) = match with_target(t, |target| {
+        let i = sound(target)?;
+        let s = &target.sounds[i];
+        let seconds = s.sample_count as f64 / s.rate.max(1) as f64;
+        Some((i, seconds / playback_rate(target.sound_effects[PITCH])))
+    }) {
+        Some(s) => s,
+        None => return,
+    };
+    let target = t as usize;
+    let id = NEXT_VOICE.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut voices = VOICES.lock().unwrap();
+        let now = Instant::now();
+        voices.retain(|v| !v.stopped && v.end > now);
+        // Playing a sound that is already playing restarts it.
+        for v in voices.iter_mut() {
+            if v.target == target && v.sound == sound {
+                v.stopped = true;
+                audio::record(audio::Event::Stop { voice: v.id });
+            }
+        }
+        voices.push(Voice {
+            id,
+            target,
+            sound,
+            end: now + Duration::from_secs_f64(duration),
+            stopped: false,
+        });
+        audio::record(audio::Event::Play {
+            voice: id,
+            target,
+            sound,
+        });
+    }
+    if wait != 0 {
+        let start = Instant::now();
+        scheduler::unlocked(|| loop {
+            let remaining = {
+                let voices = VOICES.lock().unwrap();
+                match voices.iter().find(|v| v.id == id) {
+                    Some(v) if !v.stopped => v.end.saturating_duration_since(Instant::now()),
+                    _ => break,
+                }
+            };
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            std::thread::sleep(remaining.min(display::FRAME));
+        });
+        // The whole sound, unless it was stopped early.
+        let duration = Duration::from_secs_f64(duration);
+        scheduler::advance(start.elapsed().min(duration));
+    }
+}
+
+fn sound_index(t: &Target, sound: &str) -> Option<usize> {
+    if let Some(i) = t.sounds.iter().position(|s| s.name == sound) {
+        return Some(i);
+    }
+    let n = js_number(sound);
+    if n.is_nan() || sound.trim().is_empty() {
+        return None;
+    }
+    sound_number(t, n)
+}
+
+fn sound_number(t: &Target, n: f64) -> Option<usize> {
+    if t.sounds.is_empty() || !n.is_finite() {
+        return None;
+    }
+    let len = t.sounds.len() as f64;
+    let n = js_round(n) - 1.0;
+    Some((n - (n / len).floor() * len) as usize)
+}
+
+/// # Safety
+///
+/// `sound` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_play_sound(t: i32, sound: *const c_char, wait: i32) {
+    let sound = unsafe { string(sound) };
+    play_sound(t, |t| sound_index(t, sound), wait);
+}
+
+#[no_mangle]
+pub extern "C" fn support_play_sound_number(t: i32, sound: f64, wait: i32) {
+    play_sound(t, |t| sound_number(t, sound), wait);
+}
+
+#[no_mangle]
+pub extern "C" fn support_stop_all_sounds() {
+    let mut voices = VOICES.lock().unwrap();
+    for v in voices.iter_mut() {
+        if !v.stopped {
+            v.stopped = true;
+            audio::record(audio::Event::Stop { voice: v.id });
+        }
+    }
+}
+
+fn set_mix(t: i32, f: impl FnOnce(&mut Target)) {
+    with_target(t, |target| {
+        f(target);
+        audio::record(audio::Event::Mix {
+            target: t as usize,
+            volume: target.volume,
+            pitch: target.sound_effects[PITCH],
+            pan: target.sound_effects[PAN],
+        });
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_volume(t: i32) -> f64 {
+    with_target(t, |t| t.volume)
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_volume(t: i32, volume: f64) {
+    set_mix(t, |t| t.volume = limit(volume, 0.0, 100.0));
+}
+
+#[no_mangle]
+pub extern "C" fn support_sound_effect(t: i32, effect: i32) -> f64 {
+    with_target(t, |t| t.sound_effects[effect as usize])
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_sound_effect(t: i32, effect: i32, value: f64) {
+    let effect = effect as usize;
+    let value = match effect {
+        PITCH => limit(value, -360.0, 360.0),
+        _ => limit(value, -100.0, 100.0),
+    };
+    set_mix(t, |t| t.sound_effects[effect] = value);
+}
+
+#[no_mangle]
+pub extern "C" fn support_clear_sound_effects(t: i32) {
+    set_mix(t, |t| t.sound_effects = [0.0; 2]);
+}
+
+static TEMPO: Mutex<f64> = Mutex::new(60.0);
+
+fn beats(beats: f64) -> f64 {
+    limit(beats, 0.0, 100.0) * 60.0 / *TEMPO.lock().unwrap()
+}
+
+// The menus for instruments and drums wrap around, like costumes.
+fn wrap(n: f64, len: usize) -> usize {
+    let n = if n.is_finite() {
+        js_round(n) - 1.0
+    } else {
+        0.0
+    };
+    let len = len as f64;
+    (n - (n / len).floor() * len) as usize
+}
+
+#[no_mangle]
+pub extern "C" fn support_tempo() -> f64 {
+    *TEMPO.lock().unwrap()
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_tempo(tempo: f64) {
+    *TEMPO.lock().unwrap() = limit(tempo, 20.0, 500.0);
+}
+
+#[no_mangle]
+pub extern "C" fn support_set_instrument(t: i32, instrument: f64) {
+    with_target(t, |t| {
+        t.instrument = wrap(instrument, audio::INSTRUMENT_COUNT)
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn support_play_note(t: i32, note: f64, beats: f64) {
+    let duration = self::beats(beats);
+    // Unlike drums, notes for 0 beats aren't played at all.
+    if duration.is_nan() || duration <= 0.0 {
+        return;
+    }
+    let (instrument, volume) = with_target(t, |t| (t.instrument, t.volume));
+    audio::record(audio::Event::Note {
+        target: t as usize,
+        instrument,
+        note: limit(note, 0.0, 130.0),
+        duration,
+        volume,
+    });
+    support_sleep(duration);
+}
+
+#[no_mangle]
+pub extern "C" fn support_play_drum(t: i32, drum: f64, beats: f64) {
+    let volume = with_target(t, |t| t.volume);
+    audio::record(audio::Event::Drum {
+        target: t as usize,
+        drum: wrap(drum, audio::DRUM_COUNT),
+        volume,
+    });
+    support_sleep(self::beats(beats));
+}
+
+#[no_mangle]
+pub extern "C" fn support_rest(beats: f64) {
+    support_sleep(self::beats(beats));
+}
+
+static KEY_HATS: Mutex<Vec<(String, Script)>> = Mutex::new(Vec::new());
+static KEYS: Mutex<Option<HashMap<String, Instant>>> = Mutex::new(None);
+
+// Terminals only report key presses (and autorepeat), never releases, so a
+// key counts as held for this long after its last press.
+const KEY_HOLD: Duration = Duration::from_millis(500);
+
+fn press_key(key: &str) {
+    KEYS.lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(key.to_owned(), Instant::now());
+    for (hat, script) in KEY_HATS.lock().unwrap().iter() {
+        if hat == key || hat == "any" {
+            spawn(*script, None);
+        }
+    }
+}
+
+/// Normalize a key menu option or argument to one of Scratch's key names.
+fn key_name(key: &str) -> String {
+    let key = key.to_lowercase();
+    match key.as_str() {
+        "space" | "up arrow" | "down arrow" | "right arrow" | "left arrow" | "enter" | "any" => key,
+        _ => key.chars().take(1).collect(),
+    }
+}
+
+fn register_key_hat(key: *const c_char, script: Script) {
+    let key = key_name(unsafe { string(key) });
+    KEY_HATS.lock().unwrap().push((key, script));
+    input::listen();
+}
+
+/// # Safety
+///
+/// `key` must be a valid C string, and `f` safe to run on a thread of its own.
+#[no_mangle]
+pub unsafe extern "C" fn support_register_key_hat(key: *const c_char, f: ScriptFn) {
+    register_key_hat(key, Script::Compiled(f));
+}
+
+/// # Safety
+///
+/// `key` must be a valid C string, and `f` safe to run on a thread of its own
+/// with `i`.
+#[no_mangle]
+pub unsafe extern "C" fn support_register_key_hat_interpreted(
+    key: *const c_char,
+    f: unsafe extern "C" fn(usize),
+    i: usize,
+) {
+    register_key_hat(key, Script::Interpreted(f, i));
+}
+
+/// # Safety
+///
+/// `key` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn support_key_pressed(key: *const c_char) -> i32 {
+    input::listen();
+    let key = key_name(unsafe { string(key) });
+    let keys = KEYS.lock().unwrap();
+    let held = |t: &Instant| t.elapsed() < KEY_HOLD;
+    let pressed = match &*keys {
+        Some(keys) if key == "any" => keys.values().any(held),
+        Some(keys) => keys.get(&key).map(held).unwrap_or(false),
+        None => false,
+    };
+    pressed as i32
+}
+
+/// Reads key presses from stdin. A terminal is switched to raw mode for as
+/// long as the program runs; anything else is read as a stream of key presses
+/// until end of file.
+mod input {
+    use std::io::Read;
+    use std::process::Stdio;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Mutex, Once};
+
+    static LISTEN: Once = Once::new();
+    static OPEN: AtomicBool = AtomicBool::new(false);
+    static SAVED_MODE: Mutex<Option<String>> = Mutex::new(None);
+
+    pub fn open() -> bool {
+        OPEN.load(Ordering::SeqCst)
+    }
+
+    pub fn listen() {
+        LISTEN.call_once(|| {
+            let raw = unsafe { super::isatty(0) } != 0;
+            if raw {
+                let saved_mode = super::stty(&["-g"], Stdio::inherit());
+                if saved_mode.is_none()
+                    || super::stty(&["-icanon", "-echo", "-isig", "min", "1"], Stdio::inherit())
+                        .is_none()
+                {
+                    return;
+                }
+                *SAVED_MODE.lock().unwrap() = saved_mode;
+                unsafe {
+                    super::atexit(restore);
+                }
+            }
+            OPEN.store(true, Ordering::SeqCst);
+            std::thread::spawn(move || read_keys(raw));
+        });
+    }
+
+    extern "C" fn restore() {
+        if let Some(mode) = SAVED_MODE.lock().unwrap().take() {
+            super::stty(&[&mode], Stdio::inherit());
+        }
+    }
+
+    fn read_keys(raw: bool) {
+        let stdin = std::io::stdin();
+        let mut stdin = stdin.lock();
+        let mut buf = [0; 64];
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let mut bytes = &buf[..n];
+            while let Some(&b) = bytes.first() {
+                let (key, len) = match bytes {
+                    [0x1b, b'[', k, ..] | [0x1b, b'O', k, ..] if b"ABCD".contains(k) => {
+                        let key = match k {
+                            b'A' => "up arrow",
+                            b'B' => "down arrow",
+                            b'C' => "right arrow",
+                            _ => "left arrow",
+                        };
+                        (key.to_owned(), 3)
+                    }
+                    // Some other escape sequence we don't have a key for.
+                    [0x1b, ..] => (String::new(), bytes.len()),
+                    _ => match b {
+                        3 if raw => std::process::exit(130),
+                        b' ' => ("space".to_owned(), 1),
+                        b'\r' | b'\n' => ("enter".to_owned(), 1),
+                        b if b.is_ascii_alphanumeric() => {
+                            ((b.to_ascii_lowercase() as char).to_string(), 1)
+                        }
+                        _ => (String::new(), 1),
+                    },
+                };
+                if !key.is_empty() {
+                    super::press_key(&key);
+                }
+                bytes = &bytes[len..];
+            }
+        }
+        OPEN.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Records what sounds and music play, and renders them to a WAV file for
+/// `SCRATCHC_AUDIO` and a MIDI file for `SCRATCHC_MIDI` once the project ends.
+mod audio {
+    use std::f64::consts::PI;
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    const RATE: u32 = 44100;
+
+    pub enum Event {
+        Play {
+            voice: u64,
+            target: usize,
+            sound: usize,
+        },
+        Stop {
+            voice: u64,
+        },
+        Mix {
+            target: usize,
+            volume: f64,
+            pitch: f64,
+            pan: f64,
+        },
+        Note {
+            target: usize,
+            instrument: usize,
+            note: f64,
+            duration: f64,
+            volume: f64,
+        },
+        Drum {
+            target: usize,
+            drum: usize,
+            volume: f64,
+        },
+    }
+
+    /// Everything that happened to sounds and at what virtual time, which is
+    /// only kept when rendering to a file.
+    static EVENTS: Mutex<Option<Vec<(Duration, Event)>>> = Mutex::new(None);
+    static WAV: Mutex<Option<String>> = Mutex::new(None);
+    static MIDI: Mutex<Option<String>> = Mutex::new(None);
+
+    pub fn start(wav: Option<String>, midi: Option<String>) {
+        if wav.is_none() && midi.is_none() {
+            return;
+        }
+        *WAV.lock().unwrap() = wav;
+        *MIDI.lock().unwrap() = midi;
+        *EVENTS.lock().unwrap() = Some(Vec::new());
+        unsafe {
+            super::atexit(finish);
+        }
+    }
+
+    pub fn record(event: Event) {
+        if let Some(events) = EVENTS.lock().unwrap().as_mut() {
+            events.push((super::scheduler::time(), event));
+        }
+    }
+
+    struct Instrument {
+        program: u8,
+        /// Relative strength of each harmonic.
+        harmonics: &'static [f64],
+        attack: f64,
+        decay: f64,
+        sustain: f64,
+        release: f64,
+    }
+
+    const fn instrument(
+        program: u8,
+        harmonics: &'static [f64],
+        attack: f64,
+        decay: f64,
+        sustain: f64,
+        release: f64,
+    ) -> Instrument {
+        Instrument {
+            program,
+            harmonics,
+            attack,
+            decay,
+            sustain,
+            release,
+        }
+    }
+
+    /// The music extension's instruments, in menu order, with their General
+    /// MIDI programs.
+    #[rustfmt::skip]
+    const INSTRUMENTS: [Instrument; 21] = [
+        instrument(0, &[1.0, 0.5, 0.25, 0.12], 0.005, 0.6, 0.0, 0.1), // piano
+        instrument(4, &[1.0, 0.2, 0.1], 0.005, 0.8, 0.0, 0.2), // electric piano
+        instrument(19, &[1.0, 0.8, 0.6, 0.4, 0.3], 0.02, 0.1, 1.0, 0.05), // organ
+        instrument(24, &[1.0, 0.6, 0.3, 0.2, 0.1], 0.002, 0.4, 0.0, 0.1), // guitar
+        instrument(26, &[1.0, 0.7, 0.5, 0.4, 0.3], 0.002, 0.8, 0.0, 0.1), // electric guitar
+        instrument(32, &[1.0, 0.4, 0.1], 0.005, 0.5, 0.0, 0.1), // bass
+        instrument(45, &[1.0, 0.3, 0.1], 0.002, 0.15, 0.0, 0.05), // pizzicato
+        instrument(42, &[1.0, 0.6, 0.4, 0.3, 0.2], 0.08, 0.3, 0.8, 0.15), // cello
+        instrument(57, &[1.0, 0.9, 0.7, 0.5, 0.3], 0.04, 0.2, 0.8, 0.1), // trombone
+        instrument(71, &[1.0, 0.0, 0.5, 0.0, 0.3], 0.03, 0.2, 0.8, 0.1), // clarinet
+        instrument(65, &[1.0, 0.7, 0.6, 0.5, 0.4], 0.03, 0.2, 0.8, 0.1), // saxophone
+        instrument(73, &[1.0, 0.1, 0.05], 0.05, 0.2, 0.8, 0.1), // flute
+        instrument(75, &[1.0, 0.2], 0.06, 0.2, 0.7, 0.1), // wooden flute
+        instrument(70, &[1.0, 0.8, 0.4, 0.3], 0.04, 0.2, 0.8, 0.1), // bassoon
+        instrument(52, &[1.0, 0.3, 0.2, 0.1], 0.15, 0.3, 0.8, 0.3), // choir
+        instrument(11, &[1.0, 0.0, 0.0, 0.3], 0.002, 1.0, 0.0, 0.3), // vibraphone
+        instrument(10, &[1.0, 0.0, 0.0, 0.0, 0.2], 0.002, 0.5, 0.0, 0.2), // music box
+        instrument(114, &[1.0, 0.4, 0.0, 0.3], 0.002, 0.6, 0.0, 0.2), // steel drum
+        instrument(12, &[1.0, 0.0, 0.0, 0.1], 0.002, 0.25, 0.0, 0.05), // marimba
+        instrument(80, &[1.0, 0.5, 0.33, 0.25, 0.2, 0.17], 0.01, 0.2, 0.9, 0.05), // synth lead
+        instrument(88, &[1.0, 0.5, 0.33, 0.25], 0.3, 0.5, 0.8, 0.5), // synth pad
+    ];
+
+    pub const INSTRUMENT_COUNT: usize = INSTRUMENTS.len();
+
+    struct Drum {
+        key: u8,
+        /// A tone that falls from `freq` to `end_freq`.
+        freq: f64,
+        end_freq: f64,
+        tone_decay: f64,
+        /// How much of the sound is noise, and whether that noise is filtered
+        /// to be brighter.
+        noise: f64,
+        noise_decay: f64,
+        bright: bool,
+    }
+
+    const fn drum(
+        key: u8,
+        freq: f64,
+        end_freq: f64,
+        tone_decay: f64,
+        noise: f64,
+        noise_decay: f64,
+        bright: bool,
+    ) -> Drum {
+        Drum {
+            key,
+            freq,
+            end_freq,
+            tone_decay,
+            noise,
+            noise_decay,
+            bright,
+        }
+    }
+
+    /// The music extension's drums, in menu order, with their General MIDI
+    /// percussion keys.
+    #[rustfmt::skip]
+    const DRUMS: [Drum; 18] = [
+        drum(38, 220.0, 180.0, 0.08, 0.7, 0.15, false), // snare drum
+        drum(36, 150.0, 45.0, 0.25, 0.05, 0.02, false), // bass drum
+        drum(37, 800.0, 600.0, 0.02, 0.5, 0.03, true), // side stick
+        drum(49, 400.0, 400.0, 0.01, 1.0, 0.8, true), // crash cymbal
+        drum(46, 400.0, 400.0, 0.01, 1.0, 0.3, true), // open hi-hat
+        drum(42, 400.0, 400.0, 0.01, 1.0, 0.05, true), // closed hi-hat
+        drum(54, 5000.0, 5000.0, 0.02, 0.9, 0.2, true), // tambourine
+        drum(39, 1000.0, 800.0, 0.01, 0.9, 0.08, false), // hand clap
+        drum(75, 2500.0, 2500.0, 0.05, 0.0, 0.01, false), // claves
+        drum(76, 1200.0, 1100.0, 0.06, 0.1, 0.02, false), // wood block
+        drum(56, 560.0, 540.0, 0.15, 0.05, 0.02, false), // cowbell
+        drum(81, 4000.0, 4000.0, 0.8, 0.0, 0.01, false), // triangle
+        drum(60, 400.0, 330.0, 0.12, 0.1, 0.02, false), // bongo
+        drum(63, 300.0, 220.0, 0.18, 0.1, 0.02, false), // conga
+        drum(69, 400.0, 400.0, 0.01, 1.0, 0.12, true), // cabasa
+        drum(73, 300.0, 300.0, 0.01, 0.9, 0.35, false), // guiro
+        drum(58, 250.0, 250.0, 0.6, 0.6, 0.6, true), // vibraslap
+        drum(78, 600.0, 250.0, 0.2, 0.1, 0.05, false), // cuica
+    ];
+
+    pub const DRUM_COUNT: usize = DRUMS.len();
+
+    enum Source {
+        Sample {
+            samples: &'static [u8],
+            step: f64,
+            position: f64,
+        },
+        Note {
+            instrument: &'static Instrument,
+            freq: f64,
+            duration: f64,
+            volume: f64,
+        },
+        Drum {
+            drum: &'static Drum,
+            noise: u64,
+            last: f64,
+            volume: f64,
+        },
+    }
+
+    struct Voice {
+        id: Option<u64>,
+        target: usize,
+        time: f64,
+        phase: f64,
+        source: Source,
+    }
+
+    fn sample(samples: &[u8], i: usize) -> f64 {
+        i16::from_le_bytes([samples[i * 2], samples[i * 2 + 1]]) as f64 / 32768.0
+    }
+
+    impl Voice {
+        /// The next output frame, or None once the voice has finished.
+        fn next(&mut self, (volume, pitch, pan): (f64, f64, f64)) -> Option<(f64, f64)> {
+            let (t, phase) = (self.time, self.phase);
+            self.time += 1.0 / RATE as f64;
+            let s = match &mut self.source {
+                Source::Sample {
+                    samples,
+                    step,
+                    position,
+                } => {
+                    let i = *position as usize;
+                    if i + 1 >= samples.len() / 2 {
+                        return None;
+                    }
+                    let f = position.fract();
+                    let s = sample(samples, i) * (1.0 - f) + sample(samples, i + 1) * f;
+                    *position += *step * super::playback_rate(pitch);
+                    // An equal power pan, like the Web Audio panner Scratch
+                    // uses.
+                    let x = (pan + 100.0) / 200.0 * PI / 2.0;
+                    let s = s * volume / 100.0;
+                    return Some((s * x.cos(), s * x.sin()));
+                }
+                Source::Note {
+                    instrument: i,
+                    freq,
+                    duration,
+                    volume,
+                } => {
+                    if t > *duration + i.release * 6.0
+                        || (i.sustain == 0.0 && t > i.attack + i.decay * 8.0)
+                    {
+                        return None;
+                    }
+                    let envelope = |t: f64| {
+                        if t < i.attack {
+                            t / i.attack
+                        } else {
+                            i.sustain + (1.0 - i.sustain) * (-(t - i.attack) / i.decay).exp()
+                        }
+                    };
+                    let level = if t < *duration {
+                        envelope(t)
+                    } else {
+                        envelope(*duration) * (-(t - *duration) / i.release).exp()
+                    };
+                    let tone: f64 = i
+                        .harmonics
+                        .iter()
+                        .enumerate()
+                        .map(|(n, h)| h * (phase * (n + 1) as f64 * 2.0 * PI).sin())
+                        .sum();
+                    self.phase = (phase + *freq / RATE as f64).fract();
+                    tone * level * 0.3 * *volume / 100.0
+                }
+                Source::Drum {
+                    drum: d,
+                    noise,
+                    last,
+                    volume,
+                } => {
+                    if t > d.tone_decay.max(d.noise_decay) * 8.0 {
+                        return None;
+                    }
+                    let freq = d.end_freq + (d.freq - d.end_freq) * (-t / 0.05).exp();
+                    self.phase = (phase + freq / RATE as f64).fract();
+                    let tone = (phase * 2.0 * PI).sin() * (-t / d.tone_decay).exp();
+                    *noise ^= *noise << 13;
+                    *noise ^= *noise >> 7;
+                    *noise ^= *noise << 17;
+                    let mut n = (*noise >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+                    if d.bright {
+                        let previous = *last;
+                        *last = n;
+                        n = (n - previous) / 2.0;
+                    }
+                    let n = n * (-t / d.noise_decay).exp();
+                    (tone * (1.0 - d.noise) + n * d.noise) * 0.5 * *volume / 100.0
+                }
+            };
+            // Music isn't panned.
+            let s = s * (PI / 4.0).cos();
+            Some((s, s))
+        }
+    }
+
+    fn frequency(note: f64) -> f64 {
+        440.0 * 2f64.powf((note - 69.0) / 12.0)
+    }
+
+    extern "C" fn finish() {
+        let mut events = match EVENTS.lock().unwrap().take() {
+            Some(events) => events,
+            None => return,
+        };
+        events.sort_by_key(|e| e.0);
+        if let Some(path) = MIDI.lock().unwrap().take() {
+            write_midi(&path, &events);
+        }
+        if let Some(path) = WAV.lock().unwrap().take() {
+            write_wav(&path, events);
+        }
+    }
+
+    /// Mix everything that was played into a stereo WAV file. Sounds still
+    /// playing at exit are played to the end, as they would be in Scratch.
+    fn write_wav(path: &str, events: Vec<(Duration, Event)>) {
+        let targets = super::TARGETS.lock().unwrap();
+
+        let mut mix = vec![(100.0, 0.0, 0.0); targets.len()];
+        let mut voices: Vec<Voice> = vec![];
+        let mut out: Vec<i16> = vec![];
+        let mut events = events.into_iter().peekable();
+        let mut frame = 0u64;
+        loop {
+            let now = Duration::from_secs_f64(frame as f64 / RATE as f64);
+            while let Some((_, event)) = events.next_if(|e| e.0 <= now) {
+                let (id, target, source) = match event {
+                    Event::Play {
+                        voice,
+                        target,
+                        sound,
+                    } => {
+                        let sound = &targets[target].sounds[sound];
+                        let source = Source::Sample {
+                            samples: sound.samples,
+                            step: sound.rate as f64 / RATE as f64,
+                            position: 0.0,
+                        };
+                        (Some(voice), target, source)
+                    }
+                    Event::Note {
+                        target,
+                        instrument,
+                        note,
+                        duration,
+                        volume,
+                    } => {
+                        let source = Source::Note {
+                            instrument: &INSTRUMENTS[instrument],
+                            freq: frequency(note),
+                            duration,
+                            volume,
+                        };
+                        (None, target, source)
+                    }
+                    Event::Drum {
+                        target,
+                        drum,
+                        volume,
+                    } => {
+                        let source = Source::Drum {
+                            drum: &DRUMS[drum],
+                            noise: 0x2545_f491_4f6c_dd1d ^ frame,
+                            last: 0.0,
+                            volume,
+                        };
+                        (None, target, source)
+                    }
+                    Event::Stop { voice } => {
+                        voices.retain(|v| v.id != Some(voice));
+                        continue;
+                    }
+                    Event::Mix {
+                        target,
+                        volume,
+                        pitch,
+                        pan,
+                    } => {
+                        mix[target] = (volume, pitch, pan);
+                        continue;
+                    }
+                };
+                voices.push(Voice {
+                    id,
+                    target,
+                    time: 0.0,
+                    phase: 0.0,
+                    source,
+                });
+            }
+            if voices.is_empty() && events.peek().is_none() {
+                break;
+            }
+
+            let (mut left, mut right) = (0.0, 0.0);
+            voices.retain_mut(|v| match v.next(mix[v.target]) {
+                Some((l, r)) => {
+                    left += l;
+                    right += r;
+                    true
+                }
+                None => false,
+            });
+            for s in &[left, right] {
+                out.push((s.clamp(-1.0, 1.0) * 32767.0) as i16);
+            }
+            frame += 1;
+        }
+
+        let mut file = BufWriter::new(File::create(path).unwrap());
+        let data = out.len() as u32 * 2;
+        file.write_all(b"RIFF").unwrap();
+        file.write_all(&(36 + data).to_le_bytes()).unwrap();
+        file.write_all(b"WAVEfmt ").unwrap();
+        file.write_all(&16u32.to_le_bytes()).unwrap();
+        file.write_all(&1u16.to_le_bytes()).unwrap();
+        file.write_all(&2u16.to_le_bytes()).unwrap();
+        file.write_all(&RATE.to_le_bytes()).unwrap();
+        file.write_all(&(RATE * 4).to_le_bytes()).unwrap();
+        file.write_all(&4u16.to_le_bytes()).unwrap();
+        file.write_all(&16u16.to_le_bytes()).unwrap();
+        file.write_all(b"data").unwrap();
+        file.write_all(&data.to_le_bytes()).unwrap();
+        for s in out {
+            file.write_all(&s.to_le_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+    }
+
+    fn variable_length(mut n: u64, out: &mut Vec<u8>) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        n >>= 7;
+        while n > 0 {
+            bytes.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    /// Write the music extension's notes and drums as a standard MIDI file,
+    /// with one channel per sprite. Sounds aren't included.
+    fn write_midi(path: &str, events: &[(Duration, Event)]) {
+        // Each event is (milliseconds, order, status, key, velocity), where
+        // order puts note offs before note ons at the same time.
+        let mut messages = vec![];
+        let mut programs = [None; 16];
+        for (time, event) in events {
+            let ms = time.as_millis() as u64;
+            let (channel, key, program, length, volume) = match event {
+                Event::Note {
+                    target,
+                    instrument,
+                    note,
+                    duration,
+                    volume,
+                } => {
+                    // Channel 9 is for percussion.
+                    let channel = match target % 15 {
+                        c if c >= 9 => c + 1,
+                        c => c,
+                    } as u8;
+                    let program = Some(INSTRUMENTS[*instrument].program);
+                    let length = (duration * 1000.0) as u64;
+                    (channel, note.round() as u8, program, length, volume)
+                }
+                Event::Drum { drum, volume, .. } => (9, DRUMS[*drum].key, None, 100, volume),
+                _ => continue,
+            };
+            if let Some(p) = program.filter(|_| programs[channel as usize] != program) {
+                programs[channel as usize] = program;
+                messages.push((ms, 1, 0xc0 | channel, p, None));
+            }
+            let velocity = (volume / 100.0 * 127.0).round() as u8;
+            messages.push((ms, 2, 0x90 | channel, key, Some(velocity.max(1))));
+            messages.push((ms + length, 0, 0x80 | channel, key, Some(64)));
+        }
+        messages.sort_by_key(|m| (m.0, m.1));
+
+        let mut track = vec![];
+        // One tick per millisecond: 1000 ticks per quarter note at 60 BPM.
+        track.extend_from_slice(&[0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40]);
+        let mut last = 0;
+        for (ms, _, status, key, velocity) in messages {
+            variable_length(ms - last, &mut track);
+            last = ms;
+            track.push(status);
+            track.push(key);
+            if let Some(velocity) = velocity {
+                track.push(velocity);
+            }
+        }
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+        let mut file = BufWriter::new(File::create(path).unwrap());
+        file.write_all(b"MThd").unwrap();
+        file.write_all(&6u32.to_be_bytes()).unwrap();
+        file.write_all(&0u16.to_be_bytes()).unwrap();
+        file.write_all(&1u16.to_be_bytes()).unwrap();
+        file.write_all(&1000u16.to_be_bytes()).unwrap();
+        file.write_all(b"MTrk").unwrap();
+        file.write_all(&(track.len() as u32).to_be_bytes()).unwrap();
+        file.write_all(&track).unwrap();
+        file.flush().unwrap();
+    }
+}
+
+/// Renders the stage to the terminal with half block characters, two stage
+/// pixels per character cell.
+mod display {
+    use super::limit;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 30);
+
+    const STAGE_WIDTH: f64 = 480.0;
+    const STAGE_HEIGHT: f64 = 360.0;
+
+    struct Terminal {
+        width: usize,
+        height: usize,
+    }
+
+    static TERMINAL: Mutex<Option<Terminal>> = Mutex::new(None);
+
+    pub fn active() -> bool {
+        super::DISPLAY.load(Ordering::SeqCst)
+    }
+
+    pub fn start() {
+        let (rows, cols) = File::open("/dev/tty")
+            .ok()
+            .and_then(|tty| super::stty(&["size"], tty.into()))
+            .and_then(|size| {
+                let mut size = size.split(' ').map(|n| n.parse::<usize>().ok());
+                Some((size.next()??, size.next()??))
+            })
+            .unwrap_or((24, 80));
+
+        // Keep the stage's 4:3 aspect with square pixels, leaving a row for
+        // the shell prompt.
+        let mut width = cols.min((rows - 1) * 2 * 4 / 3);
+        let mut height = width * 3 / 4;
+        height -= height % 2;
+        width = width.max(4);
+        height = height.max(2);
+
+        *TERMINAL.lock().unwrap() = Some(Terminal { width, height });
+        super::DISPLAY.store(true, Ordering::SeqCst);
+
+        print!("\x1b[2J\x1b[?25l");
+        unsafe {
+            super::atexit(stop);
+        }
+
+        // Also takes over Ctrl-C, so the terminal is restored on the way out.
+        super::input::listen();
+
+        std::thread::spawn(|| {
+            let mut next = Instant::now();
+            loop {
+                draw();
+                next += FRAME;
+                let now = Instant::now();
+                if next > now {
+                    std::thread::sleep(next - now);
+                } else {
+                    next = now;
+                }
+            }
+        });
+    }
+
+    extern "C" fn stop() {
+        let mut terminal = TERMINAL.lock().unwrap();
+        if let Some(t) = terminal.as_ref() {
+            draw_frame(t);
+            print!("\x1b[0m\x1b[?25h\x1b[{};1H", t.height / 2 + 1);
+            std::io::stdout().flush().unwrap();
+        }
+        *terminal = None;
+    }
+
+    fn draw() {
+        if let Some(t) = TERMINAL.lock().unwrap().as_ref() {
+            draw_frame(t);
+        }
+    }
+
+    fn blend(dst: &mut [u8; 3], src: &[u8]) {
+        let a = src[3] as u32;
+        for i in 0..3 {
+            dst[i] = ((src[i] as u32 * a + dst[i] as u32 * (255 - a)) / 255) as u8;
+        }
+    }
+
+    fn sample(t: &super::Target, x: f64, y: f64) -> Option<[u8; 4]> {
+        let c = t.costumes.get(t.costume)?;
+        if c.width == 0 || c.height == 0 {
+            return None;
+        }
+        let scale = t.size / 100.0;
+        let (mut dx, mut dy) = ((x - t.x) / scale, (y - t.y) / scale);
+        match t.rotation_style {
+            0 => {
+                let r = (t.direction - 90.0).to_radians();
+                let (s, c) = r.sin_cos();
+                let rx = dx * c - dy * s;
+                dy = dx * s + dy * c;
+                dx = rx;
+            }
+            1 if t.direction < 0.0 => dx = -dx,
+            _ => {}
+        }
+        let (w, h) = (c.width as f64, c.height as f64);
+        let px = c.rotation_center.0 + dx * c.resolution;
+        let py = c.rotation_center.1 - dy * c.resolution;
+        if px < 0.0 || py < 0.0 || px >= w || py >= h {
+            return None;
+        }
+        let (mut u, mut v) = distort(&t.effects, px / w, py / h, w / c.resolution);
+        u = limit(u, 0.0, 1.0 - 1e-9);
+        v = limit(v, 0.0, 1.0 - 1e-9);
+        let i = ((v * h) as usize * c.width + (u * w) as usize) * 4;
+        let mut p = [0; 4];
+        p.copy_from_slice(&c.pixels[i..i + 4]);
+        recolor(&t.effects, &mut p);
+        Some(p)
+    }
+
+    /// The texture coordinate effects, in the same order as Scratch's shader.
+    fn distort(effects: &[f64; 7], mut u: f64, mut v: f64, width: f64) -> (f64, f64) {
+        let mosaic = effects[super::MOSAIC];
+        if mosaic != 0.0 {
+            let n = limit(((mosaic.abs() + 10.0) / 10.0).round(), 1.0, 512.0);
+            u = (u * n).fract();
+            v = (v * n).fract();
+        }
+        let pixelate = effects[super::PIXELATE].abs() / 10.0;
+        if pixelate != 0.0 {
+            let cells = width / pixelate;
+            u = ((u * cells).floor() + 0.5) / cells;
+            v = ((v * cells).floor() + 0.5) / cells;
+        }
+        let whirl = effects[super::WHIRL];
+        if whirl != 0.0 {
+            let (ox, oy) = (u - 0.5, v - 0.5);
+            let factor = (1.0 - (ox * ox + oy * oy).sqrt() / 0.5).max(0.0);
+            let (s, c) = (-whirl.to_radians() * factor * factor).sin_cos();
+            u = c * ox - s * oy + 0.5;
+            v = s * ox + c * oy + 0.5;
+        }
+        let fisheye = effects[super::FISHEYE];
+        if fisheye != 0.0 {
+            let power = ((fisheye + 100.0) / 100.0).max(0.0);
+            let (vx, vy) = ((u - 0.5) / 0.5, (v - 0.5) / 0.5);
+            let length = (vx * vx + vy * vy).sqrt();
+            if length > 0.0 {
+                let r = length.min(1.0).powf(power) * length.max(1.0);
+                u = 0.5 + r * vx / length * 0.5;
+                v = 0.5 + r * vy / length * 0.5;
+            }
+        }
+        (u, v)
+    }
+
+    fn recolor(effects: &[f64; 7], p: &mut [u8; 4]) {
+        let color = effects[super::COLOR];
+        if color != 0.0 {
+            let (h, s, v) = hsv(p);
+            let h = (h + color / 200.0).rem_euclid(1.0);
+            rgb(p, h, s, v);
+        }
+        let brightness = effects[super::BRIGHTNESS];
+        if brightness != 0.0 {
+            for c in p.iter_mut().take(3) {
+                *c = limit(*c as f64 + brightness / 100.0 * 255.0, 0.0, 255.0) as u8;
+            }
+        }
+        let ghost = effects[super::GHOST];
+        if ghost != 0.0 {
+            p[3] = (p[3] as f64 * (1.0 - ghost / 100.0)) as u8;
+        }
+    }
+
+    fn hsv(p: &[u8; 4]) -> (f64, f64, f64) {
+        let (r, g, b) = (
+            p[0] as f64 / 255.0,
+            p[1] as f64 / 255.0,
+            p[2] as f64 / 255.0,
+        );
+        let max = r.max(g).max(b);
+        let d = max - r.min(g).min(b);
+        let h = if d == 0.0 {
+            0.0
+        } else if max == r {
+            ((g - b) / d).rem_euclid(6.0) / 6.0
+        } else if max == g {
+            ((b - r) / d + 2.0) / 6.0
+        } else {
+            ((r - g) / d + 4.0) / 6.0
+        };
+        let s = if max == 0.0 { 0.0 } else { d / max };
+        (h, s, max)
+    }
+
+    fn rgb(p: &mut [u8; 4], h: f64, s: f64, v: f64) {
+        let f = |n: f64| {
+            let k = (n + h * 6.0) % 6.0;
+            let k = k.min(4.0 - k).min(1.0);
+            let c = v - v * s * k.max(0.0);
+            (c * 255.0).round() as u8
+        };
+        p[0] = f(5.0);
+        p[1] = f(3.0);
+        p[2] = f(1.0);
+    }
+
+    fn draw_frame(terminal: &Terminal) {
+        let (width, height) = (terminal.width, terminal.height);
+        let mut pixels = vec![[255u8; 3]; width * height];
+        let mut text: Vec<Option<char>> = vec![None; width * height / 2];
+
+        {
+            let targets = super::TARGETS.lock().unwrap();
+            let mut order: Vec<_> = targets.iter().filter(|t| t.visible).collect();
+            order.sort_by_key(|t| (!t.is_stage, t.layer));
+
+            for py in 0..height {
+                for px in 0..width {
+                    let x = (px as f64 + 0.5) / width as f64 * STAGE_WIDTH - STAGE_WIDTH / 2.0;
+                    let y = STAGE_HEIGHT / 2.0 - (py as f64 + 0.5) / height as f64 * STAGE_HEIGHT;
+                    for t in &order {
+                        if let Some(p) = sample(t, x, y) {
+                            blend(&mut pixels[py * width + px], &p);
+                        }
+                    }
+                }
+            }
+
+            for t in &order {
+                let (speech, bubble) = match &t.bubble {
+                    Some(b) => b,
+                    None => continue,
+                };
+                let top = t
+                    .costumes
+                    .get(t.costume)
+                    .map(|c| c.rotation_center.1 / c.resolution * t.size / 100.0)
+                    .unwrap_or(0.0);
+                let col = ((t.x + STAGE_WIDTH / 2.0) / STAGE_WIDTH * width as f64) as isize;
+                let row = ((STAGE_HEIGHT / 2.0 - t.y - top) / STAGE_HEIGHT * height as f64 / 2.0)
+                    as isize
+                    - 1;
+                let row = row.max(0).min(height as isize / 2 - 1) as usize;
+                let label: Vec<char> = match speech {
+                    super::Speech::Say => format!(" {} ", bubble),
+                    super::Speech::Think => format!("( {} )", bubble),
+                }
+                .chars()
+                .take(width)
+                .collect();
+                let col = col.max(0).min((width - label.len()) as isize) as usize;
+                for (i, c) in label.into_iter().enumerate() {
+                    text[row * width + col + i] = Some(c);
+                }
+            }
+        }
+
+        let mut out = String::new();
+        for row in 0..height / 2 {
+            out.push_str(&format!("\x1b[{};1H", row + 1));
+            for col in 0..width {
+                match text[row * width + col] {
+                    Some(c) => {
+                        out.push_str("\x1b[38;2;0;0;0;48;2;255;255;255m");
+                        out.push(c);
+                    }
+                    None => {
+                        let top = pixels[row * 2 * width + col];
+                        let bottom = pixels[(row * 2 + 1) * width + col];
+                        out.push_str(&format!(
+                            "\x1b[38;2;{};{};{};48;2;{};{};{}m\u{2580}",
+                            top[0], top[1], top[2], bottom[0], bottom[1], bottom[2],
+                        ));
+                    }
+                }
+            }
+            out.push_str("\x1b[0m");
+        }
+
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(out.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+    }
+}
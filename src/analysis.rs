@@ -0,0 +1,110 @@
+use crate::scratch::{BlockInfo, TargetInfo};
+use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
+
+/// Hats we know how to fire. Scripts under any other hat never run.
+const HATS: &[&str] = &[
+    "event_whenflagclicked",
+    "event_whenkeypressed",
+    "event_whenbackdropswitchesto",
+];
+
+/// The top level blocks of a target that can run: stacks under hats that can
+/// fire, and the custom blocks they call. Everything else is left out of the
+/// binary, with a warning saying why.
+pub struct Reachability {
+    pub live: HashSet<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Reachability {
+    pub fn analyze(target: &TargetInfo, backdrops: &[&str]) -> Reachability {
+        let root = |id| root(&target.blocks, id);
+
+        let mut calls: HashMap<&String, Vec<&str>> = HashMap::new();
+        let mut definitions = HashMap::new();
+        for (id, b) in &target.blocks {
+            match b.opcode.as_str() {
+                "procedures_call" => calls
+                    .entry(root(id))
+                    .or_default()
+                    .push(proccode(b.mutation.as_ref())),
+                "procedures_prototype" => {
+                    definitions.insert(proccode(b.mutation.as_ref()), root(id));
+                }
+                _ => {}
+            }
+        }
+
+        let mut roots: Vec<_> = target.blocks.iter().filter(|(_, b)| b.top_level).collect();
+        roots.sort_by_key(|(id, _)| *id);
+
+        let mut warnings = vec![];
+        let mut queue = vec![];
+        for (id, b) in &roots {
+            let opcode = b.opcode.as_str();
+            if opcode == "procedures_definition" {
+                continue;
+            }
+            if !HATS.contains(&opcode) {
+                if opcode.contains("_when") || opcode == "control_start_as_clone" {
+                    warnings.push(format!("`{}` hats are not supported yet", opcode));
+                } else {
+                    warnings.push(format!(
+                        "stack starting with `{}` has no hat, so it never runs",
+                        opcode
+                    ));
+                }
+                continue;
+            }
+            if opcode == "event_whenbackdropswitchesto" {
+                let backdrop = b.fields["BACKDROP"][0].as_str().unwrap();
+                // Hats match backdrop names case insensitively.
+                if !backdrops.iter().any(|b| b.eq_ignore_ascii_case(backdrop)) {
+                    warnings.push(format!(
+                        "there is no backdrop named {:?}, so `when backdrop switches to` never fires",
+                        backdrop
+                    ));
+                    continue;
+                }
+            }
+            queue.push(*id);
+        }
+
+        let mut live = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if !live.insert(id.clone()) {
+                continue;
+            }
+            for proc in calls.get(id).into_iter().flatten() {
+                // Calls to blocks that aren't defined do nothing.
+                if let Some(definition) = definitions.get(proc) {
+                    queue.push(definition);
+                }
+            }
+        }
+
+        let mut unused: Vec<_> = definitions
+            .iter()
+            .filter(|(_, id)| !live.contains(**id))
+            .map(|(proc, _)| *proc)
+            .collect();
+        unused.sort();
+        for proc in unused {
+            warnings.push(format!("custom block {:?} is never used", proc));
+        }
+
+        Reachability { live, warnings }
+    }
+}
+
+fn root<'a>(blocks: &'a IndexMap<String, BlockInfo>, mut id: &'a String) -> &'a String {
+    while let Some(parent) = &blocks[id].parent {
+        id = parent;
+    }
+    id
+}
+
+fn proccode(mutation: Option<&crate::scratch::MutationInfo>) -> &str {
+    mutation.unwrap().proccode.as_ref().unwrap()
+}
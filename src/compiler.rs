@@ -1,30 +1,54 @@
-use crate::scratch;
+use crate::ir::{self, BinaryOp, CompareOp, Expr, Stmt};
+use crate::{costume, dwarf, scratch, source, Emit};
+use cranelift::codegen::isa::TargetIsa;
 use cranelift::prelude::*;
 use cranelift_module::Module;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
 
 struct Compiler<M: Module> {
     module: M,
-    data_id_counter: usize,
+    /// The symbol of the function being compiled, which names its strings.
+    function: String,
+    strings: usize,
     var_id_counter: usize,
     scratch_vars: HashMap<String, cranelift_module::DataId>,
-    procedures: HashMap<String, cranelift_module::FuncId>,
+    functions: Vec<cranelift_module::FuncId>,
+    /// Run Cranelift's early optimizations on every function.
+    optimize: bool,
+    emit: Emit,
+    /// Every function's IR or assembly so far, when that's what to emit.
+    listing: String,
+    /// The line of every block in each target's source, when compiling with
+    /// debug info.
+    lines: Option<Vec<HashMap<String, u32>>>,
+    /// The target of the function being compiled. `main` doesn't have one.
+    target: Option<usize>,
+    debug: Vec<dwarf::Function>,
 }
 
 impl<M: Module> Compiler<M> {
-    fn new(module: M) -> Compiler<M> {
+    fn new(module: M, optimize: bool, emit: Emit) -> Compiler<M> {
         Compiler {
             module,
-            data_id_counter: 0,
+            optimize,
+            emit,
+            listing: String::new(),
+            lines: None,
+            target: None,
+            debug: vec![],
+            function: String::new(),
+            strings: 0,
             var_id_counter: 0,
             scratch_vars: HashMap::new(),
-            procedures: HashMap::new(),
+            functions: Vec::new(),
         }
     }
 
     fn compile_func<F>(
         &mut self,
         name: &str,
+        label: &str,
         params: &[Type],
         ret: Option<Type>,
         export: bool,
@@ -57,9 +81,11 @@ impl<M: Module> Compiler<M> {
         let mut ctx = self.module.make_context();
         let mut fn_builder_ctx = FunctionBuilderContext::new();
         ctx.func = cranelift::codegen::ir::Function::with_name_signature(
-            ExternalName::testcase(name),
+            ExternalName::user(0, func_id.as_u32()),
             sig,
         );
+        self.function = name.to_owned();
+        self.strings = 0;
 
         let mut f = FunctionBuilder::new(&mut ctx.func, &mut fn_builder_ctx);
 
@@ -71,9 +97,24 @@ impl<M: Module> Compiler<M> {
         cranelift::codegen::verifier::verify_function(&ctx.func, self.module.isa().flags())
             .unwrap();
 
-        // println!("{}", ctx.func.display(None));
+        if self.optimize {
+            cranelift_preopt::optimize(&mut ctx, self.module.isa()).unwrap();
+        }
+
+        if self.emit == Emit::Clif {
+            writeln!(
+                self.listing,
+                "; {}\n; {}\n{}",
+                name,
+                label,
+                ctx.func.display(None)
+            )
+            .unwrap();
+        }
 
-        self.module
+        ctx.want_disasm = self.emit == Emit::Asm;
+        let compiled = self
+            .module
             .define_function(
                 func_id,
                 &mut ctx,
@@ -81,6 +122,27 @@ impl<M: Module> Compiler<M> {
             )
             .unwrap();
 
+        if self.lines.is_some() {
+            self.debug.push(dwarf::Function {
+                id: func_id,
+                target: self.target,
+                label: label.to_owned(),
+                size: compiled.size,
+                rows: rows(&ctx, self.module.isa()),
+            });
+        }
+
+        if self.emit == Emit::Asm {
+            writeln!(self.listing, "; {}\n; {}", name, label).unwrap();
+            match ctx.mach_compile_result.and_then(|result| result.disasm) {
+                Some(disasm) => writeln!(self.listing, "{}", disasm).unwrap(),
+                // The old x86 backend can't disassemble, but the function
+                // it leaves behind has the encoding and registers it chose
+                // for every instruction.
+                None => writeln!(self.listing, "{}", ctx.func.display(self.module.isa())).unwrap(),
+            }
+        }
+
         func_id
     }
 
@@ -90,19 +152,10 @@ impl<M: Module> Compiler<M> {
         Variable::new(id)
     }
 
-    fn create_data(&mut self, data: Box<[u8]>) -> cranelift_module::DataId {
+    fn create_data(&mut self, name: &str, data: Box<[u8]>) -> cranelift_module::DataId {
         let data_id = self
             .module
-            .declare_data(
-                &format!("data_{}", {
-                    let id = self.data_id_counter;
-                    self.data_id_counter += 1;
-                    id
-                }),
-                cranelift_module::Linkage::Local,
-                false,
-                false,
-            )
+            .declare_data(name, cranelift_module::Linkage::Local, false, false)
             .unwrap();
         let mut ctx = cranelift_module::DataContext::new();
         ctx.define(data);
@@ -110,6 +163,19 @@ impl<M: Module> Compiler<M> {
         data_id
     }
 
+    fn data_ptr(&mut self, data: cranelift_module::DataId, f: &mut FunctionBuilder) -> Value {
+        let tmp = self.module.declare_data_in_func(data, f.func);
+        f.ins()
+            .global_value(self.module.target_config().pointer_type(), tmp)
+    }
+
+    fn string_ptr(&mut self, s: &str, f: &mut FunctionBuilder) -> Value {
+        let name = format!("{}.str.{}", self.function, self.strings);
+        self.strings += 1;
+        let data = self.create_data(&name, format!("{}\0", s).into_bytes().into());
+        self.data_ptr(data, f)
+    }
+
     fn import_func(
         &mut self,
         name: &str,
@@ -131,15 +197,48 @@ impl<M: Module> Compiler<M> {
         self.module.declare_func_in_func(func, f.func)
     }
 
-    fn create_scratch_var(&mut self, name: &str) {
+    /// Refer to one of the project's own functions.
+    fn func_ref(
+        &mut self,
+        func: cranelift_module::FuncId,
+        f: &mut FunctionBuilder,
+    ) -> cranelift::codegen::ir::FuncRef {
+        let tmp = self.module.declare_func_in_func(func, f.func);
+        // cranelift-object can't relocate direct calls on AArch64 yet, so go
+        // through the function's address there.
+        if let target_lexicon::Architecture::Aarch64(_) = self.module.isa().triple().architecture {
+            f.func.dfg.ext_funcs[tmp].colocated = false;
+        }
+        tmp
+    }
+
+    fn create_scratch_var(&mut self, global: &ir::Global) {
         let data_id = self
             .module
-            .declare_data(name, cranelift_module::Linkage::Local, true, false)
+            .declare_data(
+                &global.symbol,
+                cranelift_module::Linkage::Local,
+                true,
+                false,
+            )
             .unwrap();
         let mut ctx = cranelift_module::DataContext::new();
-        ctx.define(Box::new([0; std::mem::size_of::<f64>()]));
+        match &global.init {
+            Expr::Number(n) => ctx.define(Box::new(n.to_ne_bytes())),
+            Expr::String(s) => {
+                let string = self.create_data(
+                    &format!("{}.init", global.symbol),
+                    format!("{}\0", s).into_bytes().into(),
+                );
+                let pointer = self.module.target_config().pointer_bytes();
+                ctx.define(vec![0; pointer as usize].into());
+                let string = self.module.declare_data_in_data(string, &mut ctx);
+                ctx.write_data_addr(0, string, 0);
+            }
+            init => unreachable!("{}", init),
+        }
         self.module.define_data(data_id, &ctx).unwrap();
-        self.scratch_vars.insert(name.to_owned(), data_id);
+        self.scratch_vars.insert(global.id.clone(), data_id);
     }
 
     fn scratch_var_ptr(&mut self, name: &str, f: &mut FunctionBuilder) -> Value {
@@ -149,9 +248,9 @@ impl<M: Module> Compiler<M> {
             .global_value(self.module.target_config().pointer_type(), data_ref)
     }
 
-    fn load_scratch_var(&mut self, name: &str, f: &mut FunctionBuilder) -> Value {
+    fn load_scratch_var(&mut self, name: &str, ty: Type, f: &mut FunctionBuilder) -> Value {
         let ptr = self.scratch_var_ptr(name, f);
-        f.ins().load(types::F64, MemFlags::new(), ptr, 0)
+        f.ins().load(ty, MemFlags::new(), ptr, 0)
     }
 
     fn store_scratch_var(&mut self, name: &str, val: Value, f: &mut FunctionBuilder) {
@@ -160,21 +259,23 @@ impl<M: Module> Compiler<M> {
     }
 }
 
-struct BlockCompiler<'a, 'b, M: Module> {
+/// A Scratch variable kept in a Cranelift variable while a function runs. It
+/// only has to be in memory when another script or function could look at it.
+struct Promoted {
+    var: Variable,
+    ty: Type,
+    /// Whether the function sets it, so it has to be written back.
+    written: bool,
+}
+
+struct FunctionCompiler<'a, 'b, M: Module> {
     c: &'b mut Compiler<M>,
     f: &'b mut FunctionBuilder<'a>,
-    ends: Vec<Block>,
-    args: HashMap<String, Variable>,
+    params: Vec<Variable>,
+    globals: BTreeMap<String, Promoted>,
 }
 
-impl<'a, 'b, M: Module> BlockCompiler<'a, 'b, M> {
-    fn fall_off_end(&mut self) {
-        match self.ends.last() {
-            Some(b) => self.f.ins().jump(*b, &[]),
-            None => self.f.ins().return_(&[]),
-        };
-    }
-
+impl<'a, 'b, M: Module> FunctionCompiler<'a, 'b, M> {
     fn import_func(
         &mut self,
         name: &str,
@@ -183,292 +284,718 @@ impl<'a, 'b, M: Module> BlockCompiler<'a, 'b, M> {
     ) -> cranelift::codegen::ir::FuncRef {
         self.c.import_func(name, params, ret, self.f)
     }
-}
 
-impl scratch::Value {
-    fn build(&self, c: &mut BlockCompiler<impl Module>) -> Value {
-        match self {
-            scratch::Value::Number(n) => c.f.ins().f64const(*n),
-            scratch::Value::String(s) => match s.parse::<f64>() {
-                Ok(n) => c.f.ins().f64const(n),
-                Err(_) => c.f.ins().f64const(0.0),
-            },
-            scratch::Value::Load(id) => c.c.load_scratch_var(id, c.f),
-            scratch::Value::Expression(b) => match &**b {
-                scratch::BlockExpression::OperatorEquals { left, right } => {
-                    let a1 = left.build(c);
-                    let a2 = right.build(c);
-                    c.f.ins().fcmp(FloatCC::Equal, a1, a2)
-                }
-                scratch::BlockExpression::OperatorGT { left, right } => {
-                    let a1 = left.build(c);
-                    let a2 = right.build(c);
-                    c.f.ins().fcmp(FloatCC::GreaterThan, a1, a2)
-                }
-                scratch::BlockExpression::OperatorAdd { left, right } => {
-                    let a1 = left.build(c);
-                    let a2 = right.build(c);
-                    c.f.ins().fadd(a1, a2)
-                }
-                scratch::BlockExpression::OperatorSubtract { left, right } => {
-                    let a1 = left.build(c);
-                    let a2 = right.build(c);
-                    c.f.ins().fsub(a1, a2)
-                }
-                scratch::BlockExpression::ArgumentReporterStringNumber { name } => {
-                    let var = c.args[name];
-                    c.f.use_var(var)
-                }
-            },
-        }
+    fn call_func(&mut self, name: &str, args: &[Value], ret: Option<Type>) -> Option<Value> {
+        let params: Vec<_> = args
+            .iter()
+            .map(|arg| self.f.func.dfg.value_type(*arg))
+            .collect();
+        let func = self.import_func(name, &params, ret);
+        let call = self.f.ins().call(func, args);
+        self.f.inst_results(call).first().copied()
     }
-}
 
-impl scratch::Block {
-    fn build(&self, c: &mut BlockCompiler<impl Module>, block: Block) {
-        c.f.switch_to_block(block);
+    fn pointer_type(&self) -> Type {
+        self.c.module.target_config().pointer_type()
+    }
 
-        match &self.op {
-            scratch::BlockOp::ControlRepeat { times, body } => {
-                let head = c.f.create_block();
-                let bbody = c.f.create_block();
-                let bnext = c.f.create_block();
-                let vtimes = c.c.new_var();
+    /// Convert a value to how it's passed to functions, since `b1` can't be.
+    fn lower_argument(&mut self, v: Value, ty: ir::Type) -> Value {
+        match ty {
+            ir::Type::Bool => self.f.ins().bint(types::I32, v),
+            _ => v,
+        }
+    }
 
-                {
-                    c.f.declare_var(vtimes, types::I32);
+    fn lift_argument(&mut self, v: Value, ty: ir::Type) -> Value {
+        match ty {
+            ir::Type::Bool => self.f.ins().icmp_imm(IntCC::NotEqual, v, 0),
+            _ => v,
+        }
+    }
 
-                    let tmp = times.build(c);
-                    let tmp = c.f.ins().fcvt_to_uint(types::I32, tmp);
-                    let one = c.f.ins().iconst(types::I32, 1);
-                    let tmp = c.f.ins().iadd(tmp, one);
-                    c.f.def_var(vtimes, tmp);
+    /// Read the promoted globals, which other scripts may have changed.
+    fn load_globals(&mut self) {
+        for (id, global) in &self.globals {
+            let tmp = self.c.load_scratch_var(id, global.ty, self.f);
+            self.f.def_var(global.var, tmp);
+        }
+    }
 
-                    c.f.ins().jump(head, &[]);
-                }
+    /// Write back the promoted globals, before anything else can see them.
+    fn store_globals(&mut self) {
+        for (id, global) in &self.globals {
+            if global.written {
+                let tmp = self.f.use_var(global.var);
+                self.c.store_scratch_var(id, tmp, self.f);
+            }
+        }
+    }
 
-                {
-                    c.f.switch_to_block(head);
-                    let a1 = c.f.use_var(vtimes);
-                    let a2 = c.f.ins().iconst(types::I32, 1);
-                    let tmp = c.f.ins().isub(a1, a2);
-                    c.f.def_var(vtimes, tmp);
-                    c.f.ins().brz(tmp, bnext, &[]);
-                    c.f.ins().jump(bbody, &[]);
-                }
+    fn cast(&mut self, v: Value, from: ir::Type, to: ir::Type) -> Value {
+        match (from, to) {
+            (from, to) if from == to => v,
+            (ir::Type::Number, ir::Type::Bool) => {
+                let zero = self.f.ins().f64const(0.0);
+                // NaN is false as well.
+                self.f.ins().fcmp(FloatCC::OrderedNotEqual, v, zero)
+            }
+            (ir::Type::Bool, ir::Type::Number) => {
+                let tmp = self.f.ins().bint(types::I32, v);
+                self.f.ins().fcvt_from_sint(types::F64, tmp)
+            }
+            (ir::Type::Bool, ir::Type::String) => {
+                let t = self.c.string_ptr("true", self.f);
+                let f = self.c.string_ptr("false", self.f);
+                self.f.ins().select(v, t, f)
+            }
+            (ir::Type::String, ir::Type::Number) => self
+                .call_func("support_string_to_number", &[v], Some(types::F64))
+                .unwrap(),
+            (ir::Type::String, ir::Type::Bool) => {
+                let tmp = self
+                    .call_func("support_string_to_bool", &[v], Some(types::I32))
+                    .unwrap();
+                self.f.ins().icmp_imm(IntCC::NotEqual, tmp, 0)
+            }
+            (ir::Type::Number, ir::Type::String) => {
+                let p = self.pointer_type();
+                self.call_func("support_number_to_string", &[v], Some(p))
+                    .unwrap()
+            }
+            _ => unreachable!("cast from {} to {}", from, to),
+        }
+    }
 
-                c.ends.push(head);
-                body.build(c, bbody);
-                c.ends.pop();
+    fn call(&mut self, call: &ir::Call) -> Option<Value> {
+        let mut args = vec![];
+        for arg in &call.args {
+            let tmp = self.expr(arg);
+            args.push(self.lower_argument(tmp, arg.ty()));
+        }
+        let pointer = self.pointer_type();
+        let ret = call.ret.map(|ty| abi(ty, pointer));
+        let tmp = self.call_func(&format!("support_{}", call.func), &args, ret);
+        tmp.map(|tmp| self.lift_argument(tmp, call.ret.unwrap()))
+    }
 
-                if let Some(next) = &self.next {
-                    next.build(c, bnext);
-                } else {
-                    c.f.switch_to_block(bnext);
-                    c.fall_off_end();
+    fn expr(&mut self, e: &Expr) -> Value {
+        match e {
+            Expr::Number(n) => self.f.ins().f64const(*n),
+            Expr::Bool(b) => self.f.ins().bconst(types::B1, *b),
+            Expr::String(s) => self.c.string_ptr(s, self.f),
+            Expr::Int(i) => self.f.ins().iconst(types::I32, *i),
+            Expr::Global(id, ty) => match self.globals.get(id) {
+                Some(global) => self.f.use_var(global.var),
+                None => {
+                    let pointer = self.pointer_type();
+                    self.c.load_scratch_var(id, abi(*ty, pointer), self.f)
                 }
+            },
+            Expr::Param(i, ty) => {
+                let tmp = self.f.use_var(self.params[*i]);
+                self.lift_argument(tmp, *ty)
             }
-            scratch::BlockOp::ControlForever(body) => {
-                c.ends.push(block);
-                body.build(c, block);
-                c.ends.pop();
-            }
-            scratch::BlockOp::ControlWait(delay) => {
-                let sleep = c.import_func("support_sleep", &[types::F64], None);
-
-                let tmp = delay.build(c);
-                c.f.ins().call(sleep, &[tmp]);
-            }
-            scratch::BlockOp::ControlIfElse {
-                condition,
-                consequent,
-                alternative,
-            } => {
-                let bcons = c.f.create_block();
-                let balt = c.f.create_block();
-                let bnext = c.f.create_block();
-
-                let tmp = condition.build(c);
-                if alternative.is_some() {
-                    c.f.ins().brz(tmp, balt, &[]);
-                } else {
-                    c.f.ins().brz(tmp, bnext, &[]);
-                }
-                c.f.ins().jump(bcons, &[]);
-
-                c.ends.push(bnext);
-                consequent.build(c, bcons);
-                if let Some(alternative) = alternative {
-                    alternative.build(c, balt);
+            Expr::Cast(ty, inner) => {
+                let tmp = self.expr(inner);
+                self.cast(tmp, inner.ty(), *ty)
+            }
+            Expr::Binary(op, l, r) => {
+                let l = self.expr(l);
+                let r = self.expr(r);
+                match op {
+                    BinaryOp::Add => self.f.ins().fadd(l, r),
+                    BinaryOp::Sub => self.f.ins().fsub(l, r),
                 }
-                c.ends.pop();
-
-                if let Some(next) = &self.next {
-                    next.build(c, bnext);
+            }
+            Expr::Compare(op, l, r) => {
+                let ty = l.ty();
+                let nan = l.may_be_nan() || r.may_be_nan();
+                let l = self.expr(l);
+                let r = self.expr(r);
+                if ty == ir::Type::Number && !nan {
+                    let cc = match op {
+                        CompareOp::Eq => FloatCC::Equal,
+                        CompareOp::Gt => FloatCC::GreaterThan,
+                    };
+                    self.f.ins().fcmp(cc, l, r)
                 } else {
-                    c.f.switch_to_block(bnext);
-                    c.fall_off_end();
+                    let cc = match op {
+                        CompareOp::Eq => IntCC::Equal,
+                        CompareOp::Gt => IntCC::SignedGreaterThan,
+                    };
+                    let func = if ty == ir::Type::Number {
+                        "support_compare_numbers"
+                    } else {
+                        "support_compare"
+                    };
+                    let tmp = self.call_func(func, &[l, r], Some(types::I32)).unwrap();
+                    self.f.ins().icmp_imm(cc, tmp, 0)
                 }
             }
-            scratch::BlockOp::ControlStopAll => {
-                let libc_exit = c.import_func("exit", &[types::I32], None);
-                let detach_scripts = c.import_func("support_detach_scripts", &[], None);
+            Expr::Call(call) => self.call(call).unwrap(),
+        }
+    }
 
-                c.f.ins().call(detach_scripts, &[]);
+    /// Free the strings made from numbers to evaluate `exprs`, once nothing
+    /// uses them any more.
+    fn free_temporaries(&mut self, exprs: &[Expr]) {
+        if exprs.iter().any(makes_temporaries) {
+            self.call_func("support_free_temporaries", &[], None);
+        }
+    }
 
-                let tmp = c.f.ins().iconst(types::I32, 0);
-                c.f.ins().call(libc_exit, &[tmp]);
+    fn body(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
 
-                c.f.ins().trap(TrapCode::UnreachableCodeReached);
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Call(call) if call.may_yield() => {
+                self.store_globals();
+                self.call(call);
+                self.load_globals();
+                self.free_temporaries(&call.args);
             }
-            scratch::BlockOp::ControlStopScript => {
-                c.f.ins().return_(&[]);
+            Stmt::Call(call) => {
+                self.call(call);
+                self.free_temporaries(&call.args);
             }
-            scratch::BlockOp::LooksSay(s) => {
-                let p = c.c.module.target_config().pointer_type();
-                match s {
-                    scratch::Value::String(s) => {
-                        let libc_write = c.import_func("write", &[types::I32, p, p], Some(p));
-
-                        let fd = c.f.ins().iconst(types::I32, 1);
-
-                        let s = format!("{}\n", s);
-
-                        let data = c.c.create_data(s.as_bytes().into());
-                        let tmp = c.c.module.declare_data_in_func(data, &mut c.f.func);
-                        let ptr = c.f.ins().global_value(p, tmp);
-
-                        let len = c.f.ins().iconst(p, s.len() as i64);
-
-                        c.f.ins().call(libc_write, &[fd, ptr, len]);
+            Stmt::SetGlobal(id, e) => {
+                let mut tmp = self.expr(e);
+                let pointer = self.pointer_type();
+                let old = (e.ty() == ir::Type::String).then(|| {
+                    tmp = self
+                        .call_func("support_own", &[tmp], Some(pointer))
+                        .unwrap();
+                    match self.globals.get(id) {
+                        Some(global) => self.f.use_var(global.var),
+                        None => self.c.load_scratch_var(id, pointer, self.f),
                     }
-                    _ => {
-                        let write_float = c.import_func("support_write_float", &[types::F64], None);
-                        let tmp = s.build(c);
-                        c.f.ins().call(write_float, &[tmp]);
+                });
+                match self.globals.get(id) {
+                    Some(global) => self.f.def_var(global.var, tmp),
+                    None => self.c.store_scratch_var(id, tmp, self.f),
+                }
+                if let Some(old) = old {
+                    self.call_func("support_release", &[old], None);
+                }
+                self.free_temporaries(std::slice::from_ref(e));
+            }
+            Stmt::CallFunction(func, args) => {
+                // The function frees temporaries of its own, so strings are
+                // copied for it to keep until it returns.
+                let pointer = self.pointer_type();
+                let mut arguments = vec![];
+                let mut owned = vec![];
+                for arg in args {
+                    let mut tmp = self.expr(arg);
+                    if arg.ty() == ir::Type::String {
+                        tmp = self
+                            .call_func("support_own", &[tmp], Some(pointer))
+                            .unwrap();
+                        owned.push(tmp);
                     }
-                };
+                    arguments.push(self.lower_argument(tmp, arg.ty()));
+                }
+                self.free_temporaries(args);
+                let tmp = self.c.func_ref(self.c.functions[*func], self.f);
+                self.store_globals();
+                self.f.ins().call(tmp, &arguments);
+                self.load_globals();
+                for tmp in owned {
+                    self.call_func("support_release", &[tmp], None);
+                }
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let bthen = self.f.create_block();
+                let botherwise = self.f.create_block();
+                let bnext = self.f.create_block();
+
+                let tmp = self.expr(condition);
+                self.free_temporaries(std::slice::from_ref(condition));
+                self.f.ins().brz(tmp, botherwise, &[]);
+                self.f.ins().jump(bthen, &[]);
+
+                for (block, body) in [(bthen, then), (botherwise, otherwise)].iter() {
+                    self.f.switch_to_block(*block);
+                    self.body(body);
+                    if !self.f.is_filled() {
+                        self.f.ins().jump(bnext, &[]);
+                    }
+                }
+
+                self.f.switch_to_block(bnext);
             }
-            scratch::BlockOp::EventWhenFlagClicked => {}
-            scratch::BlockOp::DataSetVariableTo { id, value } => {
-                let val = value.build(c);
-                c.c.store_scratch_var(id, val, c.f);
+            Stmt::Repeat(times, body) => {
+                let head = self.f.create_block();
+                let bbody = self.f.create_block();
+                let bnext = self.f.create_block();
+                let vtimes = self.c.new_var();
+
+                self.f.declare_var(vtimes, types::I32);
+                let tmp = self.expr(times);
+                self.free_temporaries(std::slice::from_ref(times));
+                // The same as `scratchc_runtime::repeat_count`.
+                let half = self.f.ins().f64const(0.5);
+                let tmp = self.f.ins().fadd(tmp, half);
+                let tmp = self.f.ins().floor(tmp);
+                let tmp = self.f.ins().fcvt_to_uint_sat(types::I32, tmp);
+                self.f.def_var(vtimes, tmp);
+                self.f.ins().jump(head, &[]);
+
+                self.f.switch_to_block(head);
+                let tmp = self.f.use_var(vtimes);
+                self.f.ins().brz(tmp, bnext, &[]);
+                self.f.ins().jump(bbody, &[]);
+
+                self.f.switch_to_block(bbody);
+                let one = self.f.ins().iconst(types::I32, 1);
+                let tmp = self.f.ins().isub(tmp, one);
+                self.f.def_var(vtimes, tmp);
+                self.body(body);
+                if !self.f.is_filled() {
+                    self.f.ins().jump(head, &[]);
+                }
+
+                self.f.switch_to_block(bnext);
             }
-            scratch::BlockOp::DataChangeVariableBy { id, value } => {
-                let val = c.c.load_scratch_var(id, c.f);
-                let dif = value.build(c);
-                let val = c.f.ins().fadd(val, dif);
-                c.c.store_scratch_var(id, val, c.f);
+            Stmt::Forever(body) => {
+                let bbody = self.f.create_block();
+                self.f.ins().jump(bbody, &[]);
+                self.f.switch_to_block(bbody);
+                self.body(body);
+                if !self.f.is_filled() {
+                    self.f.ins().jump(bbody, &[]);
+                }
             }
-            scratch::BlockOp::ProceduresCall { proc, args } => {
-                let mut arguments = vec![];
-                for v in args {
-                    arguments.push(v.build(c));
+            Stmt::Yield if self.globals.is_empty() => {
+                self.call_func("support_yield", &[], None);
+            }
+            Stmt::Yield => {
+                // Only go through memory when the turn actually ends, which
+                // it doesn't in warp mode.
+                let byield = self.f.create_block();
+                let bnext = self.f.create_block();
+                let tmp = self
+                    .call_func("support_should_yield", &[], Some(types::I32))
+                    .unwrap();
+                self.f.ins().brz(tmp, bnext, &[]);
+                self.f.ins().jump(byield, &[]);
+
+                self.f.switch_to_block(byield);
+                self.store_globals();
+                self.call_func("support_take_turns", &[], None);
+                self.load_globals();
+                self.f.ins().jump(bnext, &[]);
+
+                self.f.switch_to_block(bnext);
+            }
+            Stmt::Return => {
+                self.store_globals();
+                self.f.ins().return_(&[]);
+            }
+            Stmt::Exit => {
+                let tmp = self.f.ins().iconst(types::I32, 0);
+                self.call_func("exit", &[tmp], None);
+                self.f.ins().trap(TrapCode::UnreachableCodeReached);
+            }
+            Stmt::Source(id) => {
+                if let (Some(lines), Some(target)) = (&self.c.lines, self.c.target) {
+                    let line = lines[target][id];
+                    self.f
+                        .set_srcloc(cranelift::codegen::ir::SourceLoc::new(line));
                 }
-                let tmp =
-                    c.c.module
-                        .declare_func_in_func(c.c.procedures[proc], c.f.func);
-                c.f.ins().call(tmp, &arguments);
             }
         }
+        // Statements after a branch out are unreachable, but Cranelift still
+        // wants a block to put them in.
+        if self.f.is_filled() {
+            let block = self.f.create_block();
+            self.f.switch_to_block(block);
+        }
+    }
+}
 
-        if !c.f.is_filled() {
-            if let Some(next) = &self.next {
-                let bnext = c.f.create_block();
-                c.f.ins().jump(bnext, &[]);
-                next.build(c, bnext);
-            } else {
-                c.fall_off_end();
+/// Whether evaluating `e` makes strings from numbers, which the runtime keeps
+/// until they're freed.
+fn makes_temporaries(e: &Expr) -> bool {
+    match e {
+        Expr::Cast(ir::Type::String, inner) if inner.ty() == ir::Type::Number => true,
+        Expr::Cast(_, e) => makes_temporaries(e),
+        Expr::Binary(_, l, r) | Expr::Compare(_, l, r) => {
+            makes_temporaries(l) || makes_temporaries(r)
+        }
+        Expr::Call(call) => call.args.iter().any(makes_temporaries),
+        _ => false,
+    }
+}
+
+/// The globals used in `stmts`, with their types and whether they're set.
+fn used_globals(stmts: &[Stmt], used: &mut BTreeMap<String, (ir::Type, bool)>) {
+    fn expr(e: &Expr, used: &mut BTreeMap<String, (ir::Type, bool)>) {
+        match e {
+            Expr::Global(id, ty) => {
+                used.entry(id.clone()).or_insert((*ty, false));
+            }
+            Expr::Cast(_, e) => expr(e, used),
+            Expr::Binary(_, l, r) | Expr::Compare(_, l, r) => {
+                expr(l, used);
+                expr(r, used);
             }
+            Expr::Call(call) => call.args.iter().for_each(|arg| expr(arg, used)),
+            _ => {}
         }
     }
+
+    for stmt in stmts {
+        match stmt {
+            Stmt::Call(call) => call.args.iter().for_each(|arg| expr(arg, used)),
+            Stmt::SetGlobal(id, e) => {
+                expr(e, used);
+                used.entry(id.clone()).or_insert((e.ty(), true)).1 = true;
+            }
+            Stmt::CallFunction(_, args) => args.iter().for_each(|arg| expr(arg, used)),
+            Stmt::If(condition, then, otherwise) => {
+                expr(condition, used);
+                used_globals(then, used);
+                used_globals(otherwise, used);
+            }
+            Stmt::Repeat(times, body) => {
+                expr(times, used);
+                used_globals(body, used);
+            }
+            Stmt::Forever(body) => used_globals(body, used),
+            Stmt::Yield | Stmt::Return | Stmt::Exit | Stmt::Source(_) => {}
+        }
+    }
+}
+
+/// How a value of type `ty` is passed to and returned from functions: an
+/// `f64`, a pointer to a NUL terminated string, or an `i32` for everything
+/// else.
+fn abi(ty: ir::Type, pointer: Type) -> Type {
+    match ty {
+        ir::Type::Number => types::F64,
+        ir::Type::String => pointer,
+        ir::Type::Bool | ir::Type::Int => types::I32,
+    }
+}
+
+/// What's left over from compiling, besides the code in the module.
+pub struct Output {
+    /// Every function's IR or assembly, when that's what to emit.
+    pub listing: String,
+    /// Where every function's code came from, when compiling with debug info.
+    pub functions: Vec<dwarf::Function>,
+    /// The data object of every Scratch variable, by ID.
+    pub globals: HashMap<String, cranelift_module::DataId>,
 }
 
+/// With `sources`, source locations are kept for debug info.
 pub fn compile(
     m: &mut impl Module,
-    variables: &[String],
-    procedures: &[scratch::Procedure],
-    scripts: &[scratch::Block],
-) {
-    let mut compiler = Compiler::new(m);
-
-    let mut script_funcs = vec![];
-
-    for var in variables {
-        compiler.create_scratch_var(var);
-    }
-
-    for proc in procedures {
-        compiler.compile_func(
-            &format!("proc_{}", proc.id),
-            &vec![types::F64; proc.arguments.len()],
-            None,
-            false,
-            |c, f, func_id| {
-                c.procedures.insert(proc.id.clone(), func_id); // insert here to support recursion
-
-                let block = f.create_block();
-                f.append_block_params_for_function_params(block);
-                f.switch_to_block(block);
-                let mut args = HashMap::new();
-                for (i, name) in proc.arguments.iter().enumerate() {
+    program: &ir::Program,
+    targets: &[scratch::Target],
+    extensions: &[String],
+    optimize: bool,
+    emit: Emit,
+    sources: Option<&[source::Source]>,
+) -> Output {
+    let mut compiler = Compiler::new(m, optimize, emit);
+    compiler.lines = sources.map(|sources| sources.iter().map(|s| s.lines.clone()).collect());
+    let pointer = compiler.module.target_config().pointer_type();
+
+    for global in &program.globals {
+        compiler.create_scratch_var(global);
+    }
+
+    // Declare every function up front so that calls can come before the
+    // definition, or from the function itself.
+    for func in &program.functions {
+        let mut sig = compiler.module.make_signature();
+        for ty in &func.params {
+            sig.params.push(AbiParam::new(abi(*ty, pointer)));
+        }
+        let func_id = compiler
+            .module
+            .declare_function(&func.name, cranelift_module::Linkage::Local, &sig)
+            .unwrap();
+        compiler.functions.push(func_id);
+    }
+
+    for func in &program.functions {
+        let params: Vec<_> = func.params.iter().map(|ty| abi(*ty, pointer)).collect();
+        compiler.target = Some(func.target);
+        compiler.compile_func(&func.name, &func.label, &params, None, false, |c, f, _| {
+            let block = f.create_block();
+            f.append_block_params_for_function_params(block);
+            f.switch_to_block(block);
+            let mut vars = vec![];
+            for (i, param) in params.iter().enumerate() {
+                let var = c.new_var();
+                f.declare_var(var, *param);
+                let val = f.block_params(block)[i];
+                f.def_var(var, val);
+                vars.push(var);
+            }
+
+            let mut globals = BTreeMap::new();
+            if c.optimize {
+                used_globals(&func.body, &mut globals);
+            }
+            let globals = globals
+                .into_iter()
+                .map(|(id, (ty, written))| {
+                    let ty = abi(ty, pointer);
                     let var = c.new_var();
-                    f.declare_var(var, types::F64);
-                    let val = f.block_params(block)[i];
-                    f.def_var(var, val);
-                    args.insert(name.to_owned(), var);
+                    f.declare_var(var, ty);
+                    (id, Promoted { var, ty, written })
+                })
+                .collect();
+
+            let mut fc = FunctionCompiler {
+                c,
+                f,
+                params: vars,
+                globals,
+            };
+            fc.load_globals();
+            fc.body(&func.body);
+            if !fc.f.is_filled() {
+                fc.store_globals();
+                fc.f.ins().return_(&[]);
+            }
+        });
+    }
+
+    let label = "main: sets up the sprites and starts the scripts";
+    compiler.target = None;
+    compiler.compile_func(
+        "main",
+        label,
+        &[],
+        Some(types::I32),
+        true,
+        |compiler, f, _| {
+            let block = f.create_block();
+            f.switch_to_block(block);
+
+            let p = compiler.module.target_config().pointer_type();
+
+            let init = compiler.import_func("support_init", &[], None, f);
+            f.ins().call(init, &[]);
+
+            // Variables own their strings, so that setting them can free the
+            // string before.
+            let own = compiler.import_func("support_own", &[p], Some(p), f);
+            for global in &program.globals {
+                if global.init.ty() == ir::Type::String {
+                    let tmp = compiler.load_scratch_var(&global.id, p, f);
+                    let call = f.ins().call(own, &[tmp]);
+                    let tmp = f.inst_results(call)[0];
+                    compiler.store_scratch_var(&global.id, tmp, f);
                 }
+            }
 
-                let mut bc = BlockCompiler {
-                    c,
-                    f,
-                    ends: Vec::new(),
-                    args,
-                };
-                proc.body.build(&mut bc, block);
-            },
-        );
-    }
+            let add_target = compiler.import_func(
+                "support_add_target",
+                &[
+                    p,
+                    types::I32,
+                    types::I32,
+                    types::F64,
+                    types::F64,
+                    types::F64,
+                    types::F64,
+                    types::I32,
+                    types::I32,
+                    types::I32,
+                    types::F64,
+                ],
+                None,
+                f,
+            );
+            let add_costume = compiler.import_func(
+                "support_add_costume",
+                &[
+                    types::I32,
+                    p,
+                    types::I32,
+                    types::I32,
+                    p,
+                    types::F64,
+                    types::F64,
+                    types::F64,
+                ],
+                None,
+                f,
+            );
+            let add_sound = compiler.import_func(
+                "support_add_sound",
+                &[types::I32, p, p, types::I32, types::I32, types::I32],
+                None,
+                f,
+            );
+
+            for (t, target) in targets.iter().enumerate() {
+                let name = compiler.string_ptr(&target.name, f);
+                let is_stage = f.ins().iconst(types::I32, target.is_stage as i64);
+                let layer = f.ins().iconst(types::I32, target.layer_order as i64);
+                let x = f.ins().f64const(target.sprite.x);
+                let y = f.ins().f64const(target.sprite.y);
+                let size = f.ins().f64const(target.sprite.size);
+                let direction = f.ins().f64const(target.sprite.direction);
+                let visible = f.ins().iconst(types::I32, target.sprite.visible as i64);
+                let rotation_style = f.ins().iconst(
+                    types::I32,
+                    match target.sprite.rotation_style.as_str() {
+                        "left-right" => 1,
+                        "don't rotate" => 2,
+                        _ => 0,
+                    },
+                );
+                let costume = f.ins().iconst(types::I32, target.current_costume as i64);
+                let volume = f.ins().f64const(target.volume);
+                f.ins().call(
+                    add_target,
+                    &[
+                        name,
+                        is_stage,
+                        layer,
+                        x,
+                        y,
+                        size,
+                        direction,
+                        visible,
+                        rotation_style,
+                        costume,
+                        volume,
+                    ],
+                );
+
+                for (c, costume) in target.costumes.iter().enumerate() {
+                    let t = f.ins().iconst(types::I32, t as i64);
+                    let name = compiler.string_ptr(&costume.name, f);
+                    let width = f.ins().iconst(types::I32, costume.width as i64);
+                    let height = f.ins().iconst(types::I32, costume.height as i64);
+                    let pixels = compiler.create_data(
+                        &ir::symbol(&[&target.name, "costume", &c.to_string()]),
+                        costume.pixels.clone().into(),
+                    );
+                    let pixels = compiler.data_ptr(pixels, f);
+                    let rx = f.ins().f64const(costume.rotation_center.0);
+                    let ry = f.ins().f64const(costume.rotation_center.1);
+                    let resolution = f.ins().f64const(costume::RESOLUTION);
+                    f.ins().call(
+                        add_costume,
+                        &[t, name, width, height, pixels, rx, ry, resolution],
+                    );
+                }
+
+                for (s, sound) in target.sounds.iter().enumerate() {
+                    let t = f.ins().iconst(types::I32, t as i64);
+                    let name = compiler.string_ptr(&sound.name, f);
+                    let samples = sound
+                        .samples
+                        .iter()
+                        .flat_map(|s| s.to_le_bytes().to_vec())
+                        .collect::<Vec<_>>();
+                    let samples = compiler.create_data(
+                        &ir::symbol(&[&target.name, "sound", &s.to_string()]),
+                        samples.into(),
+                    );
+                    let samples = compiler.data_ptr(samples, f);
+                    let len = f.ins().iconst(types::I32, sound.samples.len() as i64);
+                    let sample_count = f.ins().iconst(types::I32, sound.sample_count as i64);
+                    let rate = f.ins().iconst(types::I32, sound.rate as i64);
+                    f.ins()
+                        .call(add_sound, &[t, name, samples, len, sample_count, rate]);
+                }
+            }
+
+            // The tempo is saved on the stage, but only means something with the
+            // music extension.
+            if extensions.iter().any(|e| e == "music") {
+                let set_tempo = compiler.import_func("support_set_tempo", &[types::F64], None, f);
+                for target in targets.iter().filter(|t| t.is_stage) {
+                    let tempo = f.ins().f64const(target.tempo);
+                    f.ins().call(set_tempo, &[tempo]);
+                }
+            }
+
+            let spawn_script = compiler.import_func("support_spawn_script", &[p], None, f);
+            let register_key_hat =
+                compiler.import_func("support_register_key_hat", &[p, p], None, f);
+            let register_backdrop_hat =
+                compiler.import_func("support_register_backdrop_hat", &[p, p], None, f);
 
-    for (i, script) in scripts.iter().enumerate() {
-        let func_id =
-            compiler.compile_func(&format!("script_{}", i), &[], None, false, |c, f, _| {
-                let mut bc = BlockCompiler {
-                    c,
-                    f,
-                    ends: Vec::new(),
-                    args: HashMap::new(),
+            for (func, func_id) in program.functions.iter().zip(&compiler.functions.clone()) {
+                let hat = match &func.hat {
+                    Some(hat) => hat,
+                    None => continue,
                 };
-                let block = bc.f.create_block();
-                script.build(&mut bc, block);
-            });
+                let tmp = compiler.func_ref(*func_id, f);
+                let tmp = f.ins().func_addr(p, tmp);
+                match hat {
+                    ir::Hat::Key(key) => {
+                        let key = compiler.string_ptr(key, f);
+                        f.ins().call(register_key_hat, &[key, tmp]);
+                    }
+                    ir::Hat::Backdrop(backdrop) => {
+                        let backdrop = compiler.string_ptr(backdrop, f);
+                        f.ins().call(register_backdrop_hat, &[backdrop, tmp]);
+                    }
+                    ir::Hat::Flag => {
+                        f.ins().call(spawn_script, &[tmp]);
+                    }
+                }
+            }
 
-        script_funcs.push(func_id);
-    }
+            let join_scripts = compiler.import_func("support_join_scripts", &[], None, f);
+            f.ins().call(join_scripts, &[]);
 
-    compiler.compile_func("main", &[], Some(types::I32), true, |compiler, f, _| {
-        let block = f.create_block();
-        f.switch_to_block(block);
+            let tmp = f.ins().iconst(types::I32, 0);
+            f.ins().return_(&[tmp]);
+        },
+    );
 
-        let spawn_script = compiler.import_func(
-            "support_spawn_script",
-            &[compiler.module.target_config().pointer_type()],
-            None,
-            f,
-        );
+    Output {
+        listing: compiler.listing,
+        functions: compiler.debug,
+        globals: compiler.scratch_vars,
+    }
+}
 
-        for func_id in &script_funcs {
-            let tmp = compiler.module.declare_func_in_func(*func_id, &mut f.func);
-            let tmp = f
-                .ins()
-                .func_addr(compiler.module.target_config().pointer_type(), tmp);
-            f.ins().call(spawn_script, &[tmp]);
+/// The offsets into a compiled function's code where the line changes, and
+/// the new line, from the source locations its instructions were built with.
+fn rows(ctx: &cranelift::codegen::Context, isa: &dyn TargetIsa) -> Vec<(u32, u32)> {
+    let mut locs = vec![];
+    match &ctx.mach_compile_result {
+        Some(result) => {
+            for loc in result.buffer.get_srclocs_sorted() {
+                locs.push((loc.start, loc.loc));
+            }
         }
+        None => {
+            let encinfo = isa.encoding_info();
+            for block in ctx.func.layout.blocks() {
+                for (offset, inst, _) in ctx.func.inst_offsets(block, &encinfo) {
+                    locs.push((offset, ctx.func.srclocs[inst]));
+                }
+            }
+        }
+    }
 
-        let join_scripts = compiler.import_func("support_join_scripts", &[], None, f);
-        f.ins().call(join_scripts, &[]);
-
-        let tmp = f.ins().iconst(types::I32, 0);
-        f.ins().return_(&[tmp]);
-    });
+    let mut rows: Vec<(u32, u32)> = vec![];
+    for (offset, loc) in locs {
+        // Spills and such that don't come from a block carry on the line
+        // before them.
+        if !loc.is_default() && rows.last().map(|(_, line)| *line) != Some(loc.bits()) {
+            rows.push((offset, loc.bits()));
+        }
+    }
+    rows
 }
@@ -0,0 +1,94 @@
+use crate::scratch::CostumeInfo;
+use resvg::{tiny_skia, usvg};
+
+/// Pixels per stage unit that costumes are rasterized at. The terminal stage
+/// is much coarser than this, so the runtime only ever samples these bitmaps.
+pub const RESOLUTION: f64 = 0.5;
+
+#[derive(Debug)]
+pub struct Costume {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Non-premultiplied RGBA, row major.
+    pub pixels: Vec<u8>,
+    /// Rotation center in bitmap pixels.
+    pub rotation_center: (f64, f64),
+}
+
+impl Costume {
+    /// Rasterize a costume asset. Formats we can't decode (jpg) or missing
+    /// assets produce an empty bitmap, which is never drawn.
+    pub fn rasterize(info: &CostumeInfo, data: Option<&[u8]>) -> Costume {
+        let pixmap = data.and_then(|data| match info.data_format.as_str() {
+            "svg" => rasterize_svg(data),
+            "png" => rasterize_png(data, info.bitmap_resolution),
+            _ => None,
+        });
+
+        let (width, height, pixels) = match pixmap {
+            Some(pixmap) => (
+                pixmap.width(),
+                pixmap.height(),
+                pixmap
+                    .pixels()
+                    .iter()
+                    .flat_map(|p| {
+                        let c = p.demultiply();
+                        vec![c.red(), c.green(), c.blue(), c.alpha()]
+                    })
+                    .collect(),
+            ),
+            None => (0, 0, vec![]),
+        };
+
+        let scale = RESOLUTION / info.bitmap_resolution;
+        Costume {
+            name: info.name.clone(),
+            width,
+            height,
+            pixels,
+            rotation_center: (
+                info.rotation_center_x * scale,
+                info.rotation_center_y * scale,
+            ),
+        }
+    }
+}
+
+fn new_pixmap(width: f32, height: f32, scale: f32) -> Option<tiny_skia::Pixmap> {
+    tiny_skia::Pixmap::new(
+        ((width * scale).ceil() as u32).max(1),
+        ((height * scale).ceil() as u32).max(1),
+    )
+}
+
+fn rasterize_svg(data: &[u8]) -> Option<tiny_skia::Pixmap> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).ok()?;
+    let scale = RESOLUTION as f32;
+    let mut pixmap = new_pixmap(tree.size().width(), tree.size().height(), scale)?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    Some(pixmap)
+}
+
+fn rasterize_png(data: &[u8], resolution: f64) -> Option<tiny_skia::Pixmap> {
+    let image = tiny_skia::Pixmap::decode_png(data).ok()?;
+    let scale = (RESOLUTION / resolution) as f32;
+    let mut pixmap = new_pixmap(image.width() as f32, image.height() as f32, scale)?;
+    pixmap.draw_pixmap(
+        0,
+        0,
+        image.as_ref(),
+        &tiny_skia::PixmapPaint {
+            quality: tiny_skia::FilterQuality::Bilinear,
+            ..Default::default()
+        },
+        tiny_skia::Transform::from_scale(scale, scale),
+        None,
+    );
+    Some(pixmap)
+}
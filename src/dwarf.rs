@@ -0,0 +1,367 @@
+//! DWARF debug info: line tables that map machine code back to the blocks in
+//! each target's listing, and the project's variables.
+
+use crate::{ir, scratch};
+use cranelift_module::{DataId, FuncId};
+use gimli::write::{
+    Address, AttributeValue, DwarfUnit, EndianVec, Expression, FileId, LineProgram, LineString,
+    Range, RangeList, Sections, UnitEntryId, Writer,
+};
+use gimli::{constants, RunTimeEndian, SectionId};
+use object::write::{Object, Relocation, StandardSegment};
+use object::{RelocationEncoding, RelocationKind, SectionKind};
+use std::collections::HashMap;
+
+/// Where a function's machine code came from.
+pub struct Function {
+    pub id: FuncId,
+    /// The target whose listing the lines are in. `main` doesn't have one.
+    pub target: Option<usize>,
+    pub label: String,
+    pub size: u32,
+    /// The offsets into the code where the line changes, and the new line.
+    pub rows: Vec<(u32, u32)>,
+}
+
+/// Add debug info to `product`, with `files` the paths of the targets'
+/// listings relative to `dir`.
+#[allow(clippy::too_many_arguments)]
+pub fn emit(
+    product: &mut cranelift_object::ObjectProduct,
+    functions: &[Function],
+    globals: &HashMap<String, DataId>,
+    program: &ir::Program,
+    targets: &[scratch::Target],
+    dir: &str,
+    files: &[String],
+    triple: &target_lexicon::Triple,
+) {
+    let pointer_bytes = triple.pointer_width().unwrap().bytes();
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: pointer_bytes,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    dwarf.unit.line_program = LineProgram::new(
+        encoding,
+        Default::default(),
+        LineString::String(dir.as_bytes().to_vec()),
+        LineString::String(b"project".to_vec()),
+        None,
+    );
+    let directory = dwarf.unit.line_program.default_directory();
+    let files: Vec<FileId> = files
+        .iter()
+        .map(|file| {
+            dwarf.unit.line_program.add_file(
+                LineString::String(file.as_bytes().to_vec()),
+                directory,
+                None,
+            )
+        })
+        .collect();
+
+    // Addresses refer to these by index.
+    let mut symbols = vec![];
+
+    let root = dwarf.unit.root();
+    let producer = dwarf.strings.add("scratchc");
+    let comp_dir = dwarf.strings.add(dir);
+    let entry = dwarf.unit.get_mut(root);
+    entry.set(
+        constants::DW_AT_producer,
+        AttributeValue::StringRef(producer),
+    );
+    entry.set(
+        constants::DW_AT_language,
+        AttributeValue::Language(constants::DW_LANG_C_plus_plus),
+    );
+    entry.set(
+        constants::DW_AT_name,
+        AttributeValue::String(b"project".to_vec()),
+    );
+    entry.set(
+        constants::DW_AT_comp_dir,
+        AttributeValue::StringRef(comp_dir),
+    );
+    entry.set(
+        constants::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+
+    let mut ranges = vec![];
+    for func in functions {
+        let symbol = symbols.len();
+        symbols.push(product.function_symbol(func.id));
+        let start = Address::Symbol { symbol, addend: 0 };
+        ranges.push(Range::StartLength {
+            begin: start,
+            length: func.size as u64,
+        });
+
+        let entry = dwarf.unit.add(root, constants::DW_TAG_subprogram);
+        let entry = dwarf.unit.get_mut(entry);
+        entry.set(
+            constants::DW_AT_name,
+            AttributeValue::String(func.label.as_bytes().to_vec()),
+        );
+        entry.set(constants::DW_AT_low_pc, AttributeValue::Address(start));
+        entry.set(
+            constants::DW_AT_high_pc,
+            AttributeValue::Udata(func.size as u64),
+        );
+
+        let target = match func.target {
+            Some(target) if !func.rows.is_empty() => target,
+            _ => continue,
+        };
+        entry.set(
+            constants::DW_AT_decl_file,
+            AttributeValue::FileIndex(Some(files[target])),
+        );
+        let program = &mut dwarf.unit.line_program;
+        program.begin_sequence(Some(start));
+        for (i, (offset, line)) in func.rows.iter().enumerate() {
+            // The prologue belongs to the first line.
+            program.row().address_offset = if i == 0 { 0 } else { *offset as u64 };
+            program.row().file = files[target];
+            program.row().line = *line as u64;
+            program.generate_row();
+        }
+        program.end_sequence(func.size as u64);
+    }
+    let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+    dwarf.unit.get_mut(root).set(
+        constants::DW_AT_ranges,
+        AttributeValue::RangeListRef(ranges),
+    );
+
+    let mut types = HashMap::new();
+    let mut namespaces = HashMap::new();
+    for (t, target) in targets.iter().enumerate() {
+        for (id, (name, _)) in &target.variables {
+            let data = match globals.get(id) {
+                Some(data) => *data,
+                None => continue,
+            };
+            let ty = program.globals.iter().find(|g| g.id == *id).unwrap().ty;
+            let ty = *types
+                .entry(ty)
+                .or_insert_with(|| base_type(&mut dwarf, ty, pointer_bytes));
+            // The stage's variables are everyone's, and each sprite's are
+            // in a namespace named after it.
+            let parent = if target.is_stage {
+                root
+            } else {
+                *namespaces.entry(t).or_insert_with(|| {
+                    let namespace = dwarf.unit.add(root, constants::DW_TAG_namespace);
+                    dwarf.unit.get_mut(namespace).set(
+                        constants::DW_AT_name,
+                        AttributeValue::String(target.name.as_bytes().to_vec()),
+                    );
+                    namespace
+                })
+            };
+
+            let symbol = symbols.len();
+            symbols.push(product.data_symbol(data));
+            let mut location = Expression::new();
+            location.op_addr(Address::Symbol { symbol, addend: 0 });
+
+            let entry = dwarf.unit.add(parent, constants::DW_TAG_variable);
+            let entry = dwarf.unit.get_mut(entry);
+            entry.set(
+                constants::DW_AT_name,
+                AttributeValue::String(name.as_bytes().to_vec()),
+            );
+            entry.set(constants::DW_AT_type, AttributeValue::UnitRef(ty));
+            entry.set(constants::DW_AT_external, AttributeValue::Flag(true));
+            entry.set(constants::DW_AT_location, AttributeValue::Exprloc(location));
+        }
+    }
+
+    let endian = match triple.endianness().unwrap() {
+        target_lexicon::Endianness::Little => RunTimeEndian::Little,
+        target_lexicon::Endianness::Big => RunTimeEndian::Big,
+    };
+    let mut sections = Sections::new(Section::new(endian));
+    dwarf.write(&mut sections).unwrap();
+
+    let object = &mut product.object;
+    let mut ids = HashMap::new();
+    sections
+        .for_each(|id, section| -> Result<(), ()> {
+            if !section.data.slice().is_empty() {
+                let name = section_name(object, id);
+                let segment = object.segment_name(StandardSegment::Debug).to_vec();
+                let section_id = object.add_section(segment, name, SectionKind::Debug);
+                object.append_section_data(section_id, section.data.slice(), 1);
+                ids.insert(id, section_id);
+            }
+            Ok(())
+        })
+        .unwrap();
+    sections
+        .for_each(|id, section| -> Result<(), ()> {
+            for reloc in &section.relocs {
+                let symbol = match reloc.target {
+                    RelocTarget::Symbol(symbol) => symbols[symbol],
+                    RelocTarget::Section(section) => object.section_symbol(ids[&section]),
+                };
+                object
+                    .add_relocation(
+                        ids[&id],
+                        Relocation {
+                            offset: reloc.offset,
+                            size: reloc.size * 8,
+                            kind: RelocationKind::Absolute,
+                            encoding: RelocationEncoding::Generic,
+                            symbol,
+                            addend: reloc.addend,
+                        },
+                    )
+                    .unwrap();
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+fn base_type(dwarf: &mut DwarfUnit, ty: ir::Type, pointer_bytes: u8) -> UnitEntryId {
+    let root = dwarf.unit.root();
+    let base = |dwarf: &mut DwarfUnit, name: &str, encoding, size| {
+        let entry = dwarf.unit.add(root, constants::DW_TAG_base_type);
+        let e = dwarf.unit.get_mut(entry);
+        e.set(constants::DW_AT_name, AttributeValue::String(name.into()));
+        e.set(
+            constants::DW_AT_encoding,
+            AttributeValue::Encoding(encoding),
+        );
+        e.set(constants::DW_AT_byte_size, AttributeValue::Data1(size));
+        entry
+    };
+    match ty {
+        ir::Type::Number => base(dwarf, "double", constants::DW_ATE_float, 8),
+        ir::Type::Bool => base(dwarf, "bool", constants::DW_ATE_boolean, 4),
+        ir::Type::Int => base(dwarf, "int", constants::DW_ATE_signed, 4),
+        // Strings are pointers to NUL terminated UTF-8.
+        ir::Type::String => {
+            let char = base(dwarf, "char", constants::DW_ATE_UTF, 1);
+            let entry = dwarf.unit.add(root, constants::DW_TAG_pointer_type);
+            let e = dwarf.unit.get_mut(entry);
+            e.set(constants::DW_AT_type, AttributeValue::UnitRef(char));
+            e.set(
+                constants::DW_AT_byte_size,
+                AttributeValue::Data1(pointer_bytes),
+            );
+            entry
+        }
+    }
+}
+
+fn section_name(object: &Object, id: SectionId) -> Vec<u8> {
+    match object.format() {
+        object::BinaryFormat::MachO => format!("__{}", &id.name()[1..]).into_bytes(),
+        _ => id.name().as_bytes().to_vec(),
+    }
+}
+
+#[derive(Clone)]
+enum RelocTarget {
+    Symbol(usize),
+    Section(SectionId),
+}
+
+#[derive(Clone)]
+struct Reloc {
+    offset: u64,
+    /// In bytes.
+    size: u8,
+    target: RelocTarget,
+    addend: i64,
+}
+
+/// A debug section, with the relocations it needs in the object file.
+#[derive(Clone)]
+struct Section {
+    data: EndianVec<RunTimeEndian>,
+    relocs: Vec<Reloc>,
+}
+
+impl Section {
+    fn new(endian: RunTimeEndian) -> Section {
+        Section {
+            data: EndianVec::new(endian),
+            relocs: vec![],
+        }
+    }
+}
+
+impl Writer for Section {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> RunTimeEndian {
+        self.data.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self, offset: usize, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(&mut self, address: Address, size: u8) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(value) => self.write_udata(value, size),
+            Address::Symbol { symbol, addend } => {
+                self.relocs.push(Reloc {
+                    offset: self.len() as u64,
+                    size,
+                    target: RelocTarget::Symbol(symbol),
+                    addend,
+                });
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    // Offsets into other sections are relocated too, since the linker puts
+    // everyone's debug info together.
+    fn write_offset(
+        &mut self,
+        val: usize,
+        section: SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        let offset = self.len();
+        self.write_offset_at(offset, val, section, size)?;
+        Ok(())
+    }
+
+    fn write_offset_at(
+        &mut self,
+        offset: usize,
+        val: usize,
+        section: SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        self.relocs.push(Reloc {
+            offset: offset as u64,
+            size,
+            target: RelocTarget::Section(section),
+            addend: val as i64,
+        });
+        if offset == self.len() {
+            self.write_udata(0, size)
+        } else {
+            self.write_udata_at(offset, 0, size)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Constant folding over the IR.
+//!
+//! Expressions on literals are evaluated the way the runtime would, and
+//! `if`s and `repeat`s that are decided by them are removed.
+
+use crate::ir::{BinaryOp, Call, CompareOp, Expr, Program, Stmt, Type};
+use std::cmp::Ordering;
+
+pub fn fold(program: &mut Program) {
+    for func in &mut program.functions {
+        func.body = body(std::mem::take(&mut func.body));
+    }
+}
+
+fn body(stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut out = vec![];
+    for s in stmts {
+        stmt(s, &mut out);
+        // Inlining a branch can leave a terminator in the middle.
+        if out.last().is_some_and(Stmt::is_terminator) {
+            break;
+        }
+    }
+    out
+}
+
+fn stmt(s: Stmt, out: &mut Vec<Stmt>) {
+    let s = match s {
+        Stmt::Call(c) => Stmt::Call(call(c)),
+        Stmt::SetGlobal(id, e) => Stmt::SetGlobal(id, expr(e)),
+        Stmt::CallFunction(func, args) => {
+            Stmt::CallFunction(func, args.into_iter().map(expr).collect())
+        }
+        Stmt::If(condition, then, otherwise) => match expr(condition) {
+            Expr::Bool(b) => return out.extend(body(if b { then } else { otherwise })),
+            condition => Stmt::If(condition, body(then), body(otherwise)),
+        },
+        Stmt::Repeat(times, b) => match expr(times) {
+            Expr::Number(n) if scratchc_runtime::repeat_count(n) == 0 => return,
+            times => Stmt::Repeat(times, body(b)),
+        },
+        Stmt::Forever(b) => Stmt::Forever(body(b)),
+        s @ (Stmt::Yield | Stmt::Return | Stmt::Exit | Stmt::Source(_)) => s,
+    };
+    out.push(s);
+}
+
+fn call(c: Call) -> Call {
+    Call {
+        args: c.args.into_iter().map(expr).collect(),
+        ..c
+    }
+}
+
+pub fn expr(e: Expr) -> Expr {
+    match e {
+        Expr::Cast(ty, inner) => match (expr(*inner), ty) {
+            (Expr::Number(n), Type::Bool) => Expr::Bool(scratchc_runtime::number_to_bool(n)),
+            (Expr::Number(n), Type::String) => Expr::String(scratchc_runtime::number_to_string(n)),
+            (Expr::Bool(b), Type::Number) => Expr::Number(if b { 1.0 } else { 0.0 }),
+            (Expr::Bool(b), Type::String) => {
+                Expr::String(scratchc_runtime::bool_to_string(b).to_owned())
+            }
+            (Expr::String(s), Type::Number) => Expr::Number(scratchc_runtime::string_to_number(&s)),
+            (Expr::String(s), Type::Bool) => Expr::Bool(scratchc_runtime::string_to_bool(&s)),
+            (inner, ty) => Expr::Cast(ty, Box::new(inner)),
+        },
+        Expr::Binary(op, l, r) => match (op, expr(*l), expr(*r)) {
+            (BinaryOp::Add, Expr::Number(a), Expr::Number(b)) => Expr::Number(a + b),
+            (BinaryOp::Sub, Expr::Number(a), Expr::Number(b)) => Expr::Number(a - b),
+            (op, l, r) => Expr::Binary(op, Box::new(l), Box::new(r)),
+        },
+        Expr::Compare(op, l, r) => match (expr(*l), expr(*r)) {
+            (Expr::Number(a), Expr::Number(b)) => {
+                compared(op, scratchc_runtime::compare_numbers(a, b))
+            }
+            (Expr::String(a), Expr::String(b)) => compared(op, scratchc_runtime::compare(&a, &b)),
+            (l, r) => Expr::Compare(op, Box::new(l), Box::new(r)),
+        },
+        Expr::Call(c) => Expr::Call(call(c)),
+        e => e,
+    }
+}
+
+fn compared(op: CompareOp, ordering: Ordering) -> Expr {
+    Expr::Bool(match op {
+        CompareOp::Eq => ordering == Ordering::Equal,
+        CompareOp::Gt => ordering == Ordering::Greater,
+    })
+}
@@ -0,0 +1,106 @@
+//! Type inference for Scratch variables.
+//!
+//! A variable can hold anything, but most only ever hold numbers. Those are
+//! kept as plain numbers, and everything else as text.
+
+use crate::ir::Type;
+use crate::scratch::{Block, BlockExpression, BlockOp, Target, Value};
+use std::collections::HashMap;
+
+/// The type of every variable, by ID: `Number` if it starts out as a number
+/// and is only ever set to numbers, `String` otherwise.
+pub fn variable_types(targets: &[Target]) -> HashMap<String, Type> {
+    let mut types: HashMap<_, _> = targets
+        .iter()
+        .flat_map(|t| &t.variables)
+        .map(|(id, (_, initial))| {
+            let ty = match initial {
+                serde_json::Value::Number(_) => Type::Number,
+                serde_json::Value::String(s) if scratchc_runtime::is_exact_number(s) => {
+                    Type::Number
+                }
+                _ => Type::String,
+            };
+            (id.clone(), ty)
+        })
+        .collect();
+
+    let mut sets = vec![];
+    for target in targets {
+        for script in &target.scripts {
+            assignments(script, &mut sets);
+        }
+        for proc in &target.procedures {
+            assignments(&proc.body, &mut sets);
+        }
+    }
+
+    // Setting a variable to one that turns out to hold text makes it text
+    // too, so keep going until nothing changes.
+    loop {
+        let mut changed = false;
+        for (id, v) in &sets {
+            let ty = match v {
+                // Text is only kept as a number if it would read back the
+                // same, so "1.50" stays text.
+                Value::String(s) if !scratchc_runtime::is_exact_number(s) => Type::String,
+                v => value_type(v, &types),
+            };
+            if types[*id] == Type::Number && ty != Type::Number {
+                types.insert((*id).clone(), Type::String);
+                changed = true;
+            }
+        }
+        if !changed {
+            break types;
+        }
+    }
+}
+
+/// The type a value has before any conversion.
+pub fn value_type(v: &Value, variables: &HashMap<String, Type>) -> Type {
+    match v {
+        Value::Number(_) => Type::Number,
+        // Text that looks like a number compares as one, so keep it in a
+        // register.
+        Value::String(s) => match s.parse::<f64>() {
+            Ok(n) if n.is_finite() => Type::Number,
+            _ => Type::String,
+        },
+        Value::Load(id) => variables[id],
+        Value::Expression(b) => match &**b {
+            BlockExpression::OperatorEquals { .. }
+            | BlockExpression::OperatorGT { .. }
+            | BlockExpression::SensingKeyPressed { .. } => Type::Bool,
+            BlockExpression::LooksCostumeNumberName { name: true }
+            | BlockExpression::LooksBackdropNumberName { name: true }
+            | BlockExpression::ArgumentReporterStringNumber { .. } => Type::String,
+            BlockExpression::ArgumentReporterBoolean { .. } => Type::Bool,
+            _ => Type::Number,
+        },
+    }
+}
+
+fn assignments<'a>(block: &'a Block, out: &mut Vec<(&'a String, &'a Value)>) {
+    let mut block = Some(block);
+    while let Some(b) = block {
+        match &b.op {
+            BlockOp::DataSetVariableTo { id, value } => out.push((id, value)),
+            BlockOp::ControlRepeat { body, .. } | BlockOp::ControlForever(body) => {
+                assignments(body, out)
+            }
+            BlockOp::ControlIfElse {
+                consequent,
+                alternative,
+                ..
+            } => {
+                assignments(consequent, out);
+                if let Some(alternative) = alternative {
+                    assignments(alternative, out);
+                }
+            }
+            _ => {}
+        }
+        block = b.next.as_deref();
+    }
+}
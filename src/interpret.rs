@@ -0,0 +1,512 @@
+//! A tree-walking interpreter for the blocks, as a reference to check the
+//! compiler against. It makes the same calls into the runtime as compiled
+//! code, so speech, costumes, sounds and scheduling behave the same, but it
+//! evaluates everything else itself, with Scratch's dynamic values instead of
+//! the compiler's types.
+
+use crate::lower;
+use crate::scratch::{self, Block, BlockExpression, BlockOp, Target};
+use scratchc_runtime::{
+    support_add_costume, support_add_sound, support_add_target, support_backdrop_name,
+    support_backdrop_number, support_clear_bubble, support_clear_effects,
+    support_clear_sound_effects, support_costume_name, support_costume_number,
+    support_detach_scripts, support_direction, support_effect, support_enter_procedure,
+    support_enter_warp, support_exit_procedure, support_exit_warp, support_go_forward_layers,
+    support_go_to, support_go_to_front_back, support_init, support_join_scripts,
+    support_key_pressed, support_move_steps, support_next_backdrop, support_next_costume,
+    support_play_drum, support_play_note, support_play_sound, support_play_sound_number,
+    support_point_in_direction, support_register_backdrop_hat_interpreted,
+    support_register_key_hat_interpreted, support_rest, support_say_float, support_say_string,
+    support_set_effect, support_set_instrument, support_set_size, support_set_sound_effect,
+    support_set_tempo, support_set_visible, support_set_volume, support_size, support_sleep,
+    support_sound_effect, support_spawn_interpreted, support_stop_all_sounds,
+    support_switch_backdrop, support_switch_backdrop_number, support_switch_costume,
+    support_switch_costume_number, support_tempo, support_volume, support_x_position,
+    support_y_position, support_yield,
+};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::sync::{Mutex, OnceLock};
+
+/// What a variable, argument or reporter holds.
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+fn c(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+fn from_c(s: *const c_char) -> String {
+    unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned()
+}
+
+impl Value {
+    fn number(&self) -> f64 {
+        match self {
+            Value::Number(n) => *n,
+            Value::String(s) => scratchc_runtime::string_to_number(s),
+            Value::Bool(b) => *b as i32 as f64,
+        }
+    }
+
+    fn string(&self) -> String {
+        match self {
+            Value::Number(n) => scratchc_runtime::number_to_string(*n),
+            Value::String(s) => s.clone(),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn bool(&self) -> bool {
+        match self {
+            // NaN is false as well.
+            Value::Number(n) => *n != 0.0 && !n.is_nan(),
+            Value::String(s) => scratchc_runtime::string_to_bool(s),
+            Value::Bool(b) => *b,
+        }
+    }
+}
+
+struct Interpreter {
+    targets: Vec<Target>,
+    /// Every script, by target and index, in the order they start.
+    scripts: Vec<(usize, usize)>,
+    /// Proccodes for the runtime to name custom blocks by, which it keeps.
+    proccodes: HashMap<(usize, String), CString>,
+    variables: Mutex<HashMap<String, Value>>,
+}
+
+// The runtime only lets one script run at a time, and there's only one
+// project per process.
+static INTERPRETER: OnceLock<Interpreter> = OnceLock::new();
+
+/// Where a stack of blocks is running: its target, and the arguments of the
+/// custom block it's in.
+struct Frame<'a> {
+    target: usize,
+    args: HashMap<&'a str, Value>,
+}
+
+impl Frame<'_> {
+    fn t(&self) -> i32 {
+        self.target as i32
+    }
+}
+
+/// Whether to carry on with the next block, or return from the script or
+/// custom block.
+#[derive(PartialEq)]
+enum Flow {
+    Next,
+    Return,
+}
+
+/// Run a project until every script has finished, or one stops everything.
+/// The runtime can only run one project per process.
+pub fn run(targets: Vec<Target>, extensions: &[String]) {
+    let mut variables = HashMap::new();
+    let mut proccodes = HashMap::new();
+    let mut scripts = vec![];
+    for (t, target) in targets.iter().enumerate() {
+        for (id, (_, initial)) in &target.variables {
+            let value = match initial {
+                serde_json::Value::Number(n) => Value::Number(n.as_f64().unwrap()),
+                serde_json::Value::String(s) => Value::String(s.clone()),
+                v => Value::String(v.to_string()),
+            };
+            variables.insert(id.clone(), value);
+        }
+        for proc in &target.procedures {
+            proccodes.insert((t, proc.id.clone()), c(&proc.id));
+        }
+        scripts.extend((0..target.scripts.len()).map(|s| (t, s)));
+    }
+    if INTERPRETER
+        .set(Interpreter {
+            targets,
+            scripts,
+            proccodes,
+            variables: Mutex::new(variables),
+        })
+        .is_err()
+    {
+        panic!("only one project can be interpreted at a time");
+    }
+    let interpreter = INTERPRETER.get().unwrap();
+
+    unsafe {
+        support_init();
+        for (t, target) in interpreter.targets.iter().enumerate() {
+            let rotation_style = match target.sprite.rotation_style.as_str() {
+                "left-right" => 1,
+                "don't rotate" => 2,
+                _ => 0,
+            };
+            support_add_target(
+                c(&target.name).as_ptr(),
+                target.is_stage as i32,
+                target.layer_order as i32,
+                target.sprite.x,
+                target.sprite.y,
+                target.sprite.size,
+                target.sprite.direction,
+                target.sprite.visible as i32,
+                rotation_style,
+                target.current_costume as i32,
+                target.volume,
+            );
+            for costume in &target.costumes {
+                support_add_costume(
+                    t as i32,
+                    c(&costume.name).as_ptr(),
+                    costume.width as i32,
+                    costume.height as i32,
+                    costume.pixels.as_ptr(),
+                    costume.rotation_center.0,
+                    costume.rotation_center.1,
+                    crate::costume::RESOLUTION,
+                );
+            }
+            for sound in &target.sounds {
+                support_add_sound(
+                    t as i32,
+                    c(&sound.name).as_ptr(),
+                    sound.samples.as_ptr() as *const u8,
+                    sound.samples.len() as i32,
+                    sound.sample_count as i32,
+                    sound.rate as i32,
+                );
+            }
+        }
+
+        if extensions.iter().any(|e| e == "music") {
+            for target in interpreter.targets.iter().filter(|t| t.is_stage) {
+                support_set_tempo(target.tempo);
+            }
+        }
+
+        for (i, (t, s)) in interpreter.scripts.iter().enumerate() {
+            match &interpreter.targets[*t].scripts[*s].op {
+                BlockOp::EventWhenKeyPressed(key) => {
+                    support_register_key_hat_interpreted(c(key).as_ptr(), run_script, i)
+                }
+                BlockOp::EventWhenBackdropSwitchesTo(backdrop) => {
+                    support_register_backdrop_hat_interpreted(c(backdrop).as_ptr(), run_script, i)
+                }
+                _ => support_spawn_interpreted(run_script, i),
+            }
+        }
+        support_join_scripts();
+    }
+}
+
+unsafe extern "C" fn run_script(i: usize) {
+    let interpreter = INTERPRETER.get().unwrap();
+    let (t, s) = interpreter.scripts[i];
+    let frame = Frame {
+        target: t,
+        args: HashMap::new(),
+    };
+    interpreter.stack(&interpreter.targets[t].scripts[s], &frame);
+}
+
+impl Interpreter {
+    fn stack(&self, block: &Block, frame: &Frame) -> Flow {
+        let mut block = Some(block);
+        while let Some(b) = block {
+            if self.block(b, frame) == Flow::Return {
+                return Flow::Return;
+            }
+            block = b.next.as_deref();
+        }
+        Flow::Next
+    }
+
+    /// Loops give up their turn at the end of every iteration.
+    fn iteration(&self, body: &Block, frame: &Frame) -> Flow {
+        let flow = self.stack(body, frame);
+        if flow == Flow::Next {
+            support_yield();
+        }
+        flow
+    }
+
+    fn block(&self, b: &Block, frame: &Frame) -> Flow {
+        let t = frame.t();
+        let number = |v| self.eval(v, frame).number();
+        unsafe {
+            match &b.op {
+                BlockOp::ControlRepeat { times, body } => {
+                    for _ in 0..scratchc_runtime::repeat_count(number(times)) {
+                        if self.iteration(body, frame) == Flow::Return {
+                            return Flow::Return;
+                        }
+                    }
+                }
+                BlockOp::ControlForever(body) => loop {
+                    if self.iteration(body, frame) == Flow::Return {
+                        return Flow::Return;
+                    }
+                },
+                BlockOp::ControlWait(delay) => support_sleep(number(delay)),
+                BlockOp::ControlIfElse {
+                    condition,
+                    consequent,
+                    alternative,
+                } => {
+                    if self.eval(condition, frame).bool() {
+                        return self.stack(consequent, frame);
+                    } else if let Some(alternative) = alternative {
+                        return self.stack(alternative, frame);
+                    }
+                }
+                BlockOp::ControlStopAll => {
+                    support_detach_scripts();
+                    support_stop_all_sounds();
+                    std::process::exit(0);
+                }
+                BlockOp::ControlStopScript => return Flow::Return,
+                BlockOp::LooksSay { message, think } => self.say(message, *think, frame),
+                BlockOp::LooksSayForSecs {
+                    message,
+                    secs,
+                    think,
+                } => {
+                    self.say(message, *think, frame);
+                    support_sleep(number(secs));
+                    support_clear_bubble(t);
+                }
+                BlockOp::LooksSwitchCostumeTo(costume) => match self.eval(costume, frame) {
+                    Value::Number(n) => support_switch_costume_number(t, n),
+                    v => support_switch_costume(t, c(&v.string()).as_ptr()),
+                },
+                BlockOp::LooksNextCostume => support_next_costume(t),
+                BlockOp::LooksSwitchBackdropTo { backdrop, wait } => {
+                    match self.eval(backdrop, frame) {
+                        Value::Number(n) => support_switch_backdrop_number(n, *wait as i32),
+                        v => support_switch_backdrop(c(&v.string()).as_ptr(), *wait as i32),
+                    }
+                }
+                BlockOp::LooksNextBackdrop => support_next_backdrop(),
+                BlockOp::LooksChangeSizeBy(change) => {
+                    support_set_size(t, support_size(t) + number(change))
+                }
+                BlockOp::LooksSetSizeTo(size) => support_set_size(t, number(size)),
+                BlockOp::LooksChangeEffectBy { effect, value } => {
+                    let effect = lower::effect_index(effect) as i32;
+                    support_set_effect(t, effect, support_effect(t, effect) + number(value))
+                }
+                BlockOp::LooksSetEffectTo { effect, value } => {
+                    support_set_effect(t, lower::effect_index(effect) as i32, number(value))
+                }
+                BlockOp::LooksClearGraphicEffects => support_clear_effects(t),
+                BlockOp::LooksShow => support_set_visible(t, 1),
+                BlockOp::LooksHide => support_set_visible(t, 0),
+                BlockOp::LooksGoToFrontBack { front } => support_go_to_front_back(t, *front as i32),
+                BlockOp::LooksGoForwardBackwardLayers { forward, layers } => {
+                    let layers = number(layers);
+                    support_go_forward_layers(t, if *forward { layers } else { 0.0 - layers })
+                }
+                BlockOp::SoundPlay { sound, wait } => match self.eval(sound, frame) {
+                    Value::Number(n) => support_play_sound_number(t, n, *wait as i32),
+                    v => support_play_sound(t, c(&v.string()).as_ptr(), *wait as i32),
+                },
+                BlockOp::SoundStopAllSounds => support_stop_all_sounds(),
+                BlockOp::SoundChangeVolumeBy(value) => {
+                    support_set_volume(t, support_volume(t) + number(value))
+                }
+                BlockOp::SoundSetVolumeTo(value) => support_set_volume(t, number(value)),
+                BlockOp::SoundChangeEffectBy { effect, value } => {
+                    let effect = lower::sound_effect_index(effect) as i32;
+                    let current = support_sound_effect(t, effect);
+                    support_set_sound_effect(t, effect, current + number(value))
+                }
+                BlockOp::SoundSetEffectTo { effect, value } => support_set_sound_effect(
+                    t,
+                    lower::sound_effect_index(effect) as i32,
+                    number(value),
+                ),
+                BlockOp::SoundClearEffects => support_clear_sound_effects(t),
+                BlockOp::MusicPlayNoteForBeats { note, beats } => {
+                    support_play_note(t, number(note), number(beats))
+                }
+                BlockOp::MusicPlayDrumForBeats { drum, beats } => {
+                    support_play_drum(t, number(drum), number(beats))
+                }
+                BlockOp::MusicRestForBeats(beats) => support_rest(number(beats)),
+                BlockOp::MusicSetTempo(tempo) => support_set_tempo(number(tempo)),
+                BlockOp::MusicChangeTempo(change) => {
+                    support_set_tempo(support_tempo() + number(change))
+                }
+                BlockOp::MusicSetInstrument(instrument) => {
+                    support_set_instrument(t, number(instrument))
+                }
+                BlockOp::MotionMoveSteps(steps) => support_move_steps(t, number(steps)),
+                BlockOp::MotionTurnRight(degrees) => {
+                    support_point_in_direction(t, support_direction(t) + number(degrees))
+                }
+                BlockOp::MotionTurnLeft(degrees) => {
+                    support_point_in_direction(t, support_direction(t) - number(degrees))
+                }
+                BlockOp::MotionPointInDirection(direction) => {
+                    support_point_in_direction(t, number(direction))
+                }
+                BlockOp::MotionGoToXY { x, y } => support_go_to(t, number(x), number(y)),
+                BlockOp::MotionChangeXBy(x) => {
+                    let x = support_x_position(t) + number(x);
+                    support_go_to(t, x, support_y_position(t))
+                }
+                BlockOp::MotionSetX(x) => support_go_to(t, number(x), support_y_position(t)),
+                BlockOp::MotionChangeYBy(y) => {
+                    let x = support_x_position(t);
+                    support_go_to(t, x, support_y_position(t) + number(y))
+                }
+                BlockOp::MotionSetY(y) => {
+                    let x = support_x_position(t);
+                    support_go_to(t, x, number(y))
+                }
+                BlockOp::EventWhenFlagClicked
+                | BlockOp::EventWhenKeyPressed(_)
+                | BlockOp::EventWhenBackdropSwitchesTo(_) => {}
+                BlockOp::DataSetVariableTo { id, value } => {
+                    let value = self.eval(value, frame);
+                    self.variables.lock().unwrap().insert(id.clone(), value);
+                }
+                BlockOp::DataChangeVariableBy { id, value } => {
+                    let value = number(value);
+                    let mut variables = self.variables.lock().unwrap();
+                    let current = variables[id].number();
+                    variables.insert(id.clone(), Value::Number(current + value));
+                }
+                BlockOp::ProceduresCall { proc, args } => self.call(proc, args, frame),
+            }
+        }
+        Flow::Next
+    }
+
+    fn say(&self, message: &scratch::Value, think: bool, frame: &Frame) {
+        let think = think as i32;
+        unsafe {
+            match self.eval(message, frame) {
+                Value::Number(n) => support_say_float(frame.t(), n, think),
+                v => support_say_string(frame.t(), c(&v.string()).as_ptr(), think),
+            }
+        }
+    }
+
+    fn call(&self, proc: &str, args: &[scratch::Value], frame: &Frame) {
+        // Like Scratch, calling a block another sprite defines does nothing.
+        let definition = match self.targets[frame.target]
+            .procedures
+            .iter()
+            .find(|p| p.id == proc)
+        {
+            Some(definition) => definition,
+            None => return,
+        };
+        let args = definition
+            .arguments
+            .iter()
+            .zip(args)
+            .map(|((name, kind), v)| {
+                let v = self.eval(v, frame);
+                let v = match kind {
+                    scratch::ArgumentKind::StringNumber => v,
+                    scratch::ArgumentKind::Boolean => Value::Bool(v.bool()),
+                };
+                (name.as_str(), v)
+            })
+            .collect();
+        let callee = Frame {
+            target: frame.target,
+            args,
+        };
+        let name = &self.proccodes[&(frame.target, proc.to_owned())];
+        unsafe {
+            if definition.warp {
+                support_enter_warp();
+            }
+            support_enter_procedure(frame.t(), name.as_ptr());
+            // Stopping the script only returns from the custom block, like
+            // compiled code.
+            self.stack(&definition.body, &callee);
+            support_exit_procedure();
+            if definition.warp {
+                support_exit_warp();
+            }
+        }
+    }
+
+    fn eval(&self, v: &scratch::Value, frame: &Frame) -> Value {
+        let t = frame.t();
+        let number = |v| self.eval(v, frame).number();
+        match v {
+            scratch::Value::Number(n) => Value::Number(*n),
+            scratch::Value::String(s) => Value::String(s.clone()),
+            scratch::Value::Load(id) => self.variables.lock().unwrap()[id].clone(),
+            scratch::Value::Expression(b) => unsafe {
+                match &**b {
+                    BlockExpression::OperatorEquals { left, right } => {
+                        Value::Bool(self.compare(left, right, frame) == Ordering::Equal)
+                    }
+                    BlockExpression::OperatorGT { left, right } => {
+                        Value::Bool(self.compare(left, right, frame) == Ordering::Greater)
+                    }
+                    BlockExpression::OperatorAdd { left, right } => {
+                        Value::Number(number(left) + number(right))
+                    }
+                    BlockExpression::OperatorSubtract { left, right } => {
+                        Value::Number(number(left) - number(right))
+                    }
+                    // Used outside of its definition, they're empty.
+                    BlockExpression::ArgumentReporterStringNumber { name } => frame
+                        .args
+                        .get(name.as_str())
+                        .cloned()
+                        .unwrap_or_else(|| Value::String("0".to_owned())),
+                    BlockExpression::ArgumentReporterBoolean { name } => frame
+                        .args
+                        .get(name.as_str())
+                        .cloned()
+                        .unwrap_or(Value::Bool(false)),
+                    BlockExpression::MotionXPosition => Value::Number(support_x_position(t)),
+                    BlockExpression::MotionYPosition => Value::Number(support_y_position(t)),
+                    BlockExpression::MotionDirection => Value::Number(support_direction(t)),
+                    BlockExpression::LooksCostumeNumberName { name: false } => {
+                        Value::Number(support_costume_number(t))
+                    }
+                    BlockExpression::LooksCostumeNumberName { name: true } => {
+                        Value::String(from_c(support_costume_name(t)))
+                    }
+                    BlockExpression::LooksBackdropNumberName { name: false } => {
+                        Value::Number(support_backdrop_number())
+                    }
+                    BlockExpression::LooksBackdropNumberName { name: true } => {
+                        Value::String(from_c(support_backdrop_name()))
+                    }
+                    BlockExpression::LooksSize => Value::Number(support_size(t)),
+                    BlockExpression::SoundVolume => Value::Number(support_volume(t)),
+                    BlockExpression::MusicGetTempo => Value::Number(support_tempo()),
+                    BlockExpression::SensingKeyPressed { key } => {
+                        let key = c(&self.eval(key, frame).string());
+                        Value::Bool(support_key_pressed(key.as_ptr()) != 0)
+                    }
+                }
+            },
+        }
+    }
+
+    /// Scratch compares numerically when both sides look like numbers and
+    /// as case insensitive text otherwise, which the runtime already does.
+    fn compare(&self, left: &scratch::Value, right: &scratch::Value, frame: &Frame) -> Ordering {
+        let left = self.eval(left, frame).string();
+        let right = self.eval(right, frame).string();
+        scratchc_runtime::compare(&left, &right)
+    }
+}
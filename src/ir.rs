@@ -0,0 +1,543 @@
+//! A typed, structured representation of a project's scripts, between the
+//! Scratch blocks and code generation.
+//!
+//! Every script and custom block is a `Function` whose body is a list of
+//! statements with structured control flow, like Scratch's own stacks.
+//! Expressions are trees whose types are known without looking anything up,
+//! and conversions between types are explicit `Cast`s. Everything the
+//! runtime does is a `Call` to one of its functions by name, so backends only
+//! need to know how to call them.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    Number,
+    Bool,
+    /// Text. Literals and names from the runtime last for the rest of the
+    /// program, but text made from a number is a temporary that's freed after
+    /// the statement that made it, so variables and custom block arguments
+    /// keep copies of their own.
+    String,
+    /// Indices and flags handed to the runtime, like the target index.
+    Int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+}
+
+/// Comparisons work on two numbers, or on two strings the way Scratch
+/// compares text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Gt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    Int(i64),
+    /// The value of a Scratch variable.
+    Global(String, Type),
+    Param(usize, Type),
+    Cast(Type, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// Scratch's comparison, where NaN compares as the text "NaN".
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Call(Call),
+}
+
+/// A call to a runtime function, named without its `support_` prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub func: &'static str,
+    pub args: Vec<Expr>,
+    pub ret: Option<Type>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Call(Call),
+    SetGlobal(String, Expr),
+    CallFunction(usize, Vec<Expr>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    /// Run the body a number of times, rounded to the nearest whole number.
+    /// Negative counts and NaN don't run it at all.
+    Repeat(Expr, Vec<Stmt>),
+    /// Never falls through, so it has to be the last statement.
+    Forever(Vec<Stmt>),
+    /// Let the other scripts take their turns.
+    Yield,
+    Return,
+    /// End the program.
+    Exit,
+    /// The statements that follow, up to the next `Source`, come from the
+    /// Scratch block with this ID.
+    Source(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hat {
+    Flag,
+    Key(String),
+    Backdrop(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// The symbol name.
+    pub name: String,
+    /// Which sprite and script or custom block this came from, for people.
+    pub label: String,
+    pub target: usize,
+    pub params: Vec<Type>,
+    /// Scripts have a hat, custom blocks don't.
+    pub hat: Option<Hat>,
+    pub body: Vec<Stmt>,
+}
+
+/// A Scratch variable, which keeps one type for the whole program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub id: String,
+    /// The symbol name.
+    pub symbol: String,
+    pub ty: Type,
+    /// A literal of type `ty`.
+    pub init: Expr,
+}
+
+/// A symbol name made of `parts` joined with dots, which every linker
+/// accepts and which can be read back unambiguously: letters and digits are
+/// kept, `_` is doubled and any other byte becomes `_` and its hex code.
+pub fn symbol(parts: &[&str]) -> String {
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push('.');
+        }
+        for b in part.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => out.push(b as char),
+                b'_' => out.push_str("__"),
+                _ => write!(out, "_{:02x}", b).unwrap(),
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub functions: Vec<Function>,
+}
+
+impl Expr {
+    pub fn ty(&self) -> Type {
+        match self {
+            Expr::Number(_) | Expr::Binary(..) => Type::Number,
+            Expr::Bool(_) | Expr::Compare(..) => Type::Bool,
+            Expr::String(_) => Type::String,
+            Expr::Int(_) => Type::Int,
+            Expr::Global(_, ty) | Expr::Param(_, ty) | Expr::Cast(ty, _) => *ty,
+            // Calls used as expressions always return something.
+            Expr::Call(call) => call.ret.unwrap(),
+        }
+    }
+
+    /// Whether a number could be NaN, which Scratch compares as text rather
+    /// than as a number.
+    pub fn may_be_nan(&self) -> bool {
+        match self {
+            Expr::Number(n) => n.is_nan(),
+            // Text that isn't a number becomes 0.
+            Expr::Cast(..) => false,
+            Expr::Binary(..) | Expr::Global(..) | Expr::Param(..) | Expr::Call(_) => {
+                self.ty() == Type::Number
+            }
+            _ => false,
+        }
+    }
+
+    /// Convert to `ty`, if it isn't that already.
+    pub fn cast(self, ty: Type) -> Expr {
+        if self.ty() == ty {
+            self
+        } else {
+            Expr::Cast(ty, Box::new(self))
+        }
+    }
+}
+
+impl Call {
+    /// Whether other scripts can run before the call returns, because it
+    /// waits for something.
+    pub fn may_yield(&self) -> bool {
+        matches!(
+            self.func,
+            "sleep"
+                | "rest"
+                | "play_note"
+                | "play_drum"
+                | "play_sound"
+                | "play_sound_number"
+                | "switch_backdrop"
+                | "switch_backdrop_number"
+        )
+    }
+}
+
+impl Stmt {
+    /// Whether control never reaches the next statement.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, Stmt::Forever(_) | Stmt::Return | Stmt::Exit)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::Number => "number",
+            Type::Bool => "bool",
+            Type::String => "string",
+            Type::Int => "int",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{:?}", n),
+            Expr::Bool(b) => write!(f, "{}", b),
+            Expr::String(s) => write!(f, "{:?}", s),
+            Expr::Int(i) => write!(f, "#{}", i),
+            Expr::Global(id, _) => write!(f, "@{:?}", id),
+            Expr::Param(i, _) => write!(f, "%{}", i),
+            Expr::Cast(ty, e) => write!(f, "{}({})", ty, e),
+            Expr::Binary(op, l, r) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                };
+                write!(f, "({} {} {})", l, op, r)
+            }
+            Expr::Compare(op, l, r) => {
+                let op = match op {
+                    CompareOp::Eq => "=",
+                    CompareOp::Gt => ">",
+                };
+                write!(f, "({} {} {})", l, op, r)
+            }
+            Expr::Call(call) => write!(f, "{}", call),
+        }
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.func)?;
+        list(f, &self.args)?;
+        write!(f, ")")?;
+        if let Some(ret) = self.ret {
+            write!(f, ": {}", ret)?;
+        }
+        Ok(())
+    }
+}
+
+fn list(f: &mut fmt::Formatter, items: &[impl fmt::Display]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+fn body(f: &mut fmt::Formatter, stmts: &[Stmt], indent: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    for stmt in stmts {
+        self::stmt(f, stmt, indent + 1)?;
+    }
+    write!(f, "{:1$}}}", "", indent * 4)
+}
+
+fn stmt(f: &mut fmt::Formatter, stmt: &Stmt, indent: usize) -> fmt::Result {
+    write!(f, "{:1$}", "", indent * 4)?;
+    match stmt {
+        Stmt::Call(call) => write!(f, "{}", call)?,
+        Stmt::SetGlobal(id, e) => write!(f, "@{:?} = {}", id, e)?,
+        Stmt::CallFunction(func, args) => {
+            write!(f, "call fn{}(", func)?;
+            list(f, args)?;
+            write!(f, ")")?;
+        }
+        Stmt::If(condition, then, otherwise) => {
+            write!(f, "if {} ", condition)?;
+            body(f, then, indent)?;
+            if !otherwise.is_empty() {
+                write!(f, " else ")?;
+                body(f, otherwise, indent)?;
+            }
+        }
+        Stmt::Repeat(times, b) => {
+            write!(f, "repeat {} ", times)?;
+            body(f, b, indent)?;
+        }
+        Stmt::Forever(b) => {
+            write!(f, "forever ")?;
+            body(f, b, indent)?;
+        }
+        Stmt::Yield => write!(f, "yield")?,
+        Stmt::Return => write!(f, "return")?,
+        Stmt::Exit => write!(f, "exit")?,
+        Stmt::Source(id) => write!(f, "source {:?}", id)?,
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for Hat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hat::Flag => write!(f, "when flag clicked"),
+            Hat::Key(key) => write!(f, "when key {:?} pressed", key),
+            Hat::Backdrop(backdrop) => write!(f, "when backdrop switches to {:?}", backdrop),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for global in &self.globals {
+            writeln!(
+                f,
+                "global @{:?}: {} = {}",
+                global.id, global.ty, global.init
+            )?;
+        }
+        for (i, func) in self.functions.iter().enumerate() {
+            if i > 0 || !self.globals.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "fn{} {:?}(", i, func.name)?;
+            list(f, &func.params)?;
+            write!(f, ") target #{}", func.target)?;
+            if let Some(hat) = &func.hat {
+                write!(f, " {}", hat)?;
+            }
+            write!(f, " ")?;
+            body(f, &func.body, 0)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that a program is well formed: globals start out as literals,
+/// expressions have the types their uses expect, functions are called with
+/// the right arguments, runtime functions are always called the same way, and
+/// nothing follows a statement that doesn't fall through.
+pub fn verify(program: &Program) -> Result<(), String> {
+    let mut verifier = Verifier {
+        program,
+        function: 0,
+        globals: program
+            .globals
+            .iter()
+            .map(|g| (g.id.as_str(), g.ty))
+            .collect(),
+        calls: HashMap::new(),
+    };
+    for global in &program.globals {
+        let literal = matches!(global.init, Expr::Number(_) | Expr::String(_));
+        if !literal || global.init.ty() != global.ty {
+            return Err(format!(
+                "global @{:?}: {} isn't a {} literal",
+                global.id, global.init, global.ty
+            ));
+        }
+    }
+    for (i, func) in program.functions.iter().enumerate() {
+        verifier.function = i;
+        if func.hat.is_some() && !func.params.is_empty() {
+            return Err(verifier.error("scripts can't have parameters"));
+        }
+        verifier.body(&func.body)?;
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    function: usize,
+    globals: HashMap<&'a str, Type>,
+    calls: HashMap<&'static str, (Vec<Type>, Option<Type>)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, message: impl fmt::Display) -> String {
+        format!(
+            "fn{} {:?}: {}",
+            self.function, self.program.functions[self.function].name, message
+        )
+    }
+
+    fn expect(&self, e: &Expr, ty: Type) -> Result<(), String> {
+        if e.ty() == ty {
+            Ok(())
+        } else {
+            Err(self.error(format_args!("expected {}, found {}: {}", ty, e.ty(), e)))
+        }
+    }
+
+    fn body(&mut self, stmts: &[Stmt]) -> Result<(), String> {
+        for (i, stmt) in stmts.iter().enumerate() {
+            if stmt.is_terminator() && i + 1 < stmts.len() {
+                return Err(self.error(format_args!("unreachable statement: {:?}", stmts[i + 1])));
+            }
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Call(call) => self.call(call),
+            Stmt::SetGlobal(id, e) => {
+                let ty = self.global(id)?;
+                self.expr(e)?;
+                self.expect(e, ty)
+            }
+            Stmt::CallFunction(func, args) => {
+                let callee = self
+                    .program
+                    .functions
+                    .get(*func)
+                    .ok_or_else(|| self.error(format_args!("no function fn{}", func)))?;
+                if callee.hat.is_some() {
+                    return Err(self.error(format_args!("fn{} is a script", func)));
+                }
+                // Custom blocks are only visible inside their own sprite.
+                if callee.target != self.program.functions[self.function].target {
+                    return Err(self.error(format_args!("fn{} belongs to another target", func)));
+                }
+                if args.len() != callee.params.len() {
+                    return Err(self.error(format_args!(
+                        "fn{} takes {} arguments, found {}",
+                        func,
+                        callee.params.len(),
+                        args.len()
+                    )));
+                }
+                for (arg, ty) in args.iter().zip(&callee.params) {
+                    self.expr(arg)?;
+                    self.expect(arg, *ty)?;
+                }
+                Ok(())
+            }
+            Stmt::If(condition, then, otherwise) => {
+                self.expr(condition)?;
+                self.expect(condition, Type::Bool)?;
+                self.body(then)?;
+                self.body(otherwise)
+            }
+            Stmt::Repeat(times, body) => {
+                self.expr(times)?;
+                self.expect(times, Type::Number)?;
+                self.body(body)
+            }
+            Stmt::Forever(body) => self.body(body),
+            Stmt::Yield | Stmt::Return | Stmt::Exit | Stmt::Source(_) => Ok(()),
+        }
+    }
+
+    fn global(&self, id: &str) -> Result<Type, String> {
+        self.globals
+            .get(id)
+            .copied()
+            .ok_or_else(|| self.error(format_args!("no global @{:?}", id)))
+    }
+
+    fn call(&mut self, call: &Call) -> Result<(), String> {
+        for arg in &call.args {
+            self.expr(arg)?;
+        }
+        let signature = (call.args.iter().map(Expr::ty).collect(), call.ret);
+        match self.calls.get(call.func) {
+            Some(expected) if *expected != signature => Err(self.error(format_args!(
+                "{} called as {:?}, but before as {:?}",
+                call.func, signature, expected
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                self.calls.insert(call.func, signature);
+                Ok(())
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<(), String> {
+        match e {
+            Expr::Number(_) | Expr::Bool(_) | Expr::String(_) | Expr::Int(_) => Ok(()),
+            Expr::Global(id, ty) => {
+                if self.global(id)? != *ty {
+                    return Err(self.error(format_args!("@{:?} isn't a {}", id, ty)));
+                }
+                Ok(())
+            }
+            Expr::Param(i, ty) => {
+                let params = &self.program.functions[self.function].params;
+                match params.get(*i) {
+                    Some(param) if param == ty => Ok(()),
+                    Some(param) => Err(self.error(format_args!(
+                        "parameter %{} is a {}, not a {}",
+                        i, param, ty
+                    ))),
+                    None => Err(self.error(format_args!("no parameter %{}", i))),
+                }
+            }
+            Expr::Cast(ty, inner) => {
+                self.expr(inner)?;
+                match (inner.ty(), ty) {
+                    (Type::Int, _) | (_, Type::Int) => {
+                        Err(self.error(format_args!("can't cast to or from int: {}", e)))
+                    }
+                    (from, to) if from == *to => {
+                        Err(self.error(format_args!("cast to the same type: {}", e)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Expr::Binary(_, l, r) => {
+                self.expr(l)?;
+                self.expr(r)?;
+                self.expect(l, Type::Number)?;
+                self.expect(r, Type::Number)
+            }
+            Expr::Compare(_, l, r) => {
+                self.expr(l)?;
+                self.expr(r)?;
+                if l.ty() != r.ty() || (l.ty() != Type::Number && l.ty() != Type::String) {
+                    return Err(self.error(format_args!("can't compare {}", e)));
+                }
+                Ok(())
+            }
+            Expr::Call(call) => {
+                if call.ret.is_none() {
+                    return Err(self.error(format_args!("{} doesn't return anything", call)));
+                }
+                self.call(call)
+            }
+        }
+    }
+}
@@ -1,85 +1,371 @@
+mod analysis;
 mod compiler;
+mod costume;
+mod dwarf;
+mod fold;
+mod infer;
+mod interpret;
+mod ir;
+mod lower;
 mod scratch;
+mod sound;
+mod source;
+mod wasm;
 
-pub fn compile(
-    module: &mut impl cranelift_module::Module,
-    file: impl std::io::Read + std::io::Seek,
-) {
-    let project = scratch::ProjectInfo::new(file).unwrap();
+/// How much effort to spend optimizing the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// Compile as fast as possible.
+    None,
+    /// Optimize for speed.
+    #[default]
+    Speed,
+    /// Optimize, but prefer smaller code.
+    Size,
+}
 
-    let mut variables = vec![];
-    let mut procedures = vec![];
-    let mut scripts = vec![];
+impl std::str::FromStr for OptLevel {
+    type Err = String;
 
-    for target in project.targets {
-        let target = scratch::Target::hydrate(target);
-        for var in target.variables.keys() {
-            variables.push(var.clone());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OptLevel::None),
+            "speed" => Ok(OptLevel::Speed),
+            "size" => Ok(OptLevel::Size),
+            _ => Err(format!(
+                "unknown optimization level {:?}, expected none, speed or size",
+                s
+            )),
         }
+    }
+}
+
+/// What to compile projects for.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Target {
+    /// The machine the compiler runs on.
+    #[default]
+    Native,
+    /// Another machine, like `aarch64-unknown-linux-gnu`. scratchc has to be
+    /// built with its runtime, by listing it in `SCRATCHC_TARGETS`.
+    Cross(target_lexicon::Triple),
+    /// A standalone WebAssembly module for WASI, with the runtime built in.
+    Wasm,
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
 
-        for proc in target.procedures {
-            procedures.push(proc);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Target::Native),
+            "wasm32-wasi" => Ok(Target::Wasm),
+            _ => s
+                .parse()
+                .map(Target::Cross)
+                .map_err(|e| format!("unknown target {:?}: {}", s, e)),
         }
+    }
+}
+
+/// What to write out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Emit {
+    /// An executable, linked with the runtime.
+    #[default]
+    Exe,
+    /// The object file, before linking.
+    Obj,
+    /// The machine code of every function, as text. For WebAssembly, the
+    /// module in the text format. On x86-64 it's the Cranelift IR with the
+    /// encoding of every instruction, since that backend can't disassemble.
+    Asm,
+    /// The Cranelift IR of every function, as it's handed to the backend.
+    Clif,
+}
 
-        for script in target.scripts {
-            scripts.push(script);
+impl std::str::FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exe" => Ok(Emit::Exe),
+            "obj" => Ok(Emit::Obj),
+            "asm" => Ok(Emit::Asm),
+            "clif" => Ok(Emit::Clif),
+            _ => Err(format!(
+                "unknown output {:?}, expected exe, obj, asm or clif",
+                s
+            )),
         }
     }
+}
 
-    compiler::compile(module, &variables, &procedures, &scripts);
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub opt_level: OptLevel,
+    pub target: Target,
+    pub emit: Emit,
+    /// Emit DWARF debug info, which points into a scratchblocks listing of
+    /// every target that's written to this directory.
+    pub debug_info: Option<std::path::PathBuf>,
+    /// Log every block as it runs, see the README.
+    pub trace: bool,
+    /// Count and time every block and custom block, and report them at exit.
+    pub profile: bool,
+    /// Limit how deeply custom blocks nest and name them in the watchdog's
+    /// reports, see the README. By default only with `OptLevel::None`.
+    pub guard: Option<bool>,
 }
 
-pub fn compile_native(file: impl std::io::Read + std::io::Seek, out_name: &str) {
-    let o = {
-        use cranelift::prelude::*;
+struct Project {
+    targets: Vec<scratch::Target>,
+    extensions: Vec<String>,
+    program: ir::Program,
+}
 
-        let mut flag_builder = settings::builder();
-        flag_builder.set("is_pic", "true").unwrap();
-        flag_builder.set("opt_level", "speed_and_size").unwrap();
-        let flags = settings::Flags::new(flag_builder);
+fn load(file: impl std::io::Read + std::io::Seek, instrument: lower::Instrument) -> Project {
+    let mut project = scratch::ProjectInfo::new(file).unwrap();
+    // Scripts start like in Scratch, from the sprite in front back to the
+    // stage, and each target's in the order they're saved in.
+    project
+        .targets
+        .sort_by_key(|t| std::cmp::Reverse(t.layer_order));
 
-        let isa = cranelift_native::builder().unwrap().finish(flags);
+    let assets = &project.assets;
+    let backdrops: Vec<_> = project
+        .targets
+        .iter()
+        .filter(|t| t.is_stage)
+        .flat_map(|t| t.costumes.iter().map(|c| c.name.clone()))
+        .collect();
+    let backdrops: Vec<_> = backdrops.iter().map(|b| b.as_str()).collect();
+    let targets: Vec<_> = project
+        .targets
+        .into_iter()
+        .map(|target| {
+            let reachability = analysis::Reachability::analyze(&target, &backdrops);
+            for warning in &reachability.warnings {
+                eprintln!("warning: {}: {}", target.name, warning);
+            }
+            scratch::Target::hydrate(target, &reachability.live, assets)
+        })
+        .collect();
 
-        let mut module = cranelift_object::ObjectModule::new(
-            cranelift_object::ObjectBuilder::new(
-                isa,
-                "",
-                cranelift_module::default_libcall_names(),
-            )
+    let mut program = lower::lower(&targets, instrument);
+    fold::fold(&mut program);
+    if let Err(e) = ir::verify(&program) {
+        panic!("{}\n\n{}", e, program);
+    }
+
+    Project {
+        targets,
+        extensions: project.extensions,
+        program,
+    }
+}
+
+/// Compile a project for `options.target`: an object file to link with the
+/// runtime, or a WebAssembly module that already contains it. With
+/// `Emit::Asm` or `Emit::Clif`, the listing of every function instead. The
+/// error says what the target can't do that the project or `options` need.
+pub fn compile(
+    file: impl std::io::Read + std::io::Seek,
+    options: &Options,
+) -> Result<Vec<u8>, String> {
+    let project = load(
+        file,
+        lower::Instrument {
+            trace: options.trace,
+            profile: options.profile,
+            guard: options.guard.unwrap_or(options.opt_level == OptLevel::None),
+        },
+    );
+    if options.target == Target::Wasm {
+        if options.debug_info.is_some() {
+            return Err("the WebAssembly backend doesn't support debug info yet".to_owned());
+        }
+        if options.profile {
+            return Err("the WebAssembly backend doesn't support profiling yet".to_owned());
+        }
+        if options.emit == Emit::Clif {
+            return Err("WebAssembly isn't compiled with Cranelift, use --emit=asm".to_owned());
+        }
+        let wat = wasm::text(&project.program, &project.targets)?;
+        return Ok(match options.emit {
+            Emit::Asm => wat.into_bytes(),
+            _ => wasm::assemble(&wat),
+        });
+    }
+
+    use cranelift::prelude::*;
+
+    let mut flag_builder = settings::builder();
+    flag_builder.set("is_pic", "true").unwrap();
+    flag_builder
+        .set(
+            "opt_level",
+            match options.opt_level {
+                OptLevel::None => "none",
+                OptLevel::Speed => "speed",
+                OptLevel::Size => "speed_and_size",
+            },
+        )
+        .unwrap();
+    let flags = settings::Flags::new(flag_builder);
+
+    let isa = match &options.target {
+        Target::Native => cranelift_native::builder()
+            .map_err(|e| format!("can't compile for this machine: {}", e))?,
+        Target::Cross(triple) => isa::lookup(triple.clone())
+            .map_err(|e| format!("can't compile for {}: {}", triple, e))?,
+        Target::Wasm => unreachable!(),
+    }
+    .finish(flags);
+
+    let mut module = cranelift_object::ObjectModule::new(
+        cranelift_object::ObjectBuilder::new(isa, "", cranelift_module::default_libcall_names())
             .unwrap(),
+    );
+
+    let sources = options
+        .debug_info
+        .as_ref()
+        .map(|dir| write_sources(&project.targets, dir));
+
+    let output = compiler::compile(
+        &mut module,
+        &project.program,
+        &project.targets,
+        &project.extensions,
+        options.opt_level != OptLevel::None,
+        options.emit,
+        sources.as_ref().map(|(_, sources, _)| &sources[..]),
+    );
+
+    if options.emit == Emit::Asm || options.emit == Emit::Clif {
+        return Ok(output.listing.into_bytes());
+    }
+
+    let triple = cranelift_module::Module::isa(&module).triple().clone();
+    let mut product = module.finish();
+    if let Some((dir, _, files)) = &sources {
+        dwarf::emit(
+            &mut product,
+            &output.functions,
+            &output.globals,
+            &project.program,
+            &project.targets,
+            dir,
+            files,
+            &triple,
         );
+    }
+    Ok(product.emit().unwrap())
+}
+
+/// Write every target's listing to `dir`, returning its full path, the
+/// listings and their file names.
+fn write_sources(
+    targets: &[scratch::Target],
+    dir: &std::path::Path,
+) -> (String, Vec<source::Source>, Vec<String>) {
+    std::fs::create_dir_all(dir).unwrap();
+    let dir = std::fs::canonicalize(dir).unwrap();
+
+    let names = source::names(targets);
+    let mut sources = vec![];
+    let mut files = vec![];
+    for target in targets {
+        let source = source::Source::new(target, &names);
+        let file = format!("{}.txt", ir::symbol(&[&target.name]));
+        std::fs::write(dir.join(&file), &source.text).unwrap();
+        sources.push(source);
+        files.push(file);
+    }
+    (dir.to_str().unwrap().to_owned(), sources, files)
+}
+
+/// The project's scripts in the compiler's intermediate representation.
+pub fn ir(file: impl std::io::Read + std::io::Seek) -> String {
+    load(file, Default::default()).program.to_string()
+}
+
+/// Run a project with the reference interpreter instead of compiling it. It
+/// only returns if every script finishes, and only once per process.
+pub fn interpret(file: impl std::io::Read + std::io::Seek) {
+    let project = load(file, Default::default());
+    interpret::run(project.targets, &project.extensions);
+}
+
+/// The runtime built for each target triple, and the libraries it needs.
+const SUPPORT: &[(&str, &[u8], &[&str])] = include!(concat!(env!("OUT_DIR"), "/libsupport.rs"));
+
+/// Compile a project to an executable, or whatever else `options.emit` asks
+/// for. WebAssembly modules don't need linking, so for them that's just the
+/// module. Fails like `compile`, or when scratchc has no runtime for the
+/// target or linking fails.
+pub fn compile_native(
+    file: impl std::io::Read + std::io::Seek,
+    out_name: &str,
+    options: &Options,
+) -> Result<(), String> {
+    let o = compile(file, options)?;
+    if options.target == Target::Wasm || options.emit != Emit::Exe {
+        std::fs::write(out_name, o).unwrap();
+        return Ok(());
+    }
 
-        compile(&mut module, file);
+    let clang_opt_level = match options.opt_level {
+        OptLevel::None => "-O0",
+        OptLevel::Speed => "-O3",
+        OptLevel::Size => "-Os",
+    };
 
-        module.finish().emit().unwrap()
+    let triple = match &options.target {
+        Target::Cross(triple) => triple.to_string(),
+        _ => target_lexicon::Triple::host().to_string(),
     };
+    let (_, support, libs) = SUPPORT
+        .iter()
+        .find(|(t, _, _)| *t == triple)
+        .ok_or_else(|| {
+            format!(
+                "scratchc was built without the runtime for {0}, rebuild it with SCRATCHC_TARGETS={0}",
+                triple
+            )
+        })?;
 
     // FIXME: this is terrible
     let tmp = std::env::temp_dir();
+    let support_path = tmp.join(format!("libsupport-{}.a", triple));
     std::fs::write(tmp.join("out.o"), o).unwrap();
-    std::fs::write(
-        tmp.join("libsupport.a"),
-        include_bytes!(concat!(env!("OUT_DIR"), "/libsupport.a")),
-    )
-    .unwrap();
-
-    let mut args = vec![
-        "-O3".to_owned(),
+    std::fs::write(&support_path, support).unwrap();
+
+    let mut args = vec![clang_opt_level.to_owned()];
+    if let Target::Cross(_) = options.target {
+        args.push(format!("--target={}", triple));
+    }
+    args.extend([
         tmp.join("out.o").to_str().unwrap().to_owned(),
-        tmp.join("libsupport.a").to_str().unwrap().to_owned(),
+        support_path.to_str().unwrap().to_owned(),
         "-o".to_owned(),
         out_name.to_owned(),
-    ];
-    for arg in include!(concat!(env!("OUT_DIR"), "/libsupport_libs.rs")) {
+    ]);
+    for arg in libs.iter() {
         args.push(arg.to_string());
     }
 
     let r = std::process::Command::new("clang++")
         .args(args)
         .output()
-        .unwrap();
+        .map_err(|e| format!("can't run clang++ to link: {}", e))?;
 
     if !r.status.success() {
-        panic!("{}", String::from_utf8(r.stderr).unwrap());
+        return Err(format!(
+            "linking with clang++ failed:\n{}",
+            String::from_utf8_lossy(&r.stderr)
+        ));
     }
+    Ok(())
 }
@@ -0,0 +1,637 @@
+//! Lowering from Scratch blocks to the IR.
+
+use crate::fold;
+use crate::infer;
+use crate::ir::{
+    self, BinaryOp, Call, CompareOp, Expr, Function, Global, Hat, Program, Stmt, Type,
+};
+use crate::scratch;
+use std::collections::HashMap;
+
+pub fn procedure_name(target: &scratch::Target, proc: &scratch::Procedure) -> String {
+    ir::symbol(&[&target.name, "define", &proc.definition])
+}
+
+pub fn script_name(target: &scratch::Target, script: &scratch::Block, hat: &Hat) -> String {
+    let hat = match hat {
+        Hat::Flag => "whenflagclicked",
+        Hat::Key(_) => "whenkeypressed",
+        Hat::Backdrop(_) => "whenbackdropswitchesto",
+    };
+    ir::symbol(&[&target.name, hat, &script.id])
+}
+
+/// Calls to the runtime before every block, for watching what a project does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Instrument {
+    pub trace: bool,
+    pub profile: bool,
+    /// Tell the runtime when custom blocks start and finish, for the limit on
+    /// how deep they nest and the watchdog.
+    pub guard: bool,
+}
+
+impl Instrument {
+    /// Whether custom blocks call `enter_procedure` and `exit_procedure`,
+    /// which the profiler also needs.
+    fn procedures(&self) -> bool {
+        self.guard || self.profile
+    }
+}
+
+pub fn lower(targets: &[scratch::Target], instrument: Instrument) -> Program {
+    let mut program = Program::default();
+
+    let variables = infer::variable_types(targets);
+    for target in targets {
+        for (id, (name, initial)) in &target.variables {
+            let ty = variables[id];
+            let init = match initial {
+                serde_json::Value::Number(n) => Expr::Number(n.as_f64().unwrap()),
+                serde_json::Value::String(s) => Expr::String(s.clone()),
+                v => Expr::String(v.to_string()),
+            };
+            program.globals.push(Global {
+                id: id.clone(),
+                symbol: ir::symbol(&[&target.name, "var", name, id]),
+                ty,
+                init: fold::expr(init.cast(ty)),
+            });
+        }
+    }
+
+    // Custom blocks come first, so that calls can be resolved before any of
+    // them are lowered.
+    let mut procedures = HashMap::new();
+    for (t, target) in targets.iter().enumerate() {
+        for proc in &target.procedures {
+            procedures.insert((t, proc.id.clone()), program.functions.len());
+            program.functions.push(Function {
+                name: procedure_name(target, proc),
+                label: format!("{}: define {}", target.name, proc.id),
+                target: t,
+                params: proc
+                    .arguments
+                    .iter()
+                    .map(|(_, kind)| argument_type(*kind))
+                    .collect(),
+                hat: None,
+                body: vec![],
+            });
+        }
+    }
+
+    let mut bodies = vec![];
+    for (t, target) in targets.iter().enumerate() {
+        for proc in &target.procedures {
+            let l = Lowerer {
+                targets,
+                variables: &variables,
+                procedures: &procedures,
+                target: t,
+                args: proc
+                    .arguments
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, kind))| (name.clone(), (i, argument_type(*kind))))
+                    .collect(),
+                procedure: true,
+                instrument,
+                script: String::new(),
+                label: String::new(),
+            };
+            // Entered first, so that the profiler counts the `define` block
+            // as part of it.
+            let mut body = vec![];
+            if instrument.procedures() {
+                body.push(l.target_stmt("enter_procedure", vec![Expr::String(proc.id.clone())]));
+            }
+            l.source(&proc.definition, "procedures_definition", &mut body);
+            l.stack(&proc.body, &mut body);
+            l.fall_off_end(&mut body);
+            bodies.push(body);
+        }
+    }
+    for (func, body) in program.functions.iter_mut().zip(bodies) {
+        func.body = body;
+    }
+
+    for (t, target) in targets.iter().enumerate() {
+        for script in &target.scripts {
+            let hat = match &script.op {
+                scratch::BlockOp::EventWhenKeyPressed(key) => Hat::Key(key.clone()),
+                scratch::BlockOp::EventWhenBackdropSwitchesTo(backdrop) => {
+                    Hat::Backdrop(backdrop.clone())
+                }
+                _ => Hat::Flag,
+            };
+            let label = format!("{}: {}", target.name, hat);
+            let l = Lowerer {
+                targets,
+                variables: &variables,
+                procedures: &procedures,
+                target: t,
+                args: HashMap::new(),
+                procedure: false,
+                instrument,
+                script: script.id.clone(),
+                label: format!("{} ({})", label, script.id),
+            };
+            let mut body = vec![];
+            l.stack(script, &mut body);
+            program.functions.push(Function {
+                name: script_name(target, script, &hat),
+                label,
+                target: t,
+                params: vec![],
+                hat: Some(hat),
+                body,
+            });
+        }
+    }
+
+    program
+}
+
+fn argument_type(kind: scratch::ArgumentKind) -> Type {
+    match kind {
+        scratch::ArgumentKind::StringNumber => Type::String,
+        scratch::ArgumentKind::Boolean => Type::Bool,
+    }
+}
+
+struct Lowerer<'a> {
+    targets: &'a [scratch::Target],
+    variables: &'a HashMap<String, Type>,
+    procedures: &'a HashMap<(usize, String), usize>,
+    target: usize,
+    args: HashMap<String, (usize, Type)>,
+    procedure: bool,
+    instrument: Instrument,
+    /// The ID of the script's hat block, or empty in a custom block, which
+    /// runs as part of whichever script called it.
+    script: String,
+    /// What profiles call the script, likewise.
+    label: String,
+}
+
+impl<'a> Lowerer<'a> {
+    fn call(&self, func: &'static str, args: Vec<Expr>, ret: Option<Type>) -> Stmt {
+        Stmt::Call(Call { func, args, ret })
+    }
+
+    /// A call to a runtime function about the current target, which is passed
+    /// first.
+    fn target_call(&self, func: &'static str, mut args: Vec<Expr>, ret: Option<Type>) -> Call {
+        args.insert(0, Expr::Int(self.target as i64));
+        Call { func, args, ret }
+    }
+
+    fn target_stmt(&self, func: &'static str, args: Vec<Expr>) -> Stmt {
+        Stmt::Call(self.target_call(func, args, None))
+    }
+
+    fn target_expr(&self, func: &'static str, ret: Type) -> Expr {
+        Expr::Call(self.target_call(func, vec![], Some(ret)))
+    }
+
+    fn expr(&self, func: &'static str, args: Vec<Expr>, ret: Type) -> Expr {
+        Expr::Call(Call {
+            func,
+            args,
+            ret: Some(ret),
+        })
+    }
+
+    fn returns(&self, out: &mut Vec<Stmt>) {
+        if self.procedure && self.instrument.procedures() {
+            out.push(self.call("exit_procedure", vec![], None));
+        }
+        out.push(Stmt::Return);
+    }
+
+    fn fall_off_end(&self, out: &mut Vec<Stmt>) {
+        if self.procedure && !terminated(out) {
+            self.returns(out);
+        }
+    }
+
+    fn stack(&self, block: &scratch::Block, out: &mut Vec<Stmt>) {
+        let mut block = Some(block);
+        while let Some(b) = block {
+            self.block(b, out);
+            if terminated(out) {
+                break;
+            }
+            block = b.next.as_deref();
+        }
+    }
+
+    fn substack(&self, block: &scratch::Block) -> Vec<Stmt> {
+        let mut out = vec![];
+        self.stack(block, &mut out);
+        out
+    }
+
+    /// The body of a loop, which yields at the end of every iteration.
+    fn loop_body(&self, block: &scratch::Block) -> Vec<Stmt> {
+        let mut out = self.substack(block);
+        if !terminated(&out) {
+            out.push(Stmt::Yield);
+        }
+        out
+    }
+
+    fn say(&self, message: &scratch::Value, think: bool) -> Stmt {
+        let think = Expr::Bool(think);
+        if self.is_number(message) {
+            self.target_stmt("say_float", vec![self.number(message), think])
+        } else {
+            self.target_stmt("say_string", vec![self.string(message), think])
+        }
+    }
+
+    fn source(&self, id: &str, opcode: &str, out: &mut Vec<Stmt>) {
+        out.push(Stmt::Source(id.to_owned()));
+        let block = |script: &str| {
+            vec![
+                Expr::String(script.to_owned()),
+                Expr::String(id.to_owned()),
+                Expr::String(opcode.to_owned()),
+            ]
+        };
+        if self.instrument.trace {
+            out.push(self.target_stmt("trace", block(&self.script)));
+        }
+        if self.instrument.profile {
+            out.push(self.target_stmt("profile_block", block(&self.label)));
+        }
+    }
+
+    fn block(&self, b: &scratch::Block, out: &mut Vec<Stmt>) {
+        self.source(&b.id, &b.opcode, out);
+        let t = |func, args| self.target_stmt(func, args);
+        let stmt = match &b.op {
+            scratch::BlockOp::ControlRepeat { times, body } => {
+                Stmt::Repeat(self.number(times), self.loop_body(body))
+            }
+            scratch::BlockOp::ControlForever(body) => Stmt::Forever(self.loop_body(body)),
+            scratch::BlockOp::ControlWait(delay) => {
+                self.call("sleep", vec![self.number(delay)], None)
+            }
+            scratch::BlockOp::ControlIfElse {
+                condition,
+                consequent,
+                alternative,
+            } => Stmt::If(
+                self.bool(condition),
+                self.substack(consequent),
+                alternative
+                    .as_ref()
+                    .map(|a| self.substack(a))
+                    .unwrap_or_default(),
+            ),
+            scratch::BlockOp::ControlStopAll => {
+                out.push(self.call("detach_scripts", vec![], None));
+                out.push(self.call("stop_all_sounds", vec![], None));
+                Stmt::Exit
+            }
+            scratch::BlockOp::ControlStopScript => return self.returns(out),
+            scratch::BlockOp::LooksSay { message, think } => self.say(message, *think),
+            scratch::BlockOp::LooksSayForSecs {
+                message,
+                secs,
+                think,
+            } => {
+                out.push(self.say(message, *think));
+                out.push(self.call("sleep", vec![self.number(secs)], None));
+                t("clear_bubble", vec![])
+            }
+            scratch::BlockOp::LooksSwitchCostumeTo(costume) => {
+                if self.is_number(costume) {
+                    t("switch_costume_number", vec![self.number(costume)])
+                } else {
+                    t("switch_costume", vec![self.string(costume)])
+                }
+            }
+            scratch::BlockOp::LooksNextCostume => t("next_costume", vec![]),
+            scratch::BlockOp::LooksSwitchBackdropTo { backdrop, wait } => {
+                let wait = Expr::Bool(*wait);
+                if self.is_number(backdrop) {
+                    self.call(
+                        "switch_backdrop_number",
+                        vec![self.number(backdrop), wait],
+                        None,
+                    )
+                } else {
+                    self.call("switch_backdrop", vec![self.string(backdrop), wait], None)
+                }
+            }
+            scratch::BlockOp::LooksNextBackdrop => self.call("next_backdrop", vec![], None),
+            scratch::BlockOp::LooksChangeSizeBy(change) => {
+                let size = self.target_expr("size", Type::Number);
+                t("set_size", vec![add(size, self.number(change))])
+            }
+            scratch::BlockOp::LooksSetSizeTo(size) => t("set_size", vec![self.number(size)]),
+            scratch::BlockOp::LooksChangeEffectBy { effect, value } => {
+                let effect = Expr::Int(effect_index(effect));
+                let current = Expr::Call(self.target_call(
+                    "effect",
+                    vec![effect.clone()],
+                    Some(Type::Number),
+                ));
+                t("set_effect", vec![effect, add(current, self.number(value))])
+            }
+            scratch::BlockOp::LooksSetEffectTo { effect, value } => t(
+                "set_effect",
+                vec![Expr::Int(effect_index(effect)), self.number(value)],
+            ),
+            scratch::BlockOp::LooksClearGraphicEffects => t("clear_effects", vec![]),
+            scratch::BlockOp::SoundPlay { sound, wait } => {
+                let wait = Expr::Bool(*wait);
+                if self.is_number(sound) {
+                    t("play_sound_number", vec![self.number(sound), wait])
+                } else {
+                    t("play_sound", vec![self.string(sound), wait])
+                }
+            }
+            scratch::BlockOp::SoundStopAllSounds => self.call("stop_all_sounds", vec![], None),
+            scratch::BlockOp::SoundChangeVolumeBy(value) => {
+                let volume = self.target_expr("volume", Type::Number);
+                t("set_volume", vec![add(volume, self.number(value))])
+            }
+            scratch::BlockOp::SoundSetVolumeTo(value) => t("set_volume", vec![self.number(value)]),
+            scratch::BlockOp::SoundChangeEffectBy { effect, value } => {
+                let effect = Expr::Int(sound_effect_index(effect));
+                let current = Expr::Call(self.target_call(
+                    "sound_effect",
+                    vec![effect.clone()],
+                    Some(Type::Number),
+                ));
+                t(
+                    "set_sound_effect",
+                    vec![effect, add(current, self.number(value))],
+                )
+            }
+            scratch::BlockOp::SoundSetEffectTo { effect, value } => t(
+                "set_sound_effect",
+                vec![Expr::Int(sound_effect_index(effect)), self.number(value)],
+            ),
+            scratch::BlockOp::SoundClearEffects => t("clear_sound_effects", vec![]),
+            scratch::BlockOp::MusicPlayNoteForBeats { note, beats } => {
+                t("play_note", vec![self.number(note), self.number(beats)])
+            }
+            scratch::BlockOp::MusicPlayDrumForBeats { drum, beats } => {
+                t("play_drum", vec![self.number(drum), self.number(beats)])
+            }
+            scratch::BlockOp::MusicRestForBeats(beats) => {
+                self.call("rest", vec![self.number(beats)], None)
+            }
+            scratch::BlockOp::MusicSetTempo(tempo) => {
+                self.call("set_tempo", vec![self.number(tempo)], None)
+            }
+            scratch::BlockOp::MusicChangeTempo(change) => {
+                let tempo = self.expr("tempo", vec![], Type::Number);
+                self.call("set_tempo", vec![add(tempo, self.number(change))], None)
+            }
+            scratch::BlockOp::MusicSetInstrument(instrument) => {
+                t("set_instrument", vec![self.number(instrument)])
+            }
+            scratch::BlockOp::LooksShow => t("set_visible", vec![Expr::Bool(true)]),
+            scratch::BlockOp::LooksHide => t("set_visible", vec![Expr::Bool(false)]),
+            scratch::BlockOp::LooksGoToFrontBack { front } => {
+                t("go_to_front_back", vec![Expr::Bool(*front)])
+            }
+            scratch::BlockOp::LooksGoForwardBackwardLayers { forward, layers } => {
+                let mut layers = self.number(layers);
+                if !forward {
+                    layers =
+                        Expr::Binary(BinaryOp::Sub, Box::new(Expr::Number(0.0)), Box::new(layers));
+                }
+                t("go_forward_layers", vec![layers])
+            }
+            scratch::BlockOp::MotionMoveSteps(steps) => t("move_steps", vec![self.number(steps)]),
+            scratch::BlockOp::MotionTurnRight(degrees) => {
+                let direction = self.target_expr("direction", Type::Number);
+                t(
+                    "point_in_direction",
+                    vec![add(direction, self.number(degrees))],
+                )
+            }
+            scratch::BlockOp::MotionTurnLeft(degrees) => {
+                let direction = self.target_expr("direction", Type::Number);
+                let tmp = Expr::Binary(
+                    BinaryOp::Sub,
+                    Box::new(direction),
+                    Box::new(self.number(degrees)),
+                );
+                t("point_in_direction", vec![tmp])
+            }
+            scratch::BlockOp::MotionPointInDirection(direction) => {
+                t("point_in_direction", vec![self.number(direction)])
+            }
+            scratch::BlockOp::MotionGoToXY { x, y } => {
+                t("go_to", vec![self.number(x), self.number(y)])
+            }
+            scratch::BlockOp::MotionChangeXBy(x) => {
+                let current = self.target_expr("x_position", Type::Number);
+                let y = self.target_expr("y_position", Type::Number);
+                t("go_to", vec![add(current, self.number(x)), y])
+            }
+            scratch::BlockOp::MotionSetX(x) => {
+                let y = self.target_expr("y_position", Type::Number);
+                t("go_to", vec![self.number(x), y])
+            }
+            scratch::BlockOp::MotionChangeYBy(y) => {
+                let x = self.target_expr("x_position", Type::Number);
+                let current = self.target_expr("y_position", Type::Number);
+                t("go_to", vec![x, add(current, self.number(y))])
+            }
+            scratch::BlockOp::MotionSetY(y) => {
+                let x = self.target_expr("x_position", Type::Number);
+                t("go_to", vec![x, self.number(y)])
+            }
+            scratch::BlockOp::EventWhenFlagClicked
+            | scratch::BlockOp::EventWhenKeyPressed(_)
+            | scratch::BlockOp::EventWhenBackdropSwitchesTo(_) => return,
+            scratch::BlockOp::DataSetVariableTo { id, value } => {
+                Stmt::SetGlobal(id.clone(), self.value(value, self.variables[id]))
+            }
+            scratch::BlockOp::DataChangeVariableBy { id, value } => {
+                let ty = self.variables[id];
+                let current = Expr::Global(id.clone(), ty).cast(Type::Number);
+                Stmt::SetGlobal(id.clone(), add(current, self.number(value)).cast(ty))
+            }
+            scratch::BlockOp::ProceduresCall { proc, args } => {
+                // Like Scratch, calling a block another sprite defines does
+                // nothing.
+                let func = match self.procedures.get(&(self.target, proc.clone())) {
+                    Some(func) => *func,
+                    None => return,
+                };
+                let definition = self.targets[self.target]
+                    .procedures
+                    .iter()
+                    .find(|p| p.id == *proc)
+                    .unwrap();
+                let args = args
+                    .iter()
+                    .zip(&definition.arguments)
+                    .map(|(v, (_, kind))| self.value(v, argument_type(*kind)))
+                    .collect();
+                // Everything a warp procedure calls runs in warp mode too, so
+                // it's tracked at runtime rather than in each procedure.
+                if definition.warp {
+                    out.push(self.call("enter_warp", vec![], None));
+                }
+                out.push(Stmt::CallFunction(func, args));
+                if definition.warp {
+                    self.call("exit_warp", vec![], None)
+                } else {
+                    return;
+                }
+            }
+        };
+        out.push(stmt);
+    }
+
+    /// The type the value has before any conversion.
+    fn ty(&self, v: &scratch::Value) -> Type {
+        infer::value_type(v, self.variables)
+    }
+
+    /// Whether `v` is a number rather than text that looks like one, like a
+    /// menu item, which costumes and sounds are looked up by name first.
+    fn is_number(&self, v: &scratch::Value) -> bool {
+        self.ty(v) == Type::Number && !matches!(v, scratch::Value::String(_))
+    }
+
+    fn number(&self, v: &scratch::Value) -> Expr {
+        self.value(v, Type::Number)
+    }
+
+    fn bool(&self, v: &scratch::Value) -> Expr {
+        self.value(v, Type::Bool)
+    }
+
+    fn string(&self, v: &scratch::Value) -> Expr {
+        self.value(v, Type::String)
+    }
+
+    fn value(&self, v: &scratch::Value, ty: Type) -> Expr {
+        if let (scratch::Value::String(s), Type::String) = (v, ty) {
+            // Keep the text as written, even if it looks like a number.
+            return Expr::String(s.clone());
+        }
+        self.typed(v).cast(ty)
+    }
+
+    /// Lower the value in its own type, as given by `ty`.
+    fn typed(&self, v: &scratch::Value) -> Expr {
+        match v {
+            scratch::Value::Number(n) => Expr::Number(*n),
+            scratch::Value::String(s) => match s.parse::<f64>() {
+                Ok(n) if n.is_finite() => Expr::Number(n),
+                _ => Expr::String(s.clone()),
+            },
+            scratch::Value::Load(id) => Expr::Global(id.clone(), self.variables[id]),
+            scratch::Value::Expression(b) => match &**b {
+                scratch::BlockExpression::OperatorEquals { left, right } => {
+                    self.compare(CompareOp::Eq, left, right)
+                }
+                scratch::BlockExpression::OperatorGT { left, right } => {
+                    self.compare(CompareOp::Gt, left, right)
+                }
+                scratch::BlockExpression::OperatorAdd { left, right } => {
+                    add(self.number(left), self.number(right))
+                }
+                scratch::BlockExpression::OperatorSubtract { left, right } => Expr::Binary(
+                    BinaryOp::Sub,
+                    Box::new(self.number(left)),
+                    Box::new(self.number(right)),
+                ),
+                scratch::BlockExpression::ArgumentReporterStringNumber { name } => {
+                    match self.args.get(name) {
+                        Some((i, ty)) => Expr::Param(*i, *ty).cast(Type::String),
+                        // Used outside of its definition.
+                        None => Expr::String("0".to_owned()),
+                    }
+                }
+                scratch::BlockExpression::ArgumentReporterBoolean { name } => {
+                    match self.args.get(name) {
+                        Some((i, ty)) => Expr::Param(*i, *ty).cast(Type::Bool),
+                        None => Expr::Bool(false),
+                    }
+                }
+                scratch::BlockExpression::MotionXPosition => {
+                    self.target_expr("x_position", Type::Number)
+                }
+                scratch::BlockExpression::MotionYPosition => {
+                    self.target_expr("y_position", Type::Number)
+                }
+                scratch::BlockExpression::MotionDirection => {
+                    self.target_expr("direction", Type::Number)
+                }
+                scratch::BlockExpression::LooksCostumeNumberName { name: false } => {
+                    self.target_expr("costume_number", Type::Number)
+                }
+                scratch::BlockExpression::LooksCostumeNumberName { name: true } => {
+                    self.target_expr("costume_name", Type::String)
+                }
+                scratch::BlockExpression::LooksBackdropNumberName { name: false } => {
+                    self.expr("backdrop_number", vec![], Type::Number)
+                }
+                scratch::BlockExpression::LooksBackdropNumberName { name: true } => {
+                    self.expr("backdrop_name", vec![], Type::String)
+                }
+                scratch::BlockExpression::LooksSize => self.target_expr("size", Type::Number),
+                scratch::BlockExpression::SoundVolume => self.target_expr("volume", Type::Number),
+                scratch::BlockExpression::MusicGetTempo => self.expr("tempo", vec![], Type::Number),
+                scratch::BlockExpression::SensingKeyPressed { key } => {
+                    self.expr("key_pressed", vec![self.string(key)], Type::Bool)
+                }
+            },
+        }
+    }
+
+    /// Scratch compares numerically when both sides are numbers and falls
+    /// back to comparing case insensitive text otherwise, which includes
+    /// numbers that turn out to be NaN.
+    fn compare(&self, op: CompareOp, left: &scratch::Value, right: &scratch::Value) -> Expr {
+        let (l, r) = if self.ty(left) == Type::Number && self.ty(right) == Type::Number {
+            (self.number(left), self.number(right))
+        } else {
+            (self.string(left), self.string(right))
+        };
+        Expr::Compare(op, Box::new(l), Box::new(r))
+    }
+}
+
+fn add(l: Expr, r: Expr) -> Expr {
+    Expr::Binary(BinaryOp::Add, Box::new(l), Box::new(r))
+}
+
+pub fn effect_index(effect: &str) -> i64 {
+    match effect {
+        "color" => 0,
+        "fisheye" => 1,
+        "whirl" => 2,
+        "pixelate" => 3,
+        "mosaic" => 4,
+        "brightness" => 5,
+        "ghost" => 6,
+        _ => panic!("{}", effect),
+    }
+}
+
+pub fn sound_effect_index(effect: &str) -> i64 {
+    match effect {
+        "pitch" => 0,
+        "pan" => 1,
+        _ => panic!("{}", effect),
+    }
+}
+
+fn terminated(out: &[Stmt]) -> bool {
+    out.last().is_some_and(Stmt::is_terminator)
+}
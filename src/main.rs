@@ -1,7 +1,50 @@
 fn main() {
-    let file = std::env::args().nth(1).unwrap();
-    let out_name = std::env::args().nth(2).unwrap();
-    let file = std::fs::File::open(file).unwrap();
+    let mut options = scratchc::Options::default();
+    let mut paths = vec![];
+    let mut debug_info = false;
+    let mut interpret = false;
+    for arg in std::env::args().skip(1) {
+        if arg == "-g" {
+            debug_info = true;
+        } else if arg == "--interpret" {
+            interpret = true;
+        } else if arg == "--trace" {
+            options.trace = true;
+        } else if arg == "--profile" {
+            options.profile = true;
+        } else if arg == "--guard" {
+            options.guard = Some(true);
+        } else if arg == "--no-guard" {
+            options.guard = Some(false);
+        } else if let Some(level) = arg.strip_prefix("--opt-level=") {
+            options.opt_level = level.parse().unwrap_or_else(|e| fail(e));
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            options.target = target.parse().unwrap_or_else(|e| fail(e));
+        } else if let Some(emit) = arg.strip_prefix("--emit=") {
+            options.emit = emit.parse().unwrap_or_else(|e| fail(e));
+        } else {
+            paths.push(arg);
+        }
+    }
 
-    scratchc::compile_native(file, &out_name);
+    if interpret {
+        let file = std::fs::File::open(&paths[0]).unwrap();
+        scratchc::interpret(file);
+        return;
+    }
+
+    if debug_info {
+        options.debug_info = Some(format!("{}.blocks", paths[1]).into());
+    }
+
+    let file = std::fs::File::open(&paths[0]).unwrap();
+
+    if let Err(e) = scratchc::compile_native(file, &paths[1], &options) {
+        fail(e);
+    }
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("scratchc: {}", message);
+    std::process::exit(1);
 }
@@ -1,10 +1,16 @@
-use std::collections::HashMap;
+use crate::costume::Costume;
+use crate::sound::Sound;
+use indexmap::IndexMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(serde::Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct ProjectInfo {
     pub targets: Vec<TargetInfo>,
     pub extensions: Vec<String>,
     pub meta: serde_json::Value,
+    #[serde(skip)]
+    pub assets: HashMap<String, Vec<u8>>,
 }
 
 impl ProjectInfo {
@@ -15,23 +21,176 @@ impl ProjectInfo {
         let mut file = archive.by_name("project.json")?;
         let mut source = Vec::new();
         std::io::copy(&mut file, &mut source)?;
-        Ok(serde_json::from_slice(&source)?)
+        drop(file);
+
+        let mut project: ProjectInfo = serde_json::from_slice(&source)?;
+        for target in &project.targets {
+            let costumes = target.costumes.iter().map(|c| c.file_name());
+            let sounds = target.sounds.iter().map(|s| s.file_name());
+            for name in costumes.chain(sounds) {
+                if let Ok(mut file) = archive.by_name(&name) {
+                    let mut data = Vec::new();
+                    std::io::copy(&mut file, &mut data)?;
+                    project.assets.insert(name, data);
+                }
+            }
+        }
+        Ok(project)
     }
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct TargetInfo {
     #[serde(rename = "isStage")]
     pub is_stage: bool,
     pub name: String,
-    pub variables: HashMap<String, (String, usize)>,
+    pub variables: IndexMap<String, (String, serde_json::Value)>,
     pub lists: serde_json::Value,
     pub broadcasts: serde_json::Value,
-    pub blocks: HashMap<String, BlockInfo>,
+    #[serde(deserialize_with = "deserialize_blocks")]
+    pub blocks: IndexMap<String, BlockInfo>,
     pub comments: serde_json::Value,
+    #[serde(rename = "currentCostume")]
+    pub current_costume: usize,
+    pub costumes: Vec<CostumeInfo>,
+    #[serde(default)]
+    pub sounds: Vec<SoundInfo>,
+    #[serde(default = "TargetInfo::default_volume")]
+    pub volume: f64,
+    #[serde(default = "TargetInfo::default_tempo")]
+    pub tempo: f64,
+    #[serde(rename = "layerOrder", default)]
+    pub layer_order: usize,
+    #[serde(flatten)]
+    pub sprite: SpriteInfo,
+}
+
+/// Loose variable and list reporters are saved as arrays instead of blocks.
+/// They never run, so they are dropped.
+fn deserialize_blocks<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<IndexMap<String, BlockInfo>, D::Error> {
+    let blocks: IndexMap<String, serde_json::Value> = serde::Deserialize::deserialize(d)?;
+    let mut out = IndexMap::new();
+    for (id, b) in blocks {
+        if b.is_object() {
+            out.insert(
+                id,
+                serde_json::from_value(b).map_err(serde::de::Error::custom)?,
+            );
+        }
+    }
+    Ok(out)
+}
+
+impl TargetInfo {
+    fn default_volume() -> f64 {
+        100.0
+    }
+
+    fn default_tempo() -> f64 {
+        60.0
+    }
+}
+
+/// Properties only sprites have. The stage gets the defaults.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SpriteInfo {
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    #[serde(default = "SpriteInfo::default_size")]
+    pub size: f64,
+    #[serde(default = "SpriteInfo::default_direction")]
+    pub direction: f64,
+    #[serde(default = "SpriteInfo::default_visible")]
+    pub visible: bool,
+    #[serde(
+        rename = "rotationStyle",
+        default = "SpriteInfo::default_rotation_style"
+    )]
+    pub rotation_style: String,
+}
+
+impl SpriteInfo {
+    fn default_size() -> f64 {
+        100.0
+    }
+
+    fn default_direction() -> f64 {
+        90.0
+    }
+
+    fn default_visible() -> bool {
+        true
+    }
+
+    fn default_rotation_style() -> String {
+        "all around".to_owned()
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct CostumeInfo {
+    pub name: String,
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+    pub md5ext: Option<String>,
+    #[serde(rename = "dataFormat")]
+    pub data_format: String,
+    #[serde(
+        rename = "bitmapResolution",
+        default = "CostumeInfo::default_resolution"
+    )]
+    pub bitmap_resolution: f64,
+    #[serde(rename = "rotationCenterX")]
+    pub rotation_center_x: f64,
+    #[serde(rename = "rotationCenterY")]
+    pub rotation_center_y: f64,
+}
+
+impl CostumeInfo {
+    fn default_resolution() -> f64 {
+        1.0
+    }
+
+    pub fn file_name(&self) -> String {
+        match &self.md5ext {
+            Some(name) => name.clone(),
+            None => format!("{}.{}", self.asset_id, self.data_format),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct SoundInfo {
+    pub name: String,
+    #[serde(rename = "assetId")]
+    pub asset_id: String,
+    pub md5ext: Option<String>,
+    #[serde(rename = "dataFormat")]
+    pub data_format: String,
+    #[serde(default)]
+    pub rate: u32,
+    #[serde(rename = "sampleCount", default)]
+    pub sample_count: usize,
+}
+
+impl SoundInfo {
+    pub fn file_name(&self) -> String {
+        match &self.md5ext {
+            Some(name) => name.clone(),
+            None => format!("{}.{}", self.asset_id, self.data_format),
+        }
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct BlockInfo {
     pub opcode: String,
     pub next: Option<String>,
@@ -51,40 +210,90 @@ struct NestedArguments(#[serde(with = "serde_with::json::nested")] Vec<String>);
 pub struct MutationInfo {
     argumentnames: Option<NestedArguments>,
     argumentids: Option<NestedArguments>,
-    proccode: Option<String>,
+    pub proccode: Option<String>,
+    // Saved as either a string or a bool, depending on the editor version.
+    warp: Option<serde_json::Value>,
+}
+
+impl MutationInfo {
+    fn warp(&self) -> bool {
+        match &self.warp {
+            Some(serde_json::Value::Bool(warp)) => *warp,
+            Some(serde_json::Value::String(warp)) => warp == "true",
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Target {
-    pub variables: HashMap<String, (String, usize)>,
+    pub name: String,
+    pub is_stage: bool,
+    pub layer_order: usize,
+    pub sprite: SpriteInfo,
+    pub costumes: Vec<Costume>,
+    pub current_costume: usize,
+    pub sounds: Vec<Sound>,
+    pub volume: f64,
+    pub tempo: f64,
+    /// Names and initial values, by ID.
+    pub variables: IndexMap<String, (String, serde_json::Value)>,
     pub scripts: Vec<Block>,
     pub procedures: Vec<Procedure>,
 }
 
 impl Target {
-    pub fn hydrate(i: TargetInfo) -> Self {
+    /// Build the `live` top level blocks into scripts and procedures.
+    pub fn hydrate(
+        i: TargetInfo,
+        live: &HashSet<String>,
+        assets: &HashMap<String, Vec<u8>>,
+    ) -> Self {
         let mut scripts = vec![];
         let mut procedures = vec![];
-        for b in i.blocks.values() {
+        for (id, b) in &i.blocks {
+            if !live.contains(id) {
+                continue;
+            }
             if b.opcode == "procedures_definition" {
-                let body = build_block(&i.blocks[b.next.as_ref().unwrap()], &i.blocks);
+                let body = build_block(b.next.as_ref().unwrap(), &i.blocks);
 
                 let prototype = &i.blocks[b.inputs["custom_block"][1].as_str().unwrap()];
-                let MutationInfo {
-                    argumentnames,
-                    proccode,
-                    ..
-                } = prototype.mutation.as_ref().unwrap();
+                let mutation = prototype.mutation.as_ref().unwrap();
+                let proccode = mutation.proccode.clone().unwrap();
+                let kinds = ArgumentKind::parse(&proccode);
+                let names = &mutation.argumentnames.as_ref().unwrap().0;
                 procedures.push(Procedure {
-                    id: proccode.clone().unwrap(),
-                    arguments: argumentnames.as_ref().unwrap().0.clone(),
+                    id: proccode,
+                    definition: id.clone(),
+                    arguments: names.iter().cloned().zip(kinds).collect(),
+                    warp: mutation.warp(),
                     body,
                 });
-            } else if b.top_level {
-                scripts.push(build_block(b, &i.blocks));
+            } else {
+                scripts.push(build_block(id, &i.blocks));
             }
         }
+        let costumes = i
+            .costumes
+            .iter()
+            .map(|c| Costume::rasterize(c, assets.get(&c.file_name()).map(|d| &d[..])))
+            .collect();
+        let sounds = i
+            .sounds
+            .iter()
+            .map(|s| Sound::decode(s, assets.get(&s.file_name()).map(|d| &d[..])))
+            .collect();
         Target {
+            name: i.name,
+            is_stage: i.is_stage,
+            layer_order: i.layer_order,
+            sprite: i.sprite,
+            costumes,
+            current_costume: i.current_costume,
+            sounds,
+            volume: i.volume,
+            tempo: i.tempo,
             variables: i.variables,
             scripts,
             procedures,
@@ -95,7 +304,11 @@ impl Target {
 #[derive(Debug)]
 pub struct Procedure {
     pub id: String,
-    pub arguments: Vec<String>,
+    /// The ID of the `define` block.
+    pub definition: String,
+    pub arguments: Vec<(String, ArgumentKind)>,
+    /// Run without screen refresh: loops inside don't yield.
+    pub warp: bool,
     pub body: Block,
 }
 
@@ -108,20 +321,41 @@ pub enum Value {
 }
 
 impl Value {
-    pub fn hydrate(v: &serde_json::Value, blocks: &HashMap<String, BlockInfo>) -> Value {
+    pub fn hydrate(v: &serde_json::Value, blocks: &IndexMap<String, BlockInfo>) -> Value {
         if v[1].is_array() {
             let kind = v[1][0].as_u64().unwrap();
             match kind {
-                4 | 5 | 6 | 7 => Value::Number(v[1][1].as_str().unwrap().parse().unwrap()),
+                // Number fields can be left empty, or hold whatever was typed
+                // into them, which is kept as text.
+                4..=8 => {
+                    let s = v[1][1].as_str().unwrap();
+                    match s.parse() {
+                        Ok(n) => Value::Number(n),
+                        Err(_) => Value::String(s.to_owned()),
+                    }
+                }
                 10 => Value::String(v[1][1].as_str().unwrap().to_owned()),
                 12 => Value::Load(v[1][2].as_str().unwrap().to_owned()),
                 _ => panic!("{:#?}", v),
             }
         } else {
-            Value::Expression(Box::new(build_block_expr(
-                &blocks[v[1].as_str().unwrap()],
-                blocks,
-            )))
+            let b = &blocks[v[1].as_str().unwrap()];
+            // Menus like the costume picker are shadow blocks with a single
+            // field and no inputs, standing in for a string.
+            if b.shadow && b.inputs.is_empty() && b.fields.len() == 1 {
+                let field = b.fields.values().next().unwrap();
+                return Value::String(field[0].as_str().unwrap().to_owned());
+            }
+            Value::Expression(Box::new(build_block_expr(b, blocks)))
+        }
+    }
+
+    /// Like `hydrate`, for inputs that are left out when they're empty, like
+    /// boolean slots.
+    fn boolean(v: Option<&serde_json::Value>, blocks: &IndexMap<String, BlockInfo>) -> Value {
+        match v {
+            Some(v) => Value::hydrate(v, blocks),
+            None => Value::Number(0.0),
         }
     }
 }
@@ -141,8 +375,85 @@ pub enum BlockOp {
     },
     ControlStopAll,
     ControlStopScript,
-    LooksSay(Value),
+    LooksSay {
+        message: Value,
+        think: bool,
+    },
+    LooksSayForSecs {
+        message: Value,
+        secs: Value,
+        think: bool,
+    },
+    LooksSwitchCostumeTo(Value),
+    LooksNextCostume,
+    LooksSwitchBackdropTo {
+        backdrop: Value,
+        wait: bool,
+    },
+    LooksNextBackdrop,
+    LooksChangeSizeBy(Value),
+    LooksSetSizeTo(Value),
+    LooksChangeEffectBy {
+        effect: String,
+        value: Value,
+    },
+    LooksSetEffectTo {
+        effect: String,
+        value: Value,
+    },
+    LooksClearGraphicEffects,
+    LooksShow,
+    LooksHide,
+    LooksGoToFrontBack {
+        front: bool,
+    },
+    LooksGoForwardBackwardLayers {
+        forward: bool,
+        layers: Value,
+    },
+    SoundPlay {
+        sound: Value,
+        wait: bool,
+    },
+    SoundStopAllSounds,
+    SoundChangeVolumeBy(Value),
+    SoundSetVolumeTo(Value),
+    SoundChangeEffectBy {
+        effect: String,
+        value: Value,
+    },
+    SoundSetEffectTo {
+        effect: String,
+        value: Value,
+    },
+    SoundClearEffects,
+    MusicPlayNoteForBeats {
+        note: Value,
+        beats: Value,
+    },
+    MusicPlayDrumForBeats {
+        drum: Value,
+        beats: Value,
+    },
+    MusicRestForBeats(Value),
+    MusicSetTempo(Value),
+    MusicChangeTempo(Value),
+    MusicSetInstrument(Value),
+    MotionMoveSteps(Value),
+    MotionTurnRight(Value),
+    MotionTurnLeft(Value),
+    MotionPointInDirection(Value),
+    MotionGoToXY {
+        x: Value,
+        y: Value,
+    },
+    MotionChangeXBy(Value),
+    MotionSetX(Value),
+    MotionChangeYBy(Value),
+    MotionSetY(Value),
     EventWhenFlagClicked,
+    EventWhenKeyPressed(String),
+    EventWhenBackdropSwitchesTo(String),
     DataSetVariableTo {
         id: String,
         value: Value,
@@ -157,41 +468,70 @@ pub enum BlockOp {
     },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgumentKind {
+    StringNumber,
+    Boolean,
+}
+
+impl ArgumentKind {
+    /// The kinds of a procedure's arguments, from the `%s` and `%b` slots in
+    /// its proccode.
+    fn parse(proccode: &str) -> Vec<ArgumentKind> {
+        let mut kinds = vec![];
+        let mut chars = proccode.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' {
+                match chars.next() {
+                    Some('s') | Some('n') => kinds.push(ArgumentKind::StringNumber),
+                    Some('b') => kinds.push(ArgumentKind::Boolean),
+                    _ => {}
+                }
+            }
+        }
+        kinds
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Block {
+    pub id: String,
+    /// What the project calls the block, like `looks_say`.
+    pub opcode: String,
     pub op: BlockOp,
     pub next: Option<Box<Block>>,
 }
 
-fn build_block(b: &BlockInfo, blocks: &HashMap<String, BlockInfo>) -> Block {
+fn build_block(id: &str, blocks: &IndexMap<String, BlockInfo>) -> Block {
+    let b = &blocks[id];
     let op = match b.opcode.as_str() {
         "control_repeat" => BlockOp::ControlRepeat {
             times: Value::hydrate(&b.inputs["TIMES"], blocks),
             body: Box::new(build_block(
-                &blocks[b.inputs["SUBSTACK"][1].as_str().unwrap()],
+                b.inputs["SUBSTACK"][1].as_str().unwrap(),
                 blocks,
             )),
         },
         "control_forever" => BlockOp::ControlForever(Box::new(build_block(
-            &blocks[b.inputs["SUBSTACK"][1].as_str().unwrap()],
+            b.inputs["SUBSTACK"][1].as_str().unwrap(),
             blocks,
         ))),
         "control_wait" => BlockOp::ControlWait(Value::hydrate(&b.inputs["DURATION"], blocks)),
         "control_if_else" => BlockOp::ControlIfElse {
-            condition: Value::hydrate(&b.inputs["CONDITION"], blocks),
+            condition: Value::boolean(b.inputs.get("CONDITION"), blocks),
             consequent: Box::new(build_block(
-                &blocks[b.inputs["SUBSTACK"][1].as_str().unwrap()],
+                b.inputs["SUBSTACK"][1].as_str().unwrap(),
                 blocks,
             )),
             alternative: Some(Box::new(build_block(
-                &blocks[b.inputs["SUBSTACK2"][1].as_str().unwrap()],
+                b.inputs["SUBSTACK2"][1].as_str().unwrap(),
                 blocks,
             ))),
         },
         "control_if" => BlockOp::ControlIfElse {
-            condition: Value::hydrate(&b.inputs["CONDITION"], blocks),
+            condition: Value::boolean(b.inputs.get("CONDITION"), blocks),
             consequent: Box::new(build_block(
-                &blocks[b.inputs["SUBSTACK"][1].as_str().unwrap()],
+                b.inputs["SUBSTACK"][1].as_str().unwrap(),
                 blocks,
             )),
             alternative: None,
@@ -201,21 +541,115 @@ fn build_block(b: &BlockInfo, blocks: &HashMap<String, BlockInfo>) -> Block {
             "this script" => BlockOp::ControlStopScript,
             _ => unreachable!("{:?}", b),
         },
-        "looks_say" => BlockOp::LooksSay(Value::hydrate(&b.inputs["MESSAGE"], blocks)),
-        "looks_sayforsecs" => {
-            return Block {
-                op: BlockOp::LooksSay(Value::hydrate(&b.inputs["MESSAGE"], blocks)),
-                next: Some(Box::new(Block {
-                    op: BlockOp::ControlWait(Value::hydrate(&b.inputs["SECS"], blocks)),
-                    next: if let Some(id) = &b.next {
-                        Some(Box::new(build_block(&blocks[id], blocks)))
-                    } else {
-                        None
-                    },
-                })),
-            };
+        "looks_say" | "looks_think" => BlockOp::LooksSay {
+            message: Value::hydrate(&b.inputs["MESSAGE"], blocks),
+            think: b.opcode == "looks_think",
+        },
+        "looks_sayforsecs" | "looks_thinkforsecs" => BlockOp::LooksSayForSecs {
+            message: Value::hydrate(&b.inputs["MESSAGE"], blocks),
+            secs: Value::hydrate(&b.inputs["SECS"], blocks),
+            think: b.opcode == "looks_thinkforsecs",
+        },
+        "looks_switchcostumeto" => {
+            BlockOp::LooksSwitchCostumeTo(Value::hydrate(&b.inputs["COSTUME"], blocks))
         }
+        "looks_nextcostume" => BlockOp::LooksNextCostume,
+        "looks_switchbackdropto" => BlockOp::LooksSwitchBackdropTo {
+            backdrop: Value::hydrate(&b.inputs["BACKDROP"], blocks),
+            wait: false,
+        },
+        "looks_switchbackdroptoandwait" => BlockOp::LooksSwitchBackdropTo {
+            backdrop: Value::hydrate(&b.inputs["BACKDROP"], blocks),
+            wait: true,
+        },
+        "looks_nextbackdrop" => BlockOp::LooksNextBackdrop,
+        "looks_changesizeby" => {
+            BlockOp::LooksChangeSizeBy(Value::hydrate(&b.inputs["CHANGE"], blocks))
+        }
+        "looks_setsizeto" => BlockOp::LooksSetSizeTo(Value::hydrate(&b.inputs["SIZE"], blocks)),
+        "looks_changeeffectby" => BlockOp::LooksChangeEffectBy {
+            effect: b.fields["EFFECT"][0].as_str().unwrap().to_lowercase(),
+            value: Value::hydrate(&b.inputs["CHANGE"], blocks),
+        },
+        "looks_seteffectto" => BlockOp::LooksSetEffectTo {
+            effect: b.fields["EFFECT"][0].as_str().unwrap().to_lowercase(),
+            value: Value::hydrate(&b.inputs["VALUE"], blocks),
+        },
+        "looks_cleargraphiceffects" => BlockOp::LooksClearGraphicEffects,
+        "looks_show" => BlockOp::LooksShow,
+        "looks_hide" => BlockOp::LooksHide,
+        "looks_gotofrontback" => BlockOp::LooksGoToFrontBack {
+            front: b.fields["FRONT_BACK"][0].as_str().unwrap() == "front",
+        },
+        "looks_goforwardbackwardlayers" => BlockOp::LooksGoForwardBackwardLayers {
+            forward: b.fields["FORWARD_BACKWARD"][0].as_str().unwrap() == "forward",
+            layers: Value::hydrate(&b.inputs["NUM"], blocks),
+        },
+        "sound_play" => BlockOp::SoundPlay {
+            sound: Value::hydrate(&b.inputs["SOUND_MENU"], blocks),
+            wait: false,
+        },
+        "sound_playuntildone" => BlockOp::SoundPlay {
+            sound: Value::hydrate(&b.inputs["SOUND_MENU"], blocks),
+            wait: true,
+        },
+        "sound_stopallsounds" => BlockOp::SoundStopAllSounds,
+        "sound_changevolumeby" => {
+            BlockOp::SoundChangeVolumeBy(Value::hydrate(&b.inputs["VOLUME"], blocks))
+        }
+        "sound_setvolumeto" => {
+            BlockOp::SoundSetVolumeTo(Value::hydrate(&b.inputs["VOLUME"], blocks))
+        }
+        "sound_changeeffectby" => BlockOp::SoundChangeEffectBy {
+            effect: b.fields["EFFECT"][0].as_str().unwrap().to_lowercase(),
+            value: Value::hydrate(&b.inputs["VALUE"], blocks),
+        },
+        "sound_seteffectto" => BlockOp::SoundSetEffectTo {
+            effect: b.fields["EFFECT"][0].as_str().unwrap().to_lowercase(),
+            value: Value::hydrate(&b.inputs["VALUE"], blocks),
+        },
+        "sound_cleareffects" => BlockOp::SoundClearEffects,
+        "music_playNoteForBeats" => BlockOp::MusicPlayNoteForBeats {
+            note: Value::hydrate(&b.inputs["NOTE"], blocks),
+            beats: Value::hydrate(&b.inputs["BEATS"], blocks),
+        },
+        "music_playDrumForBeats" => BlockOp::MusicPlayDrumForBeats {
+            drum: Value::hydrate(&b.inputs["DRUM"], blocks),
+            beats: Value::hydrate(&b.inputs["BEATS"], blocks),
+        },
+        "music_restForBeats" => {
+            BlockOp::MusicRestForBeats(Value::hydrate(&b.inputs["BEATS"], blocks))
+        }
+        "music_setTempo" => BlockOp::MusicSetTempo(Value::hydrate(&b.inputs["TEMPO"], blocks)),
+        "music_changeTempo" => {
+            BlockOp::MusicChangeTempo(Value::hydrate(&b.inputs["TEMPO"], blocks))
+        }
+        "music_setInstrument" => {
+            BlockOp::MusicSetInstrument(Value::hydrate(&b.inputs["INSTRUMENT"], blocks))
+        }
+        "motion_movesteps" => BlockOp::MotionMoveSteps(Value::hydrate(&b.inputs["STEPS"], blocks)),
+        "motion_turnright" => {
+            BlockOp::MotionTurnRight(Value::hydrate(&b.inputs["DEGREES"], blocks))
+        }
+        "motion_turnleft" => BlockOp::MotionTurnLeft(Value::hydrate(&b.inputs["DEGREES"], blocks)),
+        "motion_pointindirection" => {
+            BlockOp::MotionPointInDirection(Value::hydrate(&b.inputs["DIRECTION"], blocks))
+        }
+        "motion_gotoxy" => BlockOp::MotionGoToXY {
+            x: Value::hydrate(&b.inputs["X"], blocks),
+            y: Value::hydrate(&b.inputs["Y"], blocks),
+        },
+        "motion_changexby" => BlockOp::MotionChangeXBy(Value::hydrate(&b.inputs["DX"], blocks)),
+        "motion_setx" => BlockOp::MotionSetX(Value::hydrate(&b.inputs["X"], blocks)),
+        "motion_changeyby" => BlockOp::MotionChangeYBy(Value::hydrate(&b.inputs["DY"], blocks)),
+        "motion_sety" => BlockOp::MotionSetY(Value::hydrate(&b.inputs["Y"], blocks)),
         "event_whenflagclicked" => BlockOp::EventWhenFlagClicked,
+        "event_whenkeypressed" => {
+            BlockOp::EventWhenKeyPressed(b.fields["KEY_OPTION"][0].as_str().unwrap().to_owned())
+        }
+        "event_whenbackdropswitchesto" => BlockOp::EventWhenBackdropSwitchesTo(
+            b.fields["BACKDROP"][0].as_str().unwrap().to_owned(),
+        ),
         "data_setvariableto" => BlockOp::DataSetVariableTo {
             id: b.fields["VARIABLE"][1].as_str().unwrap().to_owned(),
             value: Value::hydrate(&b.inputs["VALUE"], blocks),
@@ -235,18 +669,16 @@ fn build_block(b: &BlockInfo, blocks: &HashMap<String, BlockInfo>) -> Block {
                 .unwrap()
                 .0
                 .iter()
-                .map(|id| Value::hydrate(&b.inputs[id], blocks))
+                .map(|id| Value::boolean(b.inputs.get(id), blocks))
                 .collect(),
         },
         _ => panic!("{:#?}", b),
     };
     Block {
+        id: id.to_owned(),
+        opcode: b.opcode.clone(),
         op,
-        next: if let Some(id) = &b.next {
-            Some(Box::new(build_block(&blocks[id], blocks)))
-        } else {
-            None
-        },
+        next: b.next.as_ref().map(|id| Box::new(build_block(id, blocks))),
     }
 }
 
@@ -257,9 +689,19 @@ pub enum BlockExpression {
     OperatorAdd { left: Value, right: Value },
     OperatorSubtract { left: Value, right: Value },
     ArgumentReporterStringNumber { name: String },
+    ArgumentReporterBoolean { name: String },
+    MotionXPosition,
+    MotionYPosition,
+    MotionDirection,
+    LooksCostumeNumberName { name: bool },
+    LooksBackdropNumberName { name: bool },
+    LooksSize,
+    SoundVolume,
+    MusicGetTempo,
+    SensingKeyPressed { key: Value },
 }
 
-fn build_block_expr(b: &BlockInfo, blocks: &HashMap<String, BlockInfo>) -> BlockExpression {
+fn build_block_expr(b: &BlockInfo, blocks: &IndexMap<String, BlockInfo>) -> BlockExpression {
     match b.opcode.as_str() {
         "operator_equals" => BlockExpression::OperatorEquals {
             left: Value::hydrate(&b.inputs["OPERAND1"], blocks),
@@ -280,6 +722,24 @@ fn build_block_expr(b: &BlockInfo, blocks: &HashMap<String, BlockInfo>) -> Block
         "argument_reporter_string_number" => BlockExpression::ArgumentReporterStringNumber {
             name: b.fields["VALUE"][0].as_str().unwrap().to_owned(),
         },
+        "argument_reporter_boolean" => BlockExpression::ArgumentReporterBoolean {
+            name: b.fields["VALUE"][0].as_str().unwrap().to_owned(),
+        },
+        "motion_xposition" => BlockExpression::MotionXPosition,
+        "motion_yposition" => BlockExpression::MotionYPosition,
+        "motion_direction" => BlockExpression::MotionDirection,
+        "looks_costumenumbername" => BlockExpression::LooksCostumeNumberName {
+            name: b.fields["NUMBER_NAME"][0].as_str().unwrap() == "name",
+        },
+        "looks_backdropnumbername" => BlockExpression::LooksBackdropNumberName {
+            name: b.fields["NUMBER_NAME"][0].as_str().unwrap() == "name",
+        },
+        "looks_size" => BlockExpression::LooksSize,
+        "sound_volume" => BlockExpression::SoundVolume,
+        "music_getTempo" => BlockExpression::MusicGetTempo,
+        "sensing_keypressed" => BlockExpression::SensingKeyPressed {
+            key: Value::hydrate(&b.inputs["KEY_OPTION"], blocks),
+        },
         _ => panic!("{:#?}", b),
     }
 }
@@ -0,0 +1,161 @@
+use crate::scratch::SoundInfo;
+
+#[derive(Debug)]
+pub struct Sound {
+    pub name: String,
+    pub rate: u32,
+    /// Mono 16-bit samples.
+    pub samples: Vec<i16>,
+    /// Length in samples, which is kept for sounds we couldn't decode so
+    /// that they still take the right amount of time.
+    pub sample_count: usize,
+}
+
+impl Sound {
+    /// Decode a sound asset. Formats we can't decode (mp3) or missing assets
+    /// produce a silent sound with no samples.
+    pub fn decode(info: &SoundInfo, data: Option<&[u8]>) -> Sound {
+        let (rate, samples) = data
+            .and_then(|data| match info.data_format.as_str() {
+                "wav" => decode_wav(data),
+                _ => None,
+            })
+            .unwrap_or((info.rate, vec![]));
+        Sound {
+            name: info.name.clone(),
+            rate,
+            sample_count: if samples.is_empty() {
+                info.sample_count
+            } else {
+                samples.len()
+            },
+            samples,
+        }
+    }
+}
+
+struct Format {
+    tag: u16,
+    channels: usize,
+    rate: u32,
+    block_align: usize,
+    bits: u16,
+}
+
+fn u16_at(data: &[u8], i: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]))
+}
+
+fn u32_at(data: &[u8], i: usize) -> Option<u32> {
+    Some(u32::from_le_bytes([
+        *data.get(i)?,
+        *data.get(i + 1)?,
+        *data.get(i + 2)?,
+        *data.get(i + 3)?,
+    ]))
+}
+
+fn decode_wav(data: &[u8]) -> Option<(u32, Vec<i16>)> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut format = None;
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let id = &data[i..i + 4];
+        let len = u32_at(data, i + 4)? as usize;
+        let chunk = data.get(i + 8..(i + 8 + len).min(data.len()))?;
+        match id {
+            b"fmt " => {
+                format = Some(Format {
+                    tag: u16_at(chunk, 0)?,
+                    channels: u16_at(chunk, 2)? as usize,
+                    rate: u32_at(chunk, 4)?,
+                    block_align: u16_at(chunk, 12)? as usize,
+                    bits: u16_at(chunk, 14)?,
+                })
+            }
+            b"data" => {
+                let format = format.as_ref()?;
+                let samples = match (format.tag, format.bits) {
+                    (1, 8) => downmix(format, chunk.iter().map(|&s| (s as i16 - 128) << 8)),
+                    (1, 16) => downmix(
+                        format,
+                        chunk
+                            .chunks_exact(2)
+                            .map(|s| i16::from_le_bytes([s[0], s[1]])),
+                    ),
+                    (3, 32) => downmix(
+                        format,
+                        chunk.chunks_exact(4).map(|s| {
+                            let s = f32::from_le_bytes([s[0], s[1], s[2], s[3]]);
+                            (s.clamp(-1.0, 1.0) * 32767.0) as i16
+                        }),
+                    ),
+                    (0x11, 4) if format.channels == 1 => decode_adpcm(chunk, format.block_align),
+                    _ => return None,
+                };
+                return Some((format.rate, samples));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        i += 8 + len + len % 2;
+    }
+    None
+}
+
+fn downmix(format: &Format, samples: impl Iterator<Item = i16>) -> Vec<i16> {
+    let samples: Vec<i16> = samples.collect();
+    samples
+        .chunks_exact(format.channels.max(1))
+        .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / frame.len() as i32) as i16)
+        .collect()
+}
+
+const INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// IMA ADPCM, which is what Scratch uses for compressed recordings.
+fn decode_adpcm(data: &[u8], block_align: usize) -> Vec<i16> {
+    let mut samples = vec![];
+    for block in data.chunks(block_align.max(5)) {
+        if block.len() < 4 {
+            break;
+        }
+        let mut sample = i16::from_le_bytes([block[0], block[1]]) as i32;
+        let mut index = (block[2] as i32).min(88);
+        samples.push(sample as i16);
+        for &byte in &block[4..] {
+            for &code in &[byte & 0xf, byte >> 4] {
+                let step = STEP_TABLE[index as usize];
+                let mut delta = step >> 3;
+                if code & 4 != 0 {
+                    delta += step;
+                }
+                if code & 2 != 0 {
+                    delta += step >> 1;
+                }
+                if code & 1 != 0 {
+                    delta += step >> 2;
+                }
+                if code & 8 != 0 {
+                    sample -= delta;
+                } else {
+                    sample += delta;
+                }
+                sample = sample.clamp(-32768, 32767);
+                index = (index + INDEX_TABLE[code as usize]).clamp(0, 88);
+                samples.push(sample as i16);
+            }
+        }
+    }
+    samples
+}
@@ -0,0 +1,280 @@
+//! A scratchblocks listing of a target's scripts and custom blocks, with one
+//! line per block, for debug info to point into.
+
+use crate::scratch::{self, Block, BlockExpression, BlockOp, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+pub struct Source {
+    pub text: String,
+    /// The line every block is on, by ID, counting from 1.
+    pub lines: HashMap<String, u32>,
+}
+
+/// The names of all the project's variables, by ID.
+pub fn names(targets: &[scratch::Target]) -> HashMap<String, String> {
+    targets
+        .iter()
+        .flat_map(|t| t.variables.iter())
+        .map(|(id, (name, _))| (id.clone(), name.clone()))
+        .collect()
+}
+
+impl Source {
+    /// `names` are the names of all the project's variables, by ID.
+    pub fn new(target: &scratch::Target, names: &HashMap<String, String>) -> Source {
+        let mut l = Lister {
+            names,
+            source: Source {
+                text: String::new(),
+                lines: HashMap::new(),
+            },
+            line: 0,
+        };
+        for script in &target.scripts {
+            l.stack(script, 0);
+            l.line(None, 0, String::new());
+        }
+        for proc in &target.procedures {
+            let params = proc
+                .arguments
+                .iter()
+                .map(|(name, kind)| match kind {
+                    scratch::ArgumentKind::StringNumber => format!("({})", name),
+                    scratch::ArgumentKind::Boolean => format!("<{}>", name),
+                })
+                .collect();
+            let define = format!("define {}", fill(&proc.id, params));
+            l.line(Some(&proc.definition), 0, define);
+            l.stack(&proc.body, 0);
+            l.line(None, 0, String::new());
+        }
+        l.source
+    }
+}
+
+struct Lister<'a> {
+    names: &'a HashMap<String, String>,
+    source: Source,
+    line: u32,
+}
+
+impl Lister<'_> {
+    fn line(&mut self, id: Option<&str>, indent: usize, text: String) {
+        self.line += 1;
+        if let Some(id) = id {
+            self.source.lines.insert(id.to_owned(), self.line);
+        }
+        writeln!(self.source.text, "{:2$}{}", "", text, indent * 4).unwrap();
+    }
+
+    fn stack(&mut self, block: &Block, indent: usize) {
+        let mut block = Some(block);
+        while let Some(b) = block {
+            self.block(b, indent);
+            block = b.next.as_deref();
+        }
+    }
+
+    /// A C block's opening line, body and `end`.
+    fn c(&mut self, b: &Block, indent: usize, text: String, body: &Block) {
+        self.line(Some(&b.id), indent, text);
+        self.stack(body, indent + 1);
+        self.line(None, indent, "end".to_owned());
+    }
+
+    fn block(&mut self, b: &Block, indent: usize) {
+        let v = |value| self.value(value);
+        let text = match &b.op {
+            BlockOp::ControlRepeat { times, body } => {
+                return self.c(b, indent, format!("repeat {}", v(times)), body)
+            }
+            BlockOp::ControlForever(body) => return self.c(b, indent, "forever".to_owned(), body),
+            BlockOp::ControlWait(delay) => format!("wait {} seconds", v(delay)),
+            BlockOp::ControlIfElse {
+                condition,
+                consequent,
+                alternative: None,
+            } => return self.c(b, indent, format!("if {} then", v(condition)), consequent),
+            BlockOp::ControlIfElse {
+                condition,
+                consequent,
+                alternative: Some(alternative),
+            } => {
+                self.line(Some(&b.id), indent, format!("if {} then", v(condition)));
+                self.stack(consequent, indent + 1);
+                self.line(None, indent, "else".to_owned());
+                self.stack(alternative, indent + 1);
+                return self.line(None, indent, "end".to_owned());
+            }
+            BlockOp::ControlStopAll => "stop [all v]".to_owned(),
+            BlockOp::ControlStopScript => "stop [this script v]".to_owned(),
+            BlockOp::LooksSay { message, think } => {
+                format!("{} {}", say(*think), v(message))
+            }
+            BlockOp::LooksSayForSecs {
+                message,
+                secs,
+                think,
+            } => format!("{} {} for {} seconds", say(*think), v(message), v(secs)),
+            BlockOp::LooksSwitchCostumeTo(costume) => format!("switch costume to {}", v(costume)),
+            BlockOp::LooksNextCostume => "next costume".to_owned(),
+            BlockOp::LooksSwitchBackdropTo { backdrop, wait } => format!(
+                "switch backdrop to {}{}",
+                v(backdrop),
+                if *wait { " and wait" } else { "" }
+            ),
+            BlockOp::LooksNextBackdrop => "next backdrop".to_owned(),
+            BlockOp::LooksChangeSizeBy(change) => format!("change size by {}", v(change)),
+            BlockOp::LooksSetSizeTo(size) => format!("set size to {} %", v(size)),
+            BlockOp::LooksChangeEffectBy { effect, value } => format!(
+                "change [{} v] effect by {}",
+                effect.to_lowercase(),
+                v(value)
+            ),
+            BlockOp::LooksSetEffectTo { effect, value } => {
+                format!("set [{} v] effect to {}", effect.to_lowercase(), v(value))
+            }
+            BlockOp::LooksClearGraphicEffects => "clear graphic effects".to_owned(),
+            BlockOp::LooksShow => "show".to_owned(),
+            BlockOp::LooksHide => "hide".to_owned(),
+            BlockOp::LooksGoToFrontBack { front } => {
+                format!("go to [{} v] layer", if *front { "front" } else { "back" })
+            }
+            BlockOp::LooksGoForwardBackwardLayers { forward, layers } => format!(
+                "go [{} v] {} layers",
+                if *forward { "forward" } else { "backward" },
+                v(layers)
+            ),
+            BlockOp::SoundPlay { sound, wait: false } => format!("start sound {}", v(sound)),
+            BlockOp::SoundPlay { sound, wait: true } => {
+                format!("play sound {} until done", v(sound))
+            }
+            BlockOp::SoundStopAllSounds => "stop all sounds".to_owned(),
+            BlockOp::SoundChangeVolumeBy(value) => format!("change volume by {}", v(value)),
+            BlockOp::SoundSetVolumeTo(value) => format!("set volume to {} %", v(value)),
+            BlockOp::SoundChangeEffectBy { effect, value } => format!(
+                "change [{} v] effect by {}",
+                effect.to_lowercase(),
+                v(value)
+            ),
+            BlockOp::SoundSetEffectTo { effect, value } => {
+                format!("set [{} v] effect to {}", effect.to_lowercase(), v(value))
+            }
+            BlockOp::SoundClearEffects => "clear sound effects".to_owned(),
+            BlockOp::MusicPlayNoteForBeats { note, beats } => {
+                format!("play note {} for {} beats", v(note), v(beats))
+            }
+            BlockOp::MusicPlayDrumForBeats { drum, beats } => {
+                format!("play drum {} for {} beats", v(drum), v(beats))
+            }
+            BlockOp::MusicRestForBeats(beats) => format!("rest for {} beats", v(beats)),
+            BlockOp::MusicSetTempo(tempo) => format!("set tempo to {}", v(tempo)),
+            BlockOp::MusicChangeTempo(tempo) => format!("change tempo by {}", v(tempo)),
+            BlockOp::MusicSetInstrument(instrument) => {
+                format!("set instrument to {}", v(instrument))
+            }
+            BlockOp::MotionMoveSteps(steps) => format!("move {} steps", v(steps)),
+            BlockOp::MotionTurnRight(degrees) => format!("turn right {} degrees", v(degrees)),
+            BlockOp::MotionTurnLeft(degrees) => format!("turn left {} degrees", v(degrees)),
+            BlockOp::MotionPointInDirection(direction) => {
+                format!("point in direction {}", v(direction))
+            }
+            BlockOp::MotionGoToXY { x, y } => format!("go to x: {} y: {}", v(x), v(y)),
+            BlockOp::MotionChangeXBy(x) => format!("change x by {}", v(x)),
+            BlockOp::MotionSetX(x) => format!("set x to {}", v(x)),
+            BlockOp::MotionChangeYBy(y) => format!("change y by {}", v(y)),
+            BlockOp::MotionSetY(y) => format!("set y to {}", v(y)),
+            BlockOp::EventWhenFlagClicked => "when flag clicked".to_owned(),
+            BlockOp::EventWhenKeyPressed(key) => format!("when [{} v] key pressed", key),
+            BlockOp::EventWhenBackdropSwitchesTo(backdrop) => {
+                format!("when backdrop switches to [{} v]", backdrop)
+            }
+            BlockOp::DataSetVariableTo { id, value } => {
+                format!("set [{} v] to {}", self.name(id), v(value))
+            }
+            BlockOp::DataChangeVariableBy { id, value } => {
+                format!("change [{} v] by {}", self.name(id), v(value))
+            }
+            BlockOp::ProceduresCall { proc, args } => fill(proc, args.iter().map(v).collect()),
+        };
+        self.line(Some(&b.id), indent, text);
+    }
+
+    fn name<'b>(&'b self, id: &'b str) -> &'b str {
+        self.names.get(id).map_or(id, |name| name.as_str())
+    }
+
+    fn value(&self, value: &Value) -> String {
+        match value {
+            Value::Number(n) => format!("({})", n),
+            Value::String(s) => format!("[{}]", s.replace(']', "\\]")),
+            Value::Load(id) => format!("({})", self.name(id)),
+            Value::Expression(e) => self.expr(e),
+        }
+    }
+
+    fn expr(&self, e: &BlockExpression) -> String {
+        let v = |value| self.value(value);
+        match e {
+            BlockExpression::OperatorEquals { left, right } => {
+                format!("<{} = {}>", v(left), v(right))
+            }
+            BlockExpression::OperatorGT { left, right } => format!("<{} > {}>", v(left), v(right)),
+            BlockExpression::OperatorAdd { left, right } => {
+                format!("({} + {})", v(left), v(right))
+            }
+            BlockExpression::OperatorSubtract { left, right } => {
+                format!("({} - {})", v(left), v(right))
+            }
+            BlockExpression::ArgumentReporterStringNumber { name } => format!("({})", name),
+            BlockExpression::ArgumentReporterBoolean { name } => format!("<{}>", name),
+            BlockExpression::MotionXPosition => "(x position)".to_owned(),
+            BlockExpression::MotionYPosition => "(y position)".to_owned(),
+            BlockExpression::MotionDirection => "(direction)".to_owned(),
+            BlockExpression::LooksCostumeNumberName { name } => {
+                format!("(costume [{} v])", number_name(*name))
+            }
+            BlockExpression::LooksBackdropNumberName { name } => {
+                format!("(backdrop [{} v])", number_name(*name))
+            }
+            BlockExpression::LooksSize => "(size)".to_owned(),
+            BlockExpression::SoundVolume => "(volume)".to_owned(),
+            BlockExpression::MusicGetTempo => "(tempo)".to_owned(),
+            BlockExpression::SensingKeyPressed { key } => format!("<key {} pressed?>", v(key)),
+        }
+    }
+}
+
+fn say(think: bool) -> &'static str {
+    if think {
+        "think"
+    } else {
+        "say"
+    }
+}
+
+fn number_name(name: bool) -> &'static str {
+    if name {
+        "name"
+    } else {
+        "number"
+    }
+}
+
+/// A proccode with its `%s`, `%n` and `%b` slots filled in.
+fn fill(proccode: &str, args: Vec<String>) -> String {
+    let mut out = String::new();
+    let mut args = args.into_iter();
+    let mut chars = proccode.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('%', Some('s' | 'n' | 'b')) => {
+                chars.next();
+                out.push_str(&args.next().unwrap_or_default());
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,624 @@
+//! A backend that compiles the IR to a standalone WebAssembly module for
+//! WASI.
+//!
+//! There are no threads to run scripts on, so every function is a state
+//! machine that can return in the middle and carry on where it left off
+//! when it's called again. Its state, parameters and loop counters live in
+//! a frame in linear memory instead of in locals, and a custom block's frame
+//! comes right after its caller's. Functions return 0 when they finish, 1
+//! when they give up their turn and 2 when they're waiting for time to pass.
+//! The runtime in `support.wat` takes care of the rest.
+
+use crate::ir::{BinaryOp, Call, CompareOp, Expr, Function, Hat, Program, Stmt, Type};
+use crate::{scratch, source};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const RUNTIME: &str = include_str!("../support.wat");
+
+/// Memory below this belongs to the runtime.
+const DATA_START: u32 = 9216;
+
+/// The runtime functions `support.wat` has, out of everything the native
+/// runtime can do.
+const SUPPORTED: &[&str] = &[
+    "say_string",
+    "say_float",
+    "clear_bubble",
+    "sleep",
+    "enter_procedure",
+    "exit_procedure",
+    "enter_warp",
+    "exit_warp",
+    "detach_scripts",
+    "stop_all_sounds",
+    "trace",
+];
+
+const DONE: u32 = 0;
+const YIELDED: u32 = 1;
+const WAITING: u32 = 2;
+
+/// The module in the text format, or what the project uses that the runtime
+/// doesn't have.
+pub fn text(program: &Program, targets: &[scratch::Target]) -> Result<String, String> {
+    check(program, targets)?;
+    let mut data = Data::default();
+
+    let globals = program
+        .globals
+        .iter()
+        .map(|global| {
+            let value = match &global.init {
+                Expr::Number(n) => n.to_le_bytes(),
+                Expr::String(s) => (data.string(s) as u64).to_le_bytes(),
+                e => unreachable!("global initialized to {}", e),
+            };
+            (global.id.as_str(), data.push(&value))
+        })
+        .collect();
+
+    let names: Vec<_> = targets.iter().map(|t| data.string(&t.name)).collect();
+    let target_names = data.push(
+        &names
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect::<Vec<_>>(),
+    );
+
+    let scripts: Vec<_> = program
+        .functions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, func)| match &func.hat {
+            None => None,
+            Some(Hat::Flag) => Some(i),
+            Some(_) => unreachable!("{}", func.label),
+        })
+        .collect();
+
+    let frame_sizes: Vec<_> = program.functions.iter().map(frame_size).collect();
+
+    let mut functions = String::new();
+    for (i, func) in program.functions.iter().enumerate() {
+        let mut fc = FunctionCompiler {
+            program,
+            globals: &globals,
+            frame_sizes: &frame_sizes,
+            data: &mut data,
+            frame_size: frame_sizes[i],
+            next_slot: func.params.len() as u32,
+            blocks: vec![],
+            current: 0,
+            filled: false,
+        };
+        fc.function(func);
+        writeln!(functions, "  ;; {}", func.label).unwrap();
+        writeln!(
+            functions,
+            "  (func $fn{} (param $frame i32) (result i32) (local $state i32) (local $r i32)",
+            i
+        )
+        .unwrap();
+        functions.push_str(&fc.finish());
+        functions.push_str("  )\n");
+    }
+
+    let scripts_start = align(DATA_START + data.bytes.len() as u32);
+    let heap = scripts_start + 64 * scripts.len() as u32;
+
+    let mut out = String::new();
+    out.push_str("(module\n");
+    out.push_str(RUNTIME);
+    writeln!(
+        out,
+        "\n  (memory (export \"memory\") {})",
+        heap.div_ceil(0x10000)
+    )
+    .unwrap();
+    writeln!(out, "  (global $heap (mut i32) (i32.const {}))", heap).unwrap();
+    writeln!(
+        out,
+        "  (global $target_names i32 (i32.const {}))",
+        target_names
+    )
+    .unwrap();
+    writeln!(out, "  (global $scripts i32 (i32.const {}))", scripts_start).unwrap();
+    writeln!(
+        out,
+        "  (data (i32.const {}) \"{}\")",
+        DATA_START,
+        escape(&data.bytes)
+    )
+    .unwrap();
+    writeln!(out, "  (table {} funcref)", scripts.len()).unwrap();
+    write!(out, "  (elem (i32.const 0) func").unwrap();
+    for script in &scripts {
+        write!(out, " $fn{}", script).unwrap();
+    }
+    out.push_str(")\n\n");
+    out.push_str(&functions);
+    out.push_str("\n  (func (export \"_start\")\n    call $init\n");
+    for i in 0..scripts.len() {
+        writeln!(out, "    i32.const {}\n    call $spawn", i).unwrap();
+    }
+    out.push_str("    call $run\n    i32.const 0\n    call $exit)\n)\n");
+    Ok(out)
+}
+
+pub fn assemble(wat: &str) -> Vec<u8> {
+    match wat::parse_str(wat) {
+        Ok(module) => module,
+        Err(e) => panic!("{}\n\n{}", e, wat),
+    }
+}
+
+/// Make sure every script has a hat and every block calls functions that
+/// the runtime has, or say which one doesn't.
+fn check(program: &Program, targets: &[scratch::Target]) -> Result<(), String> {
+    /// The first function `stmts` call that the runtime doesn't have, and
+    /// the block that calls it.
+    fn body<'a>(stmts: &'a [Stmt], block: &mut &'a str) -> Option<&'static str> {
+        stmts.iter().find_map(|stmt| match stmt {
+            Stmt::Source(id) => {
+                *block = id;
+                None
+            }
+            Stmt::Call(c) => call(c),
+            Stmt::SetGlobal(_, e) => expr(e),
+            Stmt::CallFunction(_, args) => args.iter().find_map(expr),
+            Stmt::If(condition, then, otherwise) => expr(condition)
+                .or_else(|| body(then, block))
+                .or_else(|| body(otherwise, block)),
+            Stmt::Repeat(times, b) => expr(times).or_else(|| body(b, block)),
+            Stmt::Forever(b) => body(b, block),
+            Stmt::Yield | Stmt::Return | Stmt::Exit => None,
+        })
+    }
+    fn expr(e: &Expr) -> Option<&'static str> {
+        match e {
+            Expr::Cast(_, e) => expr(e),
+            Expr::Binary(_, l, r) | Expr::Compare(_, l, r) => expr(l).or_else(|| expr(r)),
+            Expr::Call(c) => call(c),
+            _ => None,
+        }
+    }
+    fn call(call: &Call) -> Option<&'static str> {
+        if SUPPORTED.contains(&call.func) {
+            call.args.iter().find_map(expr)
+        } else {
+            Some(call.func)
+        }
+    }
+
+    for func in &program.functions {
+        let hat = match &func.hat {
+            Some(Hat::Key(_)) => Some("`when key pressed` hats"),
+            Some(Hat::Backdrop(_)) => Some("`when backdrop switches to` hats"),
+            _ => None,
+        };
+        if let Some(hat) = hat {
+            return Err(format!(
+                "{}: the WebAssembly backend doesn't support {} yet",
+                func.label, hat
+            ));
+        }
+
+        let mut block = "";
+        if let Some(missing) = body(&func.body, &mut block) {
+            let target = &targets[func.target];
+            let source = source::Source::new(target, &source::names(targets));
+            let line = source.lines[block] as usize;
+            return Err(format!(
+                "{}: `{}` needs `{}`, which the WebAssembly backend doesn't support yet",
+                target.name,
+                source.text.lines().nth(line - 1).unwrap().trim(),
+                missing
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn align(n: u32) -> u32 {
+    (n + 7) & !7
+}
+
+/// Bytes for a data segment, which are UTF-8 apart from the escapes.
+fn escape(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for &b in bytes {
+        if (b' '..=b'~').contains(&b) && b != b'"' && b != b'\\' {
+            s.push(b as char);
+        } else {
+            write!(s, "\\{:02x}", b).unwrap();
+        }
+    }
+    s
+}
+
+fn float(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_owned()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_owned()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+/// Everything that goes into memory before the program starts.
+#[derive(Default)]
+struct Data {
+    bytes: Vec<u8>,
+    strings: HashMap<String, u32>,
+}
+
+impl Data {
+    /// Add 8-byte aligned data, returning its address.
+    fn push(&mut self, bytes: &[u8]) -> u32 {
+        self.bytes
+            .resize(align(self.bytes.len() as u32) as usize, 0);
+        let address = DATA_START + self.bytes.len() as u32;
+        self.bytes.extend_from_slice(bytes);
+        address
+    }
+
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(address) = self.strings.get(s) {
+            return *address;
+        }
+        let address = DATA_START + self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        self.strings.insert(s.to_owned(), address);
+        address
+    }
+}
+
+/// The state, then a slot of 8 bytes for every parameter, loop counter and
+/// wait.
+fn frame_size(func: &Function) -> u32 {
+    fn slots(stmts: &[Stmt]) -> u32 {
+        stmts
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Call(call) if call.func == "sleep" => 1,
+                Stmt::If(_, then, otherwise) => slots(then) + slots(otherwise),
+                Stmt::Repeat(_, body) => 1 + slots(body),
+                Stmt::Forever(body) => slots(body),
+                _ => 0,
+            })
+            .sum()
+    }
+    8 + 8 * (func.params.len() as u32 + slots(&func.body))
+}
+
+fn slot_offset(slot: u32) -> u32 {
+    8 + 8 * slot
+}
+
+fn load(ty: Type) -> &'static str {
+    match ty {
+        Type::Number => "f64.load",
+        _ => "i32.load",
+    }
+}
+
+fn store(ty: Type) -> &'static str {
+    match ty {
+        Type::Number => "f64.store",
+        _ => "i32.store",
+    }
+}
+
+struct FunctionCompiler<'a> {
+    program: &'a Program,
+    globals: &'a HashMap<&'a str, u32>,
+    frame_sizes: &'a [u32],
+    data: &'a mut Data,
+    frame_size: u32,
+    next_slot: u32,
+    /// The code for each state.
+    blocks: Vec<String>,
+    current: usize,
+    /// Whether the current block already ends in a branch.
+    filled: bool,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn function(&mut self, func: &Function) {
+        self.current = self.create_block();
+        self.body(&func.body);
+        if !self.filled {
+            self.finish_with(DONE);
+        }
+    }
+
+    /// The function body: dispatch on the state to the block for it.
+    fn finish(&self) -> String {
+        let mut out = String::new();
+        out.push_str("    local.get $frame\n    i32.load\n    local.set $state\n");
+        out.push_str("    loop $dispatch\n");
+        for i in (0..self.blocks.len()).rev() {
+            writeln!(out, "    block $b{}", i).unwrap();
+        }
+        out.push_str("    local.get $state\n    br_table");
+        for i in 0..self.blocks.len() {
+            write!(out, " $b{}", i).unwrap();
+        }
+        out.push('\n');
+        for block in &self.blocks {
+            out.push_str("    end\n");
+            for line in block.lines() {
+                writeln!(out, "    {}", line).unwrap();
+            }
+        }
+        out.push_str("    end\n    unreachable\n");
+        out
+    }
+
+    fn emit(&mut self, instruction: impl AsRef<str>) {
+        let block = &mut self.blocks[self.current];
+        block.push_str(instruction.as_ref());
+        block.push('\n');
+    }
+
+    fn create_block(&mut self) -> usize {
+        self.blocks.push(String::new());
+        self.blocks.len() - 1
+    }
+
+    fn switch_to_block(&mut self, block: usize) {
+        assert!(self.filled, "block {} falls through", self.current);
+        self.current = block;
+        self.filled = false;
+    }
+
+    fn new_slot(&mut self) -> u32 {
+        self.next_slot += 1;
+        slot_offset(self.next_slot - 1)
+    }
+
+    fn jump(&mut self, block: usize) {
+        self.emit(format!(
+            "i32.const {}\nlocal.set $state\nbr $dispatch",
+            block
+        ));
+        self.filled = true;
+    }
+
+    fn jump_if(&mut self, block: usize) {
+        self.emit("if");
+        self.jump(block);
+        self.emit("end");
+        self.filled = false;
+    }
+
+    /// Return `status`, to carry on at `block` next time.
+    fn suspend(&mut self, block: usize, status: u32) {
+        self.emit(format!(
+            "local.get $frame\ni32.const {}\ni32.store\ni32.const {}\nreturn",
+            block, status
+        ));
+        self.filled = true;
+    }
+
+    fn finish_with(&mut self, status: u32) {
+        self.emit(format!("i32.const {}\nreturn", status));
+        self.filled = true;
+    }
+
+    fn body(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Call(call) if call.func == "sleep" => {
+                let deadline = self.new_slot();
+                let wait = self.create_block();
+                self.emit("local.get $frame");
+                self.expr(&call.args[0]);
+                self.emit(format!("call $deadline\nf64.store offset={}", deadline));
+                self.suspend(wait, WAITING);
+
+                self.switch_to_block(wait);
+                self.emit(format!(
+                    "call $now\nlocal.get $frame\nf64.load offset={}\nf64.lt\nif",
+                    deadline
+                ));
+                self.suspend(wait, WAITING);
+                self.emit("end");
+                self.filled = false;
+            }
+            Stmt::Call(call) => self.call(call),
+            Stmt::SetGlobal(id, e) => {
+                self.emit(format!("i32.const {}", self.globals[id.as_str()]));
+                self.expr(e);
+                self.emit(store(e.ty()));
+            }
+            Stmt::CallFunction(func, args) => {
+                let callee = self.frame_size;
+                let params = &self.program.functions[*func].params;
+                for (i, (arg, ty)) in args.iter().zip(params).enumerate() {
+                    self.emit("local.get $frame");
+                    self.expr(arg);
+                    self.emit(format!(
+                        "{} offset={}",
+                        store(*ty),
+                        callee + slot_offset(i as u32)
+                    ));
+                }
+                self.emit(format!(
+                    "local.get $frame\ni32.const 0\ni32.store offset={}",
+                    callee
+                ));
+                self.emit(format!(
+                    "local.get $frame\ni32.const {}\ni32.add\ncall $check_stack",
+                    callee + self.frame_sizes[*func]
+                ));
+                let bcall = self.create_block();
+                self.jump(bcall);
+
+                self.switch_to_block(bcall);
+                self.emit(format!(
+                    "local.get $frame\ni32.const {}\ni32.add\ncall $fn{}\nlocal.tee $r\nif",
+                    callee, func
+                ));
+                self.emit(format!(
+                    "local.get $frame\ni32.const {}\ni32.store\nlocal.get $r\nreturn\nend",
+                    bcall
+                ));
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let bthen = self.create_block();
+                let botherwise = self.create_block();
+                let bnext = self.create_block();
+
+                self.expr(condition);
+                self.jump_if(bthen);
+                self.jump(botherwise);
+
+                for (block, body) in [(bthen, then), (botherwise, otherwise)].iter() {
+                    self.switch_to_block(*block);
+                    self.body(body);
+                    if !self.filled {
+                        self.jump(bnext);
+                    }
+                }
+
+                self.switch_to_block(bnext);
+            }
+            Stmt::Repeat(times, body) => {
+                let counter = self.new_slot();
+                let head = self.create_block();
+                let bnext = self.create_block();
+
+                self.emit("local.get $frame");
+                self.expr(times);
+                // The same as `scratchc_runtime::repeat_count`.
+                self.emit(format!(
+                    "f64.const 0.5\nf64.add\nf64.floor\ni32.trunc_sat_f64_u\ni32.store offset={}",
+                    counter
+                ));
+                self.jump(head);
+
+                self.switch_to_block(head);
+                self.emit(format!(
+                    "local.get $frame\ni32.load offset={}\ni32.eqz",
+                    counter
+                ));
+                self.jump_if(bnext);
+                self.emit(format!(
+                    "local.get $frame\nlocal.get $frame\ni32.load offset={0}\ni32.const 1\ni32.sub\ni32.store offset={0}",
+                    counter
+                ));
+                self.body(body);
+                if !self.filled {
+                    self.jump(head);
+                }
+
+                self.switch_to_block(bnext);
+            }
+            Stmt::Forever(body) => {
+                let head = self.create_block();
+                self.jump(head);
+                self.switch_to_block(head);
+                self.body(body);
+                if !self.filled {
+                    self.jump(head);
+                }
+            }
+            Stmt::Yield => {
+                let bnext = self.create_block();
+                self.emit("call $should_yield\nif");
+                self.suspend(bnext, YIELDED);
+                self.emit("end");
+                self.filled = false;
+                self.jump(bnext);
+                self.switch_to_block(bnext);
+            }
+            Stmt::Return => self.finish_with(DONE),
+            Stmt::Exit => {
+                self.emit("i32.const 0\ncall $exit\nunreachable");
+                self.filled = true;
+            }
+            Stmt::Source(_) => {}
+        }
+    }
+
+    fn call(&mut self, call: &Call) {
+        for arg in &call.args {
+            self.expr(arg);
+        }
+        self.emit(format!("call ${}", call.func));
+    }
+
+    fn cast(&mut self, from: Type, to: Type) {
+        self.emit(match (from, to) {
+            (Type::Number, Type::Bool) => "call $number_to_bool",
+            (Type::Bool, Type::Number) => "f64.convert_i32_u",
+            (Type::Bool, Type::String) => "call $bool_to_string",
+            (Type::String, Type::Number) => "call $string_to_number",
+            (Type::String, Type::Bool) => "call $string_to_bool",
+            (Type::Number, Type::String) => "call $number_to_string",
+            _ => unreachable!("cast from {} to {}", from, to),
+        });
+    }
+
+    fn expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Number(n) => self.emit(format!("f64.const {}", float(*n))),
+            Expr::Bool(b) => self.emit(format!("i32.const {}", *b as i32)),
+            Expr::String(s) => {
+                let address = self.data.string(s);
+                self.emit(format!("i32.const {}", address));
+            }
+            Expr::Int(i) => self.emit(format!("i32.const {}", i)),
+            Expr::Global(id, ty) => {
+                let address = self.globals[id.as_str()];
+                self.emit(format!("i32.const {}\n{}", address, load(*ty)));
+            }
+            Expr::Param(i, ty) => self.emit(format!(
+                "local.get $frame\n{} offset={}",
+                load(*ty),
+                slot_offset(*i as u32)
+            )),
+            Expr::Cast(ty, inner) => {
+                self.expr(inner);
+                self.cast(inner.ty(), *ty);
+            }
+            Expr::Binary(op, l, r) => {
+                self.expr(l);
+                self.expr(r);
+                self.emit(match op {
+                    BinaryOp::Add => "f64.add",
+                    BinaryOp::Sub => "f64.sub",
+                });
+            }
+            Expr::Compare(op, l, r) => {
+                self.expr(l);
+                self.expr(r);
+                let op = match op {
+                    CompareOp::Eq => "eq",
+                    CompareOp::Gt => "gt",
+                };
+                if l.ty() == Type::Number && !l.may_be_nan() && !r.may_be_nan() {
+                    self.emit(format!("f64.{}", op));
+                } else {
+                    let func = if l.ty() == Type::Number {
+                        "compare_numbers"
+                    } else {
+                        "compare"
+                    };
+                    let op = if op == "gt" { "gt_s" } else { op };
+                    self.emit(format!("call ${}\ni32.const 0\ni32.{}", func, op));
+                }
+            }
+            Expr::Call(call) => self.call(call),
+        }
+    }
+}
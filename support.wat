@@ -0,0 +1,1098 @@
+  ;; The runtime for projects compiled to WebAssembly, spliced into every
+  ;; module by `src/wasm.rs`. It covers what the native runtime in support.rs
+  ;; does for scheduling, speech and conversions, using WASI for the outside
+  ;; world.
+  ;;
+  ;; Memory below 9216 belongs to the runtime:
+  ;;
+  ;;   0     iovec for fd_write, and the count it writes back at 8
+  ;;   16    clock_time_get result
+  ;;   32    poll_oneoff subscription, event at 80 and count at 112
+  ;;   128   environ_sizes_get results
+  ;;   256   constant strings, 32 bytes each
+  ;;   1536  buffer for formatting numbers
+  ;;   2048  three big integers, then four buffers of decimal digits
+  ;;
+  ;; Every script has an entry of 64 bytes at $scripts:
+  ;;
+  ;;   0     table index of its function
+  ;;   4     its first frame, at the start of its stack
+  ;;   8     the end of its stack
+  ;;   12    whether it has finished
+  ;;   16    warp depth
+  ;;   20    custom block depth
+  ;;   24    when warp mode started, or -1
+  ;;   32    its hat block ID, once traced
+
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "clock_time_get"
+    (func $clock_time_get (param i32 i64 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "poll_oneoff"
+    (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_sizes_get"
+    (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get"
+    (func $environ_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "proc_exit"
+    (func $proc_exit (param i32)))
+
+  (data (i32.const 256) "true")
+  (data (i32.const 288) "false")
+  (data (i32.const 320) "NaN")
+  (data (i32.const 352) "Infinity")
+  (data (i32.const 384) "-Infinity")
+  (data (i32.const 416) "SCRATCHC_MAX_DEPTH=")
+  (data (i32.const 448) "SCRATCHC_SPEECH=")
+  (data (i32.const 480) "speaker")
+  (data (i32.const 512) "scratchc: custom block \22")
+  (data (i32.const 544) "\22 in ")
+  (data (i32.const 576) " is nested more than ")
+  (data (i32.const 608) " deep\0a")
+  (data (i32.const 640) "[")
+  (data (i32.const 672) " says] ")
+  (data (i32.const 704) " thinks] ")
+  (data (i32.const 736) "\0a")
+  (data (i32.const 768) "\09")
+
+  (type $script (func (param i32) (result i32)))
+
+  ;; Each script's stack of frames.
+  (global $STACK_SIZE i32 (i32.const 0x1000000))
+  ;; Scratch lets warp mode run for half a second before yielding anyway.
+  (global $WARP_TIME f64 (f64.const 0.5))
+
+  (global $BIG_A i32 (i32.const 2048))
+  (global $BIG_B i32 (i32.const 3072))
+  (global $BIG_C i32 (i32.const 4096))
+  (global $DIGITS i32 (i32.const 1024))
+  (global $LOW i32 (i32.const 5120))
+  (global $MID i32 (i32.const 6144))
+  (global $HIGH i32 (i32.const 7168))
+  (global $CANDIDATE i32 (i32.const 8192))
+
+  (global $script_count (mut i32) (i32.const 0))
+  (global $current (mut i32) (i32.const 0))
+  (global $clock_start (mut i64) (i64.const 0))
+  (global $max_depth (mut i32) (i32.const 100000))
+  (global $speakers (mut i32) (i32.const 0))
+
+  (func $strlen (param $s i32) (result i32)
+    (local $p i32)
+    (local.set $p (local.get $s))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (i32.load8_u (local.get $p))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (br $next)))
+    (i32.sub (local.get $p) (local.get $s)))
+
+  (func $write (param $fd i32) (param $p i32) (param $len i32)
+    (i32.store (i32.const 0) (local.get $p))
+    (i32.store (i32.const 4) (local.get $len))
+    (drop (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8))))
+
+  (func $print (param $fd i32) (param $s i32)
+    (call $write (local.get $fd) (local.get $s) (call $strlen (local.get $s))))
+
+  (func $exit (param $code i32)
+    (call $proc_exit (local.get $code))
+    (unreachable))
+
+  ;; Memory that is never freed, like the native runtime's interned strings.
+  (func $alloc (param $size i32) (result i32)
+    (local $p i32)
+    (local $missing i32)
+    (local.set $p
+      (i32.and (i32.add (global.get $heap) (i32.const 7)) (i32.const -8)))
+    (global.set $heap (i32.add (local.get $p) (local.get $size)))
+    (local.set $missing
+      (i32.sub (global.get $heap) (i32.mul (memory.size) (i32.const 0x10000))))
+    (if (i32.gt_s (local.get $missing) (i32.const 0))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.shr_u (i32.add (local.get $missing) (i32.const 0xffff))
+                  (i32.const 16)))
+              (i32.const -1))
+          (then (unreachable)))))
+    (local.get $p))
+
+  ;; Seconds since the project started.
+  (func $now (result f64)
+    (drop (call $clock_time_get (i32.const 1) (i64.const 1000) (i32.const 16)))
+    (f64.div
+      (f64.convert_i64_u
+        (i64.sub (i64.load (i32.const 16)) (global.get $clock_start)))
+      (f64.const 1e9)))
+
+  ;; When a wait for `secs` seconds ends.
+  (func $deadline (param $secs f64) (result f64)
+    (f64.add (call $now)
+      (select (local.get $secs) (f64.const 0)
+        (f64.gt (local.get $secs) (f64.const 0)))))
+
+  (func $starts_with (param $s i32) (param $prefix i32) (result i32)
+    (local $c i32)
+    (block $no
+      (loop $next
+        (local.set $c (i32.load8_u (local.get $prefix)))
+        (if (i32.eqz (local.get $c)) (then (return (i32.const 1))))
+        (br_if $no (i32.ne (i32.load8_u (local.get $s)) (local.get $c)))
+        (local.set $s (i32.add (local.get $s) (i32.const 1)))
+        (local.set $prefix (i32.add (local.get $prefix) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  (func $parse_uint (param $s i32) (result i32)
+    (local $n i32)
+    (local $c i32)
+    (block $done
+      (loop $next
+        (local.set $c (i32.sub (i32.load8_u (local.get $s)) (i32.const 48)))
+        (br_if $done (i32.gt_u (local.get $c) (i32.const 9)))
+        (local.set $n
+          (i32.add (i32.mul (local.get $n) (i32.const 10)) (local.get $c)))
+        (local.set $s (i32.add (local.get $s) (i32.const 1)))
+        (br $next)))
+    (local.get $n))
+
+  (func $init
+    (local $count i32)
+    (local $pointers i32)
+    (local $i i32)
+    (local $var i32)
+    (drop (call $clock_time_get (i32.const 1) (i64.const 1000) (i32.const 16)))
+    (global.set $clock_start (i64.load (i32.const 16)))
+
+    (drop (call $environ_sizes_get (i32.const 128) (i32.const 132)))
+    (local.set $count (i32.load (i32.const 128)))
+    (local.set $pointers (call $alloc (i32.shl (local.get $count) (i32.const 2))))
+    (drop (call $environ_get
+      (local.get $pointers)
+      (call $alloc (i32.load (i32.const 132)))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $count)))
+        (local.set $var
+          (i32.load (i32.add (local.get $pointers) (i32.shl (local.get $i) (i32.const 2)))))
+        (if (call $starts_with (local.get $var) (i32.const 416))
+          (then
+            (global.set $max_depth
+              (call $parse_uint (i32.add (local.get $var) (i32.const 19))))))
+        (if (call $starts_with (local.get $var) (i32.const 448))
+          (then
+            (global.set $speakers
+              (call $starts_with (i32.add (local.get $var) (i32.const 16)) (i32.const 480)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next))))
+
+  (func $spawn (param $f i32)
+    (local $entry i32)
+    (local $frame i32)
+    (local.set $entry
+      (i32.add (global.get $scripts) (i32.shl (global.get $script_count) (i32.const 6))))
+    (global.set $script_count (i32.add (global.get $script_count) (i32.const 1)))
+    (local.set $frame (call $alloc (global.get $STACK_SIZE)))
+    (i32.store (local.get $frame) (i32.const 0))
+    (i32.store (local.get $entry) (local.get $f))
+    (i32.store offset=4 (local.get $entry) (local.get $frame))
+    (i32.store offset=8 (local.get $entry)
+      (i32.add (local.get $frame) (global.get $STACK_SIZE)))
+    (i32.store offset=12 (local.get $entry) (i32.const 0))
+    (i32.store offset=16 (local.get $entry) (i32.const 0))
+    (i32.store offset=20 (local.get $entry) (i32.const 0))
+    (f64.store offset=24 (local.get $entry) (f64.const -1)))
+
+  ;; Give every script a turn until they have all finished. When all of them
+  ;; are waiting, sleep a little instead of spinning.
+  (func $run
+    (local $i i32)
+    (local $entry i32)
+    (local $status i32)
+    (local $running i32)
+    (local $waiting i32)
+    (loop $round
+      (local.set $running (i32.const 0))
+      (local.set $waiting (i32.const 1))
+      (local.set $i (i32.const 0))
+      (block $done
+        (loop $next
+          (br_if $done (i32.ge_u (local.get $i) (global.get $script_count)))
+          (local.set $entry
+            (i32.add (global.get $scripts) (i32.shl (local.get $i) (i32.const 6))))
+          (if (i32.eqz (i32.load offset=12 (local.get $entry)))
+            (then
+              (global.set $current (local.get $entry))
+              (local.set $status
+                (call_indirect (type $script)
+                  (i32.load offset=4 (local.get $entry))
+                  (i32.load (local.get $entry))))
+              (if (i32.eqz (local.get $status))
+                (then (i32.store offset=12 (local.get $entry) (i32.const 1)))
+                (else (local.set $running (i32.const 1))))
+              (if (i32.eq (local.get $status) (i32.const 1))
+                (then (local.set $waiting (i32.const 0))))))
+          (local.set $i (i32.add (local.get $i) (i32.const 1)))
+          (br $next)))
+      (if (i32.eqz (local.get $running)) (then (return)))
+      (if (local.get $waiting) (then (call $sleep_briefly)))
+      (br $round)))
+
+  (func $sleep_briefly
+    ;; A relative timeout of a millisecond on the monotonic clock.
+    (i64.store (i32.const 32) (i64.const 0))
+    (i32.store8 (i32.const 40) (i32.const 0))
+    (i32.store (i32.const 48) (i32.const 1))
+    (i64.store (i32.const 56) (i64.const 1000000))
+    (i64.store (i32.const 64) (i64.const 1000))
+    (i32.store16 (i32.const 72) (i32.const 0))
+    (drop (call $poll_oneoff (i32.const 32) (i32.const 80) (i32.const 1) (i32.const 112))))
+
+  ;; Frames for custom blocks go on the running script's stack.
+  (func $check_stack (param $end i32)
+    (if (i32.gt_u (local.get $end) (i32.load offset=8 (global.get $current)))
+      (then (unreachable))))
+
+  ;; Whether a loop should give up its turn at the end of an iteration:
+  ;; always, unless it's in warp mode and hasn't used up its time.
+  (func $should_yield (result i32)
+    (local $now f64)
+    (if (i32.eqz (i32.load offset=16 (global.get $current)))
+      (then (return (i32.const 1))))
+    (local.set $now (call $now))
+    (if (f64.lt
+          (f64.sub (local.get $now) (f64.load offset=24 (global.get $current)))
+          (global.get $WARP_TIME))
+      (then (return (i32.const 0))))
+    (f64.store offset=24 (global.get $current) (local.get $now))
+    (i32.const 1))
+
+  (func $enter_warp
+    (i32.store offset=16 (global.get $current)
+      (i32.add (i32.load offset=16 (global.get $current)) (i32.const 1)))
+    (if (f64.lt (f64.load offset=24 (global.get $current)) (f64.const 0))
+      (then (f64.store offset=24 (global.get $current) (call $now)))))
+
+  (func $exit_warp
+    (local $depth i32)
+    (local.set $depth (i32.sub (i32.load offset=16 (global.get $current)) (i32.const 1)))
+    (i32.store offset=16 (global.get $current) (local.get $depth))
+    (if (i32.eqz (local.get $depth))
+      (then (f64.store offset=24 (global.get $current) (f64.const -1)))))
+
+  (func $target_name (param $t i32) (result i32)
+    (i32.load (i32.add (global.get $target_names) (i32.shl (local.get $t) (i32.const 2)))))
+
+  (func $enter_procedure (param $t i32) (param $name i32)
+    (local $depth i32)
+    (local.set $depth (i32.add (i32.load offset=20 (global.get $current)) (i32.const 1)))
+    (i32.store offset=20 (global.get $current) (local.get $depth))
+    (if (i32.and
+          (i32.ne (global.get $max_depth) (i32.const 0))
+          (i32.gt_u (local.get $depth) (global.get $max_depth)))
+      (then
+        (call $print (i32.const 2) (i32.const 512))
+        (call $print (i32.const 2) (local.get $name))
+        (call $print (i32.const 2) (i32.const 544))
+        (call $print (i32.const 2) (call $target_name (local.get $t)))
+        (call $print (i32.const 2) (i32.const 576))
+        (call $print (i32.const 2)
+          (call $number_to_string (f64.convert_i32_u (global.get $max_depth))))
+        (call $print (i32.const 2) (i32.const 608))
+        (call $exit (i32.const 1)))))
+
+  (func $exit_procedure
+    (i32.store offset=20 (global.get $current)
+      (i32.sub (i32.load offset=20 (global.get $current)) (i32.const 1))))
+
+  ;; Scripts only run while the program does, so stopping them is exiting.
+  (func $detach_scripts)
+
+  ;; Sounds aren't supported, so there are none to stop.
+  (func $stop_all_sounds)
+
+  ;; Without a display, speech is only printed.
+  (func $clear_bubble (param $t i32))
+
+  (func $say_string (param $t i32) (param $s i32) (param $think i32)
+    (if (global.get $speakers)
+      (then
+        ;; An empty message only removes the bubble.
+        (if (i32.eqz (i32.load8_u (local.get $s))) (then (return)))
+        (call $print (i32.const 1) (i32.const 640))
+        (call $print (i32.const 1) (call $target_name (local.get $t)))
+        (call $print (i32.const 1)
+          (select (i32.const 704) (i32.const 672) (local.get $think)))))
+    (call $print (i32.const 1) (local.get $s))
+    (call $print (i32.const 1) (i32.const 736)))
+
+  ;; Like the native runtime's trace, but always to stderr.
+  (func $trace (param $t i32) (param $script i32) (param $block i32) (param $opcode i32)
+    (if (i32.load8_u (local.get $script))
+      (then (i32.store offset=32 (global.get $current) (local.get $script)))
+      (else (local.set $script (i32.load offset=32 (global.get $current)))))
+    (call $print (i32.const 2) (call $target_name (local.get $t)))
+    (call $print (i32.const 2) (i32.const 768))
+    (call $print (i32.const 2) (local.get $script))
+    (call $print (i32.const 2) (i32.const 768))
+    (call $print (i32.const 2) (local.get $block))
+    (call $print (i32.const 2) (i32.const 768))
+    (call $print (i32.const 2) (local.get $opcode))
+    (call $print (i32.const 2) (i32.const 736)))
+
+  (func $say_float (param $t i32) (param $n f64) (param $think i32)
+    (call $say_string (local.get $t) (call $number_to_string (local.get $n)) (local.get $think)))
+
+  (func $bool_to_string (param $b i32) (result i32)
+    (select (i32.const 256) (i32.const 288) (local.get $b)))
+
+  (func $number_to_bool (param $n f64) (result i32)
+    (i32.and
+      (f64.ne (local.get $n) (f64.const 0))
+      (f64.eq (local.get $n) (local.get $n))))
+
+  (func $string_to_bool (param $s i32) (result i32)
+    (local $c i32)
+    (local.set $c (i32.load8_u (local.get $s)))
+    (if (i32.eqz (local.get $c)) (then (return (i32.const 0))))
+    (if (i32.and
+          (i32.eq (local.get $c) (i32.const 48))
+          (i32.eqz (i32.load8_u offset=1 (local.get $s))))
+      (then (return (i32.const 0))))
+    (i32.eqz (call $lowercase_equal (local.get $s) (i32.const 288))))
+
+  ;; 10^n, exactly for n up to 22 and within a few units in the last place
+  ;; after that.
+  (func $pow10 (param $n i32) (result f64)
+    (local $r f64)
+    (local.set $r (f64.const 1))
+    (block $done
+      (loop $next
+        (br_if $done (i32.lt_s (local.get $n) (i32.const 22)))
+        (local.set $r (f64.mul (local.get $r) (f64.const 1e22)))
+        (local.set $n (i32.sub (local.get $n) (i32.const 22)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+        (local.set $r (f64.mul (local.get $r) (f64.const 10)))
+        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+        (br $next)))
+    (local.get $r))
+
+  ;; m * 10^e, correctly rounded when both are small enough to be exact.
+  (func $scale (param $m f64) (param $e i32) (result f64)
+    ;; Very small numbers are scaled in two steps, so that 10^-e doesn't
+    ;; overflow on the way.
+    (if (i32.lt_s (local.get $e) (i32.const -300))
+      (then
+        (local.set $m (f64.div (local.get $m) (call $pow10 (i32.const 300))))
+        (local.set $e (i32.add (local.get $e) (i32.const 300)))))
+    (if (result f64) (i32.ge_s (local.get $e) (i32.const 0))
+      (then (f64.mul (local.get $m) (call $pow10 (local.get $e))))
+      (else (f64.div (local.get $m) (call $pow10 (i32.sub (i32.const 0) (local.get $e)))))))
+
+  ;; Big unsigned integers, for converting numbers exactly: a length, then
+  ;; that many 32-bit limbs, least significant first, with no leading zeros.
+
+  (func $big_set (param $b i32) (param $v i64)
+    (i64.store offset=4 (local.get $b) (local.get $v))
+    (i32.store (local.get $b)
+      (select (i32.const 2)
+        (i32.ne (i32.wrap_i64 (local.get $v)) (i32.const 0))
+        (i64.gt_u (local.get $v) (i64.const 0xffffffff)))))
+
+  ;; b = b * m + a
+  (func $big_mul_add (param $b i32) (param $m i32) (param $a i32)
+    (local $len i32)
+    (local $p i32)
+    (local $end i32)
+    (local $t i64)
+    (local.set $len (i32.load (local.get $b)))
+    (local.set $t (i64.extend_i32_u (local.get $a)))
+    (local.set $p (i32.add (local.get $b) (i32.const 4)))
+    (local.set $end (i32.add (local.get $p) (i32.shl (local.get $len) (i32.const 2))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+        (local.set $t
+          (i64.add
+            (i64.mul (i64.load32_u (local.get $p)) (i64.extend_i32_u (local.get $m)))
+            (local.get $t)))
+        (i64.store32 (local.get $p) (local.get $t))
+        (local.set $t (i64.shr_u (local.get $t) (i64.const 32)))
+        (local.set $p (i32.add (local.get $p) (i32.const 4)))
+        (br $next)))
+    (if (i64.ne (local.get $t) (i64.const 0))
+      (then
+        (i64.store32 (local.get $p) (local.get $t))
+        (i32.store (local.get $b) (i32.add (local.get $len) (i32.const 1))))))
+
+  ;; b = b * base^n, multiplying by base^chunk_n at a time.
+  (func $big_mul_pow (param $b i32) (param $base i32) (param $n i32)
+    (param $chunk i32) (param $chunk_n i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.lt_s (local.get $n) (local.get $chunk_n)))
+        (call $big_mul_add (local.get $b) (local.get $chunk) (i32.const 0))
+        (local.set $n (i32.sub (local.get $n) (local.get $chunk_n)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+        (call $big_mul_add (local.get $b) (local.get $base) (i32.const 0))
+        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+        (br $next))))
+
+  (func $big_mul_pow2 (param $b i32) (param $n i32)
+    (call $big_mul_pow (local.get $b) (i32.const 2) (local.get $n)
+      (i32.const 0x80000000) (i32.const 31)))
+
+  (func $big_mul_pow5 (param $b i32) (param $n i32)
+    (call $big_mul_pow (local.get $b) (i32.const 5) (local.get $n)
+      (i32.const 1220703125) (i32.const 13)))
+
+  (func $big_mul_pow10 (param $b i32) (param $n i32)
+    (call $big_mul_pow (local.get $b) (i32.const 10) (local.get $n)
+      (i32.const 1000000000) (i32.const 9)))
+
+  ;; b = b / d, returning the remainder.
+  (func $big_div (param $b i32) (param $d i32) (result i32)
+    (local $len i32)
+    (local $p i32)
+    (local $r i64)
+    (local.set $len (i32.load (local.get $b)))
+    (local.set $p (i32.add (local.get $b) (i32.shl (local.get $len) (i32.const 2))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_u (local.get $p) (local.get $b)))
+        (local.set $r
+          (i64.or
+            (i64.shl (local.get $r) (i64.const 32))
+            (i64.load32_u (local.get $p))))
+        (i64.store32 (local.get $p)
+          (i64.div_u (local.get $r) (i64.extend_i32_u (local.get $d))))
+        (local.set $r (i64.rem_u (local.get $r) (i64.extend_i32_u (local.get $d))))
+        (local.set $p (i32.sub (local.get $p) (i32.const 4)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $len)))
+        (br_if $done
+          (i32.load (i32.add (local.get $b) (i32.shl (local.get $len) (i32.const 2)))))
+        (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+        (br $next)))
+    (i32.store (local.get $b) (local.get $len))
+    (i32.wrap_i64 (local.get $r)))
+
+  (func $big_compare (param $a i32) (param $b i32) (result i32)
+    (local $i i32)
+    (local $x i32)
+    (local $y i32)
+    (local.set $i (i32.load (local.get $a)))
+    (if (i32.ne (local.get $i) (i32.load (local.get $b)))
+      (then
+        (return
+          (select (i32.const 1) (i32.const -1)
+            (i32.gt_u (local.get $i) (i32.load (local.get $b)))))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $i)))
+        (local.set $x (i32.load (i32.add (local.get $a) (i32.shl (local.get $i) (i32.const 2)))))
+        (local.set $y (i32.load (i32.add (local.get $b) (i32.shl (local.get $i) (i32.const 2)))))
+        (if (i32.ne (local.get $x) (local.get $y))
+          (then
+            (return
+              (select (i32.const 1) (i32.const -1) (i32.gt_u (local.get $x) (local.get $y))))))
+        (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  ;; Write the decimal digits of b ending just before `end`, destroying b.
+  ;; They may start with zeros.
+  (func $big_digits (param $b i32) (param $end i32)
+    (local $r i32)
+    (local $i i32)
+    (loop $next
+      (local.set $r (call $big_div (local.get $b) (i32.const 1000000000)))
+      (local.set $i (i32.const 9))
+      (loop $digit
+        (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+        (i32.store8 (local.get $end)
+          (i32.add (i32.rem_u (local.get $r) (i32.const 10)) (i32.const 48)))
+        (local.set $r (i32.div_u (local.get $r) (i32.const 10)))
+        (local.set $i (i32.sub (local.get $i) (i32.const 1)))
+        (br_if $digit (local.get $i)))
+      (br_if $next (i32.load (local.get $b)))))
+
+  ;; The mantissa and exponent of a positive number, so that it's m * 2^e.
+  (func $decompose (param $n f64) (result i64 i32)
+    (local $bits i64)
+    (local $exp i32)
+    (local.set $bits (i64.reinterpret_f64 (local.get $n)))
+    (local.set $exp (i32.wrap_i64 (i64.shr_u (local.get $bits) (i64.const 52))))
+    (local.set $bits (i64.and (local.get $bits) (i64.const 0xfffffffffffff)))
+    (if (result i64 i32) (i32.eqz (local.get $exp))
+      (then (local.get $bits) (i32.const -1074))
+      (else
+        (i64.or (local.get $bits) (i64.const 0x10000000000000))
+        (i32.sub (local.get $exp) (i32.const 1075)))))
+
+  (func $is_space (param $c i32) (result i32)
+    (i32.or
+      (i32.eq (local.get $c) (i32.const 32))
+      (i32.and
+        (i32.ge_u (local.get $c) (i32.const 9))
+        (i32.le_u (local.get $c) (i32.const 13)))))
+
+  (func $is_blank (param $s i32) (result i32)
+    (local $c i32)
+    (loop $next
+      (local.set $c (i32.load8_u (local.get $s)))
+      (if (i32.eqz (local.get $c)) (then (return (i32.const 1))))
+      (if (i32.eqz (call $is_space (local.get $c))) (then (return (i32.const 0))))
+      (local.set $s (i32.add (local.get $s) (i32.const 1)))
+      (br $next))
+    (unreachable))
+
+  ;; Compare the number the digits in $BIG_A stand for, times 10^e10 and a
+  ;; little more if `sticky`, with the point halfway between `z` and the next
+  ;; number up.
+  (func $compare_halfway (param $z f64) (param $e10 i32) (param $sticky i32) (result i32)
+    (local $m i64)
+    (local $e i32)
+    (local $c i32)
+    (call $decompose (local.get $z))
+    (local.set $e)
+    (local.set $m)
+    ;; digits * 10^e10 against (2m + 1) * 2^(e - 1), scaled to integers.
+    (memory.copy (global.get $BIG_B) (global.get $BIG_A) (i32.const 1024))
+    (call $big_mul_pow10 (global.get $BIG_B)
+      (select (local.get $e10) (i32.const 0) (i32.gt_s (local.get $e10) (i32.const 0))))
+    (call $big_mul_pow2 (global.get $BIG_B)
+      (select (i32.sub (i32.const 1) (local.get $e)) (i32.const 0)
+        (i32.lt_s (local.get $e) (i32.const 1))))
+    (call $big_set (global.get $BIG_C)
+      (i64.add (i64.shl (local.get $m) (i64.const 1)) (i64.const 1)))
+    (call $big_mul_pow10 (global.get $BIG_C)
+      (select (i32.sub (i32.const 0) (local.get $e10)) (i32.const 0)
+        (i32.lt_s (local.get $e10) (i32.const 0))))
+    (call $big_mul_pow2 (global.get $BIG_C)
+      (select (i32.sub (local.get $e) (i32.const 1)) (i32.const 0)
+        (i32.gt_s (local.get $e) (i32.const 1))))
+    (local.set $c (call $big_compare (global.get $BIG_B) (global.get $BIG_C)))
+    (select (i32.const 1) (local.get $c)
+      (i32.and (i32.eqz (local.get $c)) (local.get $sticky))))
+
+  ;; JavaScript's `Number(s)`, which is NaN for anything that isn't a number.
+  ;; Like `str::parse`, it takes digits with an optional point and exponent,
+  ;; and also hex after "0x" and "Infinity".
+  (func $js_number (param $s i32) (result f64)
+    (local $end i32)
+    (local $p i32)
+    (local $c i32)
+    (local $sign f64)
+    (local $m f64)
+    (local $any i32)
+    (local $digits i32)
+    (local $exact i64)
+    (local $chunk i32)
+    (local $chunk_digits i32)
+    (local $sticky i32)
+    (local $e10 i32)
+    (local $approx_e i32)
+    (local $point i32)
+    (local $exp i32)
+    (local $exp_sign i32)
+    (local $z f64)
+    (local $bits i64)
+    (local $odd i32)
+    ;; Trim whitespace.
+    (local.set $end (i32.add (local.get $s) (call $strlen (local.get $s))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (call $is_space (i32.load8_u (local.get $s)))))
+        (local.set $s (i32.add (local.get $s) (i32.const 1)))
+        (br $next)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_u (local.get $end) (local.get $s)))
+        (br_if $done
+          (i32.eqz (call $is_space (i32.load8_u (i32.sub (local.get $end) (i32.const 1))))))
+        (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+        (br $next)))
+    (if (i32.eq (local.get $s) (local.get $end)) (then (return (f64.const 0))))
+
+    ;; Hex, without a sign.
+    (if (i32.and
+          (i32.gt_u (i32.sub (local.get $end) (local.get $s)) (i32.const 2))
+          (i32.and
+            (i32.eq (i32.load8_u (local.get $s)) (i32.const 48))
+            (i32.eq (i32.or (i32.load8_u offset=1 (local.get $s)) (i32.const 32))
+              (i32.const 120))))
+      (then
+        (local.set $p (i32.add (local.get $s) (i32.const 2)))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+            (local.set $c (i32.load8_u (local.get $p)))
+            (if (i32.le_u (i32.sub (local.get $c) (i32.const 48)) (i32.const 9))
+              (then (local.set $c (i32.sub (local.get $c) (i32.const 48))))
+              (else
+                (local.set $c (i32.sub (i32.or (local.get $c) (i32.const 32)) (i32.const 87)))
+                (if (i32.or
+                      (i32.lt_s (local.get $c) (i32.const 10))
+                      (i32.gt_s (local.get $c) (i32.const 15)))
+                  (then (return (f64.const nan))))))
+            (local.set $m
+              (f64.add (f64.mul (local.get $m) (f64.const 16)) (f64.convert_i32_u (local.get $c))))
+            (local.set $p (i32.add (local.get $p) (i32.const 1)))
+            (br $next)))
+        (return (local.get $m))))
+
+    (local.set $sign (f64.const 1))
+    (local.set $p (local.get $s))
+    (local.set $c (i32.load8_u (local.get $p)))
+    (if (i32.or (i32.eq (local.get $c) (i32.const 45)) (i32.eq (local.get $c) (i32.const 43)))
+      (then
+        (if (i32.eq (local.get $c) (i32.const 45)) (then (local.set $sign (f64.const -1))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))))
+    (if (i32.and
+          (i32.eq (i32.sub (local.get $end) (local.get $p)) (i32.const 8))
+          (call $starts_with (local.get $p) (i32.const 352)))
+      (then (return (f64.mul (local.get $sign) (f64.const inf)))))
+
+    ;; The significant digits go into $BIG_A, up to enough to tell any two
+    ;; numbers apart. Only whether the rest are zero matters. The first 17
+    ;; also make an approximation in $m, and the first 15 an exact $exact.
+    (call $big_set (global.get $BIG_A) (i64.const 0))
+    (local.set $point (i32.const 0))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+        (local.set $c (i32.load8_u (local.get $p)))
+        (if (i32.eq (local.get $c) (i32.const 46))
+          (then
+            (if (local.get $point) (then (return (f64.const nan))))
+            (local.set $point (i32.const 1)))
+          (else
+            (local.set $c (i32.sub (local.get $c) (i32.const 48)))
+            (br_if $done (i32.gt_u (local.get $c) (i32.const 9)))
+            (local.set $any (i32.const 1))
+            (if (local.get $point) (then (local.set $e10 (i32.sub (local.get $e10) (i32.const 1)))))
+            (if (i32.or (local.get $digits) (local.get $c))
+              (then
+                (local.set $digits (i32.add (local.get $digits) (i32.const 1)))
+                (if (i32.le_u (local.get $digits) (i32.const 800))
+                  (then
+                    (local.set $chunk
+                      (i32.add (i32.mul (local.get $chunk) (i32.const 10)) (local.get $c)))
+                    (local.set $chunk_digits (i32.add (local.get $chunk_digits) (i32.const 1)))
+                    (if (i32.eq (local.get $chunk_digits) (i32.const 9))
+                      (then
+                        (call $big_mul_add (global.get $BIG_A) (i32.const 1000000000) (local.get $chunk))
+                        (local.set $chunk (i32.const 0))
+                        (local.set $chunk_digits (i32.const 0))))
+                    (if (i32.le_u (local.get $digits) (i32.const 15))
+                      (then
+                        (local.set $exact
+                          (i64.add (i64.mul (local.get $exact) (i64.const 10))
+                            (i64.extend_i32_u (local.get $c))))))
+                    (if (i32.le_u (local.get $digits) (i32.const 17))
+                      (then
+                        (local.set $m
+                          (f64.add (f64.mul (local.get $m) (f64.const 10))
+                            (f64.convert_i32_u (local.get $c)))))
+                      (else (local.set $approx_e (i32.add (local.get $approx_e) (i32.const 1))))))
+                  (else
+                    (local.set $e10 (i32.add (local.get $e10) (i32.const 1)))
+                    (local.set $sticky (i32.or (local.get $sticky) (local.get $c)))))))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (br $next)))
+    (if (i32.eqz (local.get $any)) (then (return (f64.const nan))))
+    (call $big_mul_add (global.get $BIG_A)
+      (i32.trunc_f64_u (call $pow10 (local.get $chunk_digits)))
+      (local.get $chunk))
+
+    (if (i32.lt_u (local.get $p) (local.get $end))
+      (then
+        (if (i32.ne (i32.or (i32.load8_u (local.get $p)) (i32.const 32)) (i32.const 101))
+          (then (return (f64.const nan))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (local.set $c (i32.load8_u (local.get $p)))
+        (local.set $exp_sign (i32.const 1))
+        (if (i32.or (i32.eq (local.get $c) (i32.const 45)) (i32.eq (local.get $c) (i32.const 43)))
+          (then
+            (if (i32.eq (local.get $c) (i32.const 45)) (then (local.set $exp_sign (i32.const -1))))
+            (local.set $p (i32.add (local.get $p) (i32.const 1)))))
+        (if (i32.ge_u (local.get $p) (local.get $end)) (then (return (f64.const nan))))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $p) (local.get $end)))
+            (local.set $c (i32.sub (i32.load8_u (local.get $p)) (i32.const 48)))
+            (if (i32.gt_u (local.get $c) (i32.const 9)) (then (return (f64.const nan))))
+            (if (i32.lt_s (local.get $exp) (i32.const 100000))
+              (then
+                (local.set $exp
+                  (i32.add (i32.mul (local.get $exp) (i32.const 10)) (local.get $c)))))
+            (local.set $p (i32.add (local.get $p) (i32.const 1)))
+            (br $next)))
+        (local.set $e10
+          (i32.add (local.get $e10) (i32.mul (local.get $exp) (local.get $exp_sign))))))
+
+    (if (i32.eqz (local.get $digits))
+      (then (return (f64.mul (local.get $sign) (f64.const 0)))))
+    ;; Where the first digit is decides whether it's out of range.
+    (local.set $exp
+      (i32.add (local.get $e10)
+        (select (local.get $digits) (i32.const 800)
+          (i32.le_u (local.get $digits) (i32.const 800)))))
+    (if (i32.gt_s (local.get $exp) (i32.const 309))
+      (then (return (f64.mul (local.get $sign) (f64.const inf)))))
+    (if (i32.lt_s (local.get $exp) (i32.const -323))
+      (then (return (f64.mul (local.get $sign) (f64.const 0)))))
+    ;; When the digits and the power of ten are both exact, so is the result.
+    (if (i32.and
+          (i32.le_u (local.get $digits) (i32.const 15))
+          (i32.le_u (i32.add (local.get $e10) (i32.const 22)) (i32.const 44)))
+      (then
+        (return
+          (f64.mul (local.get $sign)
+            (call $scale (f64.convert_i64_u (local.get $exact)) (local.get $e10))))))
+
+    ;; Otherwise, start from an approximation and step to the closest number
+    ;; by comparing exactly with the points halfway to the numbers around it.
+    (local.set $z
+      (call $scale (local.get $m) (i32.add (local.get $e10) (local.get $approx_e))))
+    (if (f64.eq (local.get $z) (f64.const inf))
+      (then (local.set $z (f64.const 0x1.fffffffffffffp+1023))))
+    (loop $next
+      (local.set $bits (i64.reinterpret_f64 (local.get $z)))
+      (if (f64.eq (local.get $z) (f64.const inf))
+        (then (return (f64.mul (local.get $sign) (local.get $z)))))
+      (local.set $odd (i32.wrap_i64 (i64.and (local.get $bits) (i64.const 1))))
+      (local.set $c
+        (call $compare_halfway (local.get $z) (local.get $e10) (local.get $sticky)))
+      (if (i32.or
+            (i32.gt_s (local.get $c) (i32.const 0))
+            (i32.and (i32.eqz (local.get $c)) (local.get $odd)))
+        (then
+          (local.set $z (f64.reinterpret_i64 (i64.add (local.get $bits) (i64.const 1))))
+          (br $next)))
+      (if (i64.ne (local.get $bits) (i64.const 0))
+        (then
+          (local.set $c
+            (call $compare_halfway
+              (f64.reinterpret_i64 (i64.sub (local.get $bits) (i64.const 1)))
+              (local.get $e10)
+              (local.get $sticky)))
+          (if (i32.or
+                (i32.lt_s (local.get $c) (i32.const 0))
+                (i32.and (i32.eqz (local.get $c)) (local.get $odd)))
+            (then
+              (local.set $z (f64.reinterpret_i64 (i64.sub (local.get $bits) (i64.const 1))))
+              (br $next))))))
+    (f64.mul (local.get $sign) (local.get $z)))
+
+  (func $string_to_number (param $s i32) (result f64)
+    (local $n f64)
+    (local.set $n (call $js_number (local.get $s)))
+    (select (f64.const 0) (local.get $n) (f64.ne (local.get $n) (local.get $n))))
+
+  (func $lowercase (param $c i32) (result i32)
+    (select
+      (i32.or (local.get $c) (i32.const 32))
+      (local.get $c)
+      (i32.le_u (i32.sub (local.get $c) (i32.const 65)) (i32.const 25))))
+
+  (func $lowercase_equal (param $a i32) (param $b i32) (result i32)
+    (i32.eqz (call $lowercase_compare (local.get $a) (local.get $b))))
+
+  (func $lowercase_compare (param $a i32) (param $b i32) (result i32)
+    (local $x i32)
+    (local $y i32)
+    (loop $next
+      (local.set $x (call $lowercase (i32.load8_u (local.get $a))))
+      (local.set $y (call $lowercase (i32.load8_u (local.get $b))))
+      (if (i32.lt_u (local.get $x) (local.get $y)) (then (return (i32.const -1))))
+      (if (i32.gt_u (local.get $x) (local.get $y)) (then (return (i32.const 1))))
+      (if (i32.eqz (local.get $x)) (then (return (i32.const 0))))
+      (local.set $a (i32.add (local.get $a) (i32.const 1)))
+      (local.set $b (i32.add (local.get $b) (i32.const 1)))
+      (br $next))
+    (unreachable))
+
+  ;; Scratch's comparison: numeric when both sides are numbers, otherwise case
+  ;; insensitive. Only ASCII letters are folded.
+  (func $compare (param $a i32) (param $b i32) (result i32)
+    (local $x f64)
+    (local $y f64)
+    (local.set $x
+      (select (f64.const nan) (call $js_number (local.get $a)) (call $is_blank (local.get $a))))
+    (local.set $y
+      (select (f64.const nan) (call $js_number (local.get $b)) (call $is_blank (local.get $b))))
+    (if (i32.or
+          (f64.ne (local.get $x) (local.get $x))
+          (f64.ne (local.get $y) (local.get $y)))
+      (then (return (call $lowercase_compare (local.get $a) (local.get $b)))))
+    (i32.sub
+      (f64.gt (local.get $x) (local.get $y))
+      (f64.lt (local.get $x) (local.get $y))))
+
+  ;; `compare` for two numbers, which only has to compare them as text when
+  ;; one is NaN.
+  (func $compare_numbers (param $a f64) (param $b f64) (result i32)
+    (if (i32.or
+          (f64.ne (local.get $a) (local.get $a))
+          (f64.ne (local.get $b) (local.get $b)))
+      (then (return (call $compare
+        (call $number_to_string (local.get $a))
+        (call $number_to_string (local.get $b))))))
+    (i32.sub
+      (f64.gt (local.get $a) (local.get $b))
+      (f64.lt (local.get $a) (local.get $b))))
+
+  ;; Write the digits of `n` ending just before `end`, returning where they
+  ;; start.
+  (func $write_digits (param $n i64) (param $end i32) (result i32)
+    (loop $next
+      (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+      (i64.store8 (local.get $end)
+        (i64.add (i64.rem_u (local.get $n) (i64.const 10)) (i64.const 48)))
+      (local.set $n (i64.div_u (local.get $n) (i64.const 10)))
+      (br_if $next (i64.ne (local.get $n) (i64.const 0))))
+    (local.get $end))
+
+  (func $fill (param $p i32) (param $c i32) (param $count i32) (result i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $count) (i32.const 0)))
+        (i32.store8 (local.get $p) (local.get $c))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (local.set $count (i32.sub (local.get $count) (i32.const 1)))
+        (br $next)))
+    (local.get $p))
+
+  (func $copy (param $to i32) (param $from i32) (param $len i32) (result i32)
+    (memory.copy (local.get $to) (local.get $from) (local.get $len))
+    (i32.add (local.get $to) (local.get $len)))
+
+  (func $digits_compare (param $a i32) (param $b i32) (param $len i32) (result i32)
+    (local $x i32)
+    (local $y i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $len)))
+        (local.set $x (i32.load8_u (local.get $a)))
+        (local.set $y (i32.load8_u (local.get $b)))
+        (if (i32.ne (local.get $x) (local.get $y))
+          (then
+            (return
+              (select (i32.const 1) (i32.const -1) (i32.gt_u (local.get $x) (local.get $y))))))
+        (local.set $a (i32.add (local.get $a) (i32.const 1)))
+        (local.set $b (i32.add (local.get $b) (i32.const 1)))
+        (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  (func $all_zeros (param $p i32) (param $len i32) (result i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_s (local.get $len) (i32.const 0)))
+        (if (i32.ne (i32.load8_u (local.get $p)) (i32.const 48))
+          (then (return (i32.const 0))))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))
+        (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+        (br $next)))
+    (i32.const 1))
+
+  ;; Write the exact decimal digits of x * 2^e to one of the digit buffers,
+  ;; which are DIGITS wide and scaled so the last digit is 10^min(e, 0).
+  (func $exact_digits (param $x i64) (param $e i32) (param $buffer i32)
+    (memory.fill (local.get $buffer) (i32.const 48) (global.get $DIGITS))
+    (call $big_set (global.get $BIG_A) (local.get $x))
+    (if (i32.ge_s (local.get $e) (i32.const 0))
+      (then (call $big_mul_pow2 (global.get $BIG_A) (local.get $e)))
+      ;; x / 2^-e = x * 5^-e / 10^-e
+      (else (call $big_mul_pow5 (global.get $BIG_A) (i32.sub (i32.const 0) (local.get $e)))))
+    (call $big_digits (global.get $BIG_A) (i32.add (local.get $buffer) (global.get $DIGITS))))
+
+  ;; The fewest significant digits that read back as `n`, which is positive,
+  ;; and where the decimal point goes relative to the first of them. When
+  ;; there's a choice, they're the closest to `n`.
+  (func $shortest (param $n f64) (result i32 i32 i32)
+    (local $m i64)
+    (local $e i32)
+    (local $even i32)
+    (local $first i32)
+    (local $end i32)
+    (local $rest i32)
+    (local $low_ok i32)
+    (local $high_ok i32)
+    (local $c i32)
+    (local $p i32)
+    (local $result i32)
+    (call $decompose (local.get $n))
+    (local.set $e)
+    (local.set $m)
+    (local.set $even (i64.eqz (i64.and (local.get $m) (i64.const 1))))
+    ;; Anything strictly between the points halfway to the numbers on either
+    ;; side reads back as n, and so do those points when n is even. Above a
+    ;; power of two, the number below is closer.
+    (call $exact_digits
+      (i64.sub (i64.shl (local.get $m) (i64.const 2))
+        (select (i64.const 1) (i64.const 2)
+          (i32.and
+            (i64.eq (local.get $m) (i64.const 0x10000000000000))
+            (i32.gt_s (local.get $e) (i32.const -1074)))))
+      (i32.sub (local.get $e) (i32.const 2))
+      (global.get $LOW))
+    (call $exact_digits
+      (i64.shl (local.get $m) (i64.const 2))
+      (i32.sub (local.get $e) (i32.const 2))
+      (global.get $MID))
+    (call $exact_digits
+      (i64.add (i64.shl (local.get $m) (i64.const 2)) (i64.const 2))
+      (i32.sub (local.get $e) (i32.const 2))
+      (global.get $HIGH))
+
+    (block $done
+      (loop $next
+        (br_if $done (i32.ne (i32.load8_u (i32.add (global.get $MID) (local.get $first))) (i32.const 48)))
+        (local.set $first (i32.add (local.get $first) (i32.const 1)))
+        (br $next)))
+
+    ;; Round n to more and more digits, until either rounding down or up
+    ;; lands between the halfway points.
+    (local.set $end (local.get $first))
+    (block $found
+      (loop $next
+        (local.set $end (i32.add (local.get $end) (i32.const 1)))
+        (local.set $rest (i32.sub (global.get $DIGITS) (local.get $end)))
+        (local.set $result (global.get $MID))
+        (br_if $found (call $all_zeros (i32.add (global.get $MID) (local.get $end)) (local.get $rest)))
+
+        (local.set $c
+          (call $digits_compare (global.get $MID) (global.get $LOW) (local.get $end)))
+        (if (i32.eqz (local.get $c))
+          (then
+            (local.set $c
+              (i32.sub
+                (call $all_zeros (i32.add (global.get $LOW) (local.get $end)) (local.get $rest))
+                (i32.const 1)))))
+        (local.set $low_ok
+          (i32.or (i32.gt_s (local.get $c) (i32.const 0))
+            (i32.and (i32.eqz (local.get $c)) (local.get $even))))
+
+        (memory.copy (global.get $CANDIDATE) (global.get $MID) (local.get $end))
+        (memory.fill (i32.add (global.get $CANDIDATE) (local.get $end)) (i32.const 48) (local.get $rest))
+        (local.set $p (i32.add (global.get $CANDIDATE) (local.get $end)))
+        (loop $carry
+          (local.set $p (i32.sub (local.get $p) (i32.const 1)))
+          (if (i32.eq (i32.load8_u (local.get $p)) (i32.const 57))
+            (then
+              (i32.store8 (local.get $p) (i32.const 48))
+              (br $carry))))
+        (i32.store8 (local.get $p) (i32.add (i32.load8_u (local.get $p)) (i32.const 1)))
+        (local.set $c
+          (call $digits_compare (global.get $CANDIDATE) (global.get $HIGH) (global.get $DIGITS)))
+        (local.set $high_ok
+          (i32.or (i32.lt_s (local.get $c) (i32.const 0))
+            (i32.and (i32.eqz (local.get $c)) (local.get $even))))
+
+        (if (i32.and (local.get $low_ok) (local.get $high_ok))
+          (then
+            ;; Both work, so take the closer one, rounding halves up like
+            ;; Rust does.
+            (local.set $low_ok
+              (i32.lt_u (i32.load8_u (i32.add (global.get $MID) (local.get $end))) (i32.const 53)))))
+        (br_if $found (local.get $low_ok))
+        (local.set $result (global.get $CANDIDATE))
+        (br_if $found (local.get $high_ok))
+        (br $next)))
+
+    ;; Rounding up can carry into another digit, like 9.99 to 10.0.
+    (if (i32.and
+          (i32.eq (local.get $result) (global.get $CANDIDATE))
+          (i32.ne (i32.load8_u (i32.add (global.get $CANDIDATE) (i32.sub (local.get $first) (i32.const 1))))
+            (i32.const 48)))
+      (then (local.set $first (i32.sub (local.get $first) (i32.const 1)))))
+    (block $done
+      (loop $next
+        (br_if $done
+          (i32.ne (i32.load8_u (i32.add (local.get $result) (i32.sub (local.get $end) (i32.const 1))))
+            (i32.const 48)))
+        (local.set $end (i32.sub (local.get $end) (i32.const 1)))
+        (br $next)))
+    (i32.add (local.get $result) (local.get $first))
+    (i32.sub (local.get $end) (local.get $first))
+    ;; The last digit in the buffer is 10^(e - 2) for small numbers.
+    (i32.sub (i32.sub (global.get $DIGITS) (local.get $first))
+      (select (i32.sub (i32.const 2) (local.get $e)) (i32.const 0)
+        (i32.lt_s (local.get $e) (i32.const 2)))))
+
+  ;; Like Rust's `{}` for f64, which the native runtime uses: the fewest
+  ;; digits that read back as the same number, never in exponent notation.
+  (func $number_to_string (param $n f64) (result i32)
+    (local $a f64)
+    (local $p i32)
+    (local $start i32)
+    (local $len i32)
+    (local $point i32)
+    (local $out i32)
+    (if (f64.ne (local.get $n) (local.get $n)) (then (return (i32.const 320))))
+    (if (f64.eq (local.get $n) (f64.const inf)) (then (return (i32.const 352))))
+    (if (f64.eq (local.get $n) (f64.const -inf)) (then (return (i32.const 384))))
+
+    (local.set $p (i32.const 1536))
+    (if (i64.lt_s (i64.reinterpret_f64 (local.get $n)) (i64.const 0))
+      (then
+        (i32.store8 (local.get $p) (i32.const 45))
+        (local.set $p (i32.add (local.get $p) (i32.const 1)))))
+    (local.set $a (f64.abs (local.get $n)))
+
+    (if (i32.and
+          (f64.eq (local.get $a) (f64.floor (local.get $a)))
+          (f64.lt (local.get $a) (f64.const 9007199254740992)))
+      (then
+        ;; Small integers are common, and easy.
+        (local.set $start
+          (call $write_digits (i64.trunc_f64_u (local.get $a)) (global.get $BIG_A)))
+        (local.set $len (i32.sub (global.get $BIG_A) (local.get $start)))
+        (local.set $point (local.get $len)))
+      (else
+        (call $shortest (local.get $a))
+        (local.set $point)
+        (local.set $len)
+        (local.set $start)))
+
+    (if (i32.le_s (local.get $point) (i32.const 0))
+      (then
+        ;; 0.000ddd
+        (local.set $p (call $fill (local.get $p) (i32.const 48) (i32.const 1)))
+        (local.set $p (call $fill (local.get $p) (i32.const 46) (i32.const 1)))
+        (local.set $p
+          (call $fill (local.get $p) (i32.const 48) (i32.sub (i32.const 0) (local.get $point))))
+        (local.set $p (call $copy (local.get $p) (local.get $start) (local.get $len))))
+      (else
+        (if (i32.ge_s (local.get $point) (local.get $len))
+          (then
+            ;; ddd000
+            (local.set $p (call $copy (local.get $p) (local.get $start) (local.get $len)))
+            (local.set $p
+              (call $fill (local.get $p) (i32.const 48)
+                (i32.sub (local.get $point) (local.get $len)))))
+          (else
+            ;; ddd.ddd
+            (local.set $p (call $copy (local.get $p) (local.get $start) (local.get $point)))
+            (local.set $p (call $fill (local.get $p) (i32.const 46) (i32.const 1)))
+            (local.set $p
+              (call $copy (local.get $p)
+                (i32.add (local.get $start) (local.get $point))
+                (i32.sub (local.get $len) (local.get $point))))))))
+
+    (local.set $len (i32.sub (local.get $p) (i32.const 1536)))
+    (local.set $out (call $alloc (i32.add (local.get $len) (i32.const 1))))
+    (i32.store8 (call $copy (local.get $out) (i32.const 1536) (local.get $len)) (i32.const 0))
+    (local.get $out))
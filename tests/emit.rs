@@ -0,0 +1,107 @@
+use scratchc::{Emit, OptLevel, Options, Target};
+
+fn listing(emit: Emit, target: Target) -> String {
+    let file = std::fs::File::open("tests/out/arguments.sb3").unwrap();
+    let listing = scratchc::compile(
+        file,
+        &Options {
+            emit,
+            target,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    String::from_utf8(listing).unwrap()
+}
+
+#[test]
+fn labels() {
+    for (emit, target, comment) in &[
+        (Emit::Clif, Target::Native, "; "),
+        (Emit::Asm, Target::Native, "; "),
+        (Emit::Asm, Target::Wasm, "  ;; "),
+    ] {
+        let listing = listing(*emit, target.clone());
+        let labels: Vec<_> = listing
+            .lines()
+            .filter_map(|line| line.strip_prefix(comment))
+            .filter(|line| line.starts_with("Sprite1: "))
+            .collect();
+        assert_eq!(
+            labels,
+            ["Sprite1: define greet %s %b", "Sprite1: when flag clicked"],
+            "{:?} for {:?}",
+            emit,
+            target
+        );
+    }
+}
+
+#[test]
+fn debug_info() {
+    let dir = std::env::temp_dir().join("scratchc-debug-info");
+    let file = std::fs::File::open("tests/out/arguments.sb3").unwrap();
+    let object = scratchc::compile(
+        file,
+        &Options {
+            emit: Emit::Obj,
+            debug_info: Some(dir.clone()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let listing = std::fs::read_to_string(dir.join("Sprite1.txt")).unwrap();
+    assert_eq!(listing.lines().next(), Some("when flag clicked"));
+    assert!(listing.contains("\ndefine greet (name) <flag>\nsay (name)\n"));
+    assert!(object.windows(11).any(|w| w == b".debug_line"));
+}
+
+#[test]
+fn guard() {
+    for (opt_level, guard, expected) in &[
+        (OptLevel::None, None, true),
+        (OptLevel::Speed, None, false),
+        (OptLevel::Speed, Some(true), true),
+        (OptLevel::None, Some(false), false),
+    ] {
+        let file = std::fs::File::open("tests/out/arguments.sb3").unwrap();
+        let object = scratchc::compile(
+            file,
+            &Options {
+                opt_level: *opt_level,
+                emit: Emit::Obj,
+                guard: *guard,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // The runtime's functions are only imported when they're called.
+        let symbol = b"support_enter_procedure";
+        assert_eq!(
+            object.windows(symbol.len()).any(|w| w == symbol),
+            *expected,
+            "{:?} with {:?}",
+            opt_level,
+            guard
+        );
+    }
+}
+
+#[test]
+fn unknown_target() {
+    let file = std::fs::File::open("tests/out/arguments.sb3").unwrap();
+    let error = scratchc::compile(
+        file,
+        &Options {
+            target: "mips-unknown-linux-gnu".parse().unwrap(),
+            emit: Emit::Obj,
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(
+        error.starts_with("can't compile for mips-unknown-linux-gnu: "),
+        "{}",
+        error
+    );
+}
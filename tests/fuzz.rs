@@ -0,0 +1,52 @@
+//! Compiles random projects, which runs the Cranelift verifier on every
+//! function, and checks they do the same compiled as interpreted. Set
+//! `SCRATCHC_FUZZ_CASES` to try more of them, and `SCRATCHC_FUZZ_SEED` to
+//! start from another seed, like the one a failure reports.
+
+#[macro_use]
+extern crate pretty_assertions;
+
+mod generate;
+
+use scratchc::OptLevel;
+
+#[test]
+fn fuzz() {
+    let var = |name, default| {
+        std::env::var(name)
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(default)
+    };
+    let seed = var("SCRATCHC_FUZZ_SEED", 0);
+    for seed in seed..seed + var("SCRATCHC_FUZZ_CASES", 10) {
+        let sb3 = std::env::temp_dir().join(format!("fuzz-{}.sb3", seed));
+        std::fs::write(&sb3, generate::sb3(seed)).unwrap();
+        eprintln!("seed {}: {}", seed, sb3.display());
+
+        let mut interpreted = std::process::Command::new(env!("CARGO_BIN_EXE_scratchc"));
+        interpreted.arg("--interpret").arg(&sb3);
+        let expected = interpreted.output().unwrap();
+
+        for opt_level in &[OptLevel::None, OptLevel::Speed] {
+            let exe = format!("{}-{:?}", sb3.with_extension("").display(), opt_level);
+            scratchc::compile_native(
+                std::fs::File::open(&sb3).unwrap(),
+                &exe,
+                &scratchc::Options {
+                    opt_level: *opt_level,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let o = std::process::Command::new(&exe).output().unwrap();
+            assert_eq!(
+                String::from_utf8(o.stdout).unwrap(),
+                String::from_utf8(expected.stdout.clone()).unwrap(),
+                "seed {} at {:?}",
+                seed,
+                opt_level
+            );
+            assert_eq!(o.status.code(), expected.status.code(), "seed {}", seed);
+        }
+    }
+}
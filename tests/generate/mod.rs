@@ -0,0 +1,782 @@
+//! Random, well-formed projects for fuzzing the compiler: a stage and a few
+//! sprites with variables, custom blocks and green flag scripts, built from
+//! nested loops, conditionals and every kind of value the supported blocks
+//! take. Everything they do is deterministic, and they always finish.
+
+use serde_json::{json, Map, Value};
+
+/// The most blocks one statement may run, counting loops and custom blocks,
+/// so that projects finish quickly.
+const MAX_COST: usize = 200;
+
+/// A xorshift generator, so that a seed always gives the same project.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+        rng.next();
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Text for number slots: mostly numbers, but also what people type into
+/// them.
+const NUMBERS: &[&str] = &[
+    "0", "1", "2", "3", "-1", "0.5", "-2.25", "10", "1e2", "100", "", "1.50", "abc",
+];
+
+/// Text for text slots, including text that looks like numbers or booleans.
+const STRINGS: &[&str] = &[
+    "", "hello", "Hello", "10", "1.50", " 3 ", "-0", "true", "false", "Infinity", "NaN", "0x10",
+    "a b",
+];
+
+/// The project.json of a random project.
+fn project(seed: u64) -> Value {
+    let mut rng = Rng::new(seed);
+    let mut targets = vec![];
+    let mut globals = vec![];
+    for t in 0..1 + rng.below(3) {
+        let is_stage = t == 0;
+        let name = if is_stage {
+            "Stage".to_owned()
+        } else {
+            format!("Sprite{}", t)
+        };
+        let mut g = Generator {
+            rng: &mut rng,
+            is_stage,
+            blocks: Map::new(),
+            next_id: 0,
+            variables: Map::new(),
+            visible: globals.clone(),
+            procedures: vec![],
+            args: vec![],
+        };
+        for _ in 0..1 + g.rng.below(3) {
+            let id = format!("{}_var{}", name, g.variables.len());
+            let initial = g.initial_value();
+            g.variables.insert(
+                id.clone(),
+                json!([format!("{} var {}", name, g.variables.len()), initial]),
+            );
+            g.visible.push(id);
+        }
+        if is_stage {
+            globals = g.visible.clone();
+        }
+        for _ in 0..g.rng.below(4) {
+            g.procedure();
+        }
+        for _ in 0..1 + g.rng.below(3) {
+            g.script();
+        }
+
+        let costumes: Vec<_> = (1..=2)
+            .map(|c| {
+                json!({
+                    "assetId": "blank",
+                    "name": format!("{}{}", if is_stage { "backdrop" } else { "costume" }, c),
+                    "md5ext": "blank.svg",
+                    "dataFormat": "svg",
+                    "rotationCenterX": 0,
+                    "rotationCenterY": 0,
+                })
+            })
+            .collect();
+        let mut target = json!({
+            "isStage": is_stage,
+            "name": name,
+            "variables": g.variables,
+            "lists": {},
+            "broadcasts": {},
+            "blocks": g.blocks,
+            "comments": {},
+            "currentCostume": 0,
+            "costumes": costumes,
+            "sounds": [],
+            "volume": 100,
+            "layerOrder": t,
+        });
+        if !is_stage {
+            let sprite = json!({
+                "visible": true,
+                "x": 0,
+                "y": 0,
+                "size": 100,
+                "direction": 90,
+                "draggable": false,
+                "rotationStyle": "all around",
+            });
+            for (k, v) in sprite.as_object().unwrap() {
+                target[k] = v.clone();
+            }
+        }
+        targets.push(target);
+    }
+    json!({
+        "targets": targets,
+        "monitors": [],
+        "extensions": [],
+        "meta": { "semver": "3.0.0", "vm": "0.2.0", "agent": "" },
+    })
+}
+
+/// A random project as an .sb3 file.
+pub fn sb3(seed: u64) -> Vec<u8> {
+    let mut out = std::io::Cursor::new(vec![]);
+    let mut zip = zip::ZipWriter::new(&mut out);
+    zip.start_file("project.json", Default::default()).unwrap();
+    std::io::Write::write_all(&mut zip, project(seed).to_string().as_bytes()).unwrap();
+    zip.finish().unwrap();
+    drop(zip);
+    out.into_inner()
+}
+
+struct Procedure {
+    proccode: String,
+    /// IDs, and whether they're booleans.
+    args: Vec<(String, bool)>,
+    warp: bool,
+    /// The most blocks a call runs.
+    cost: usize,
+}
+
+struct Generator<'a> {
+    rng: &'a mut Rng,
+    is_stage: bool,
+    blocks: Map<String, Value>,
+    next_id: usize,
+    variables: Map<String, Value>,
+    /// Variables this target can use, by ID.
+    visible: Vec<String>,
+    /// Custom blocks defined so far, which are the ones the next can call, so
+    /// none of them recurse.
+    procedures: Vec<Procedure>,
+    /// The arguments of the custom block being defined: names, and whether
+    /// they're booleans.
+    args: Vec<(String, bool)>,
+}
+
+impl Generator<'_> {
+    fn initial_value(&mut self) -> Value {
+        match self.rng.below(4) {
+            0 => json!(0),
+            1 => json!(self.rng.below(10) as f64 / 2.0),
+            _ => json!(self.rng.pick(STRINGS)),
+        }
+    }
+
+    fn block(&mut self, opcode: &str, inputs: Value, fields: Value) -> String {
+        let id = format!("b{}", self.next_id);
+        self.next_id += 1;
+        self.blocks.insert(
+            id.clone(),
+            json!({
+                "opcode": opcode,
+                "next": null,
+                "parent": null,
+                "inputs": inputs,
+                "fields": fields,
+                "shadow": false,
+                "topLevel": false,
+            }),
+        );
+        id
+    }
+
+    fn set(&mut self, id: &str, key: &str, value: Value) {
+        self.blocks[id][key] = value;
+    }
+
+    fn script(&mut self) {
+        let hat = self.block("event_whenflagclicked", json!({}), json!({}));
+        self.set(&hat, "topLevel", json!(true));
+        self.set(&hat, "x", json!(0));
+        self.set(&hat, "y", json!(0));
+        self.args.clear();
+        let (body, _) = self.stack(1, 0);
+        self.set(&hat, "next", json!(body));
+        self.set(&body, "parent", json!(hat));
+    }
+
+    fn procedure(&mut self) {
+        let n = self.procedures.len();
+        let mut proccode = format!("{} {}", if self.is_stage { "stage" } else { "block" }, n);
+        let mut ids = vec![];
+        self.args.clear();
+        for i in 0..self.rng.below(4) {
+            let boolean = self.rng.chance(30);
+            proccode.push_str(if boolean { " %b" } else { " %s" });
+            ids.push((format!("proc{}_arg{}", n, i), boolean));
+            self.args.push((format!("arg {}", i), boolean));
+        }
+        let warp = self.rng.chance(30);
+
+        let prototype = self.block("procedures_prototype", json!({}), json!({}));
+        self.set(&prototype, "shadow", json!(true));
+        let names: Vec<_> = self.args.iter().map(|(name, _)| name.clone()).collect();
+        let defaults: Vec<_> = ids.iter().map(|_| "").collect();
+        self.set(
+            &prototype,
+            "mutation",
+            json!({
+                "tagName": "mutation",
+                "children": [],
+                "proccode": proccode,
+                "argumentids": json!(ids.iter().map(|(id, _)| id).collect::<Vec<_>>()).to_string(),
+                "argumentnames": json!(names).to_string(),
+                "argumentdefaults": json!(defaults).to_string(),
+                "warp": warp.to_string(),
+            }),
+        );
+        let definition = self.block(
+            "procedures_definition",
+            json!({ "custom_block": [1, prototype] }),
+            json!({}),
+        );
+        self.set(&definition, "topLevel", json!(true));
+        self.set(&definition, "x", json!(0));
+        self.set(&definition, "y", json!(0));
+        self.set(&prototype, "parent", json!(definition));
+        let (body, cost) = self.stack(1, 0);
+        self.set(&definition, "next", json!(body));
+        self.set(&body, "parent", json!(definition));
+        self.args.clear();
+
+        self.procedures.push(Procedure {
+            proccode,
+            args: ids,
+            warp,
+            cost: cost + 1,
+        });
+    }
+
+    /// A stack of blocks, run `times` times over by the loops around it, and
+    /// the most blocks it runs each time.
+    fn stack(&mut self, times: usize, depth: usize) -> (String, usize) {
+        let len = if depth == 0 {
+            2 + self.rng.below(6)
+        } else {
+            1 + self.rng.below(3)
+        };
+        let mut first = None;
+        let mut last: Option<String> = None;
+        let mut cost = 0;
+        for i in 0..len {
+            let (id, c) = self.statement(times, depth);
+            cost += c;
+            if let Some(last) = &last {
+                self.set(last, "next", json!(id));
+                self.set(&id, "parent", json!(last));
+            } else {
+                first = Some(id.clone());
+            }
+            let tail = self.last(&id);
+            last = Some(tail.clone());
+            // Nothing can follow a forever, or stopping.
+            if self.blocks[&tail]["opcode"] == "control_forever" {
+                break;
+            }
+            if i == len - 1 && self.rng.chance(5) {
+                let option = if self.rng.chance(10) {
+                    "all"
+                } else {
+                    "this script"
+                };
+                let id = self.block(
+                    "control_stop",
+                    json!({}),
+                    json!({ "STOP_OPTION": [option, null] }),
+                );
+                self.set(&tail, "next", json!(id));
+                self.set(&id, "parent", json!(tail));
+                cost += 1;
+            }
+        }
+        (first.unwrap(), cost)
+    }
+
+    /// A statement, and the most blocks it runs.
+    fn statement(&mut self, times: usize, depth: usize) -> (String, usize) {
+        let nested = depth < 3;
+        loop {
+            match self.rng.below(16) {
+                0 | 1 => {
+                    let opcode = if self.rng.chance(80) {
+                        "looks_say"
+                    } else {
+                        "looks_think"
+                    };
+                    let message = self.text();
+                    let id = self.block(opcode, json!({ "MESSAGE": message }), json!({}));
+                    return (self.parent(&id), 1);
+                }
+                2 | 3 => {
+                    let variable = self.variable_field();
+                    let value = self.stored();
+                    let id = self.block(
+                        "data_setvariableto",
+                        json!({ "VALUE": value }),
+                        json!({ "VARIABLE": variable }),
+                    );
+                    return (self.parent(&id), 1);
+                }
+                4 => {
+                    let variable = self.variable_field();
+                    let value = self.number();
+                    let id = self.block(
+                        "data_changevariableby",
+                        json!({ "VALUE": value }),
+                        json!({ "VARIABLE": variable }),
+                    );
+                    return (self.parent(&id), 1);
+                }
+                5 if nested => {
+                    // Counts are rounded like Math.round, and less than one,
+                    // or NaN, is none.
+                    let n = self.rng.below(5);
+                    let (count, runs) = match self.rng.below(8) {
+                        0 => (format!("{}.4", n), n),
+                        1 => (format!("{}.5", n), n + 1),
+                        2 => (format!("-{}.5", n), 0),
+                        3 => ("-2".to_owned(), 0),
+                        // Infinity minus Infinity is NaN.
+                        4 => ("Infinity".to_owned(), 0),
+                        _ => (n.to_string(), n),
+                    };
+                    if times * runs.max(1) > MAX_COST {
+                        continue;
+                    }
+                    let (body, cost) = self.stack(times * runs.max(1), depth + 1);
+                    // The count comes from a literal, a variable, or
+                    // arithmetic on either.
+                    let mut setup = None;
+                    let mut operand = |g: &mut Self, value: String| {
+                        if g.rng.chance(50) {
+                            return json!([1, [4, value]]);
+                        }
+                        let name = format!("count{}", g.next_id);
+                        g.variables.insert(name.clone(), json!([name.clone(), 0]));
+                        let set = g.block(
+                            "data_setvariableto",
+                            json!({ "VALUE": [1, [10, value]] }),
+                            json!({ "VARIABLE": [name.clone(), name.clone()] }),
+                        );
+                        if let Some(previous) = setup.replace(set.clone()) {
+                            g.set(&previous, "next", json!(set));
+                            g.set(&set, "parent", json!(previous));
+                        }
+                        json!([3, [12, name.clone(), name], [4, ""]])
+                    };
+                    let input = if count == "Infinity" || self.rng.chance(50) {
+                        let k = if count == "Infinity" {
+                            count.clone()
+                        } else {
+                            self.rng.below(3).to_string()
+                        };
+                        let total = if count == "Infinity" {
+                            count.clone()
+                        } else {
+                            (count.parse::<f64>().unwrap() + k.parse::<f64>().unwrap()).to_string()
+                        };
+                        let left = operand(self, total);
+                        let right = operand(self, k);
+                        let id = self.block(
+                            "operator_subtract",
+                            json!({ "NUM1": left, "NUM2": right }),
+                            json!({}),
+                        );
+                        json!([3, id, [6, ""]])
+                    } else {
+                        operand(self, count)
+                    };
+                    let id = self.block(
+                        "control_repeat",
+                        json!({ "TIMES": input, "SUBSTACK": [2, body] }),
+                        json!({}),
+                    );
+                    self.reparent(&id);
+                    if let Some(id) = input[1].as_str() {
+                        self.reparent(id);
+                    }
+                    return match setup {
+                        Some(last) => {
+                            self.set(&last, "next", json!(id));
+                            self.set(&id, "parent", json!(last));
+                            (self.first(&last), 1 + runs.max(1) * cost + 2)
+                        }
+                        None => (id, 1 + runs.max(1) * cost),
+                    };
+                }
+                6 if nested => {
+                    // Forever, until a counter runs out.
+                    let n = 1 + self.rng.below(4);
+                    if times * n > MAX_COST {
+                        continue;
+                    }
+                    let counter = format!("loop{}", self.next_id);
+                    self.variables
+                        .insert(counter.clone(), json!([counter.clone(), 0]));
+                    let field = json!([counter.clone(), counter.clone()]);
+                    let reset = self.block(
+                        "data_setvariableto",
+                        json!({ "VALUE": [1, [10, "0"]] }),
+                        json!({ "VARIABLE": field }),
+                    );
+                    let count = self.block(
+                        "data_changevariableby",
+                        json!({ "VALUE": [1, [4, "1"]] }),
+                        json!({ "VARIABLE": field }),
+                    );
+                    let (body, cost) = self.stack(times * n, depth + 1);
+                    let done = self.block(
+                        "operator_gt",
+                        json!({
+                            "OPERAND1": [3, [12, counter.clone(), counter], [10, ""]],
+                            "OPERAND2": [1, [10, n.to_string()]],
+                        }),
+                        json!({}),
+                    );
+                    let stop = self.block(
+                        "control_stop",
+                        json!({}),
+                        json!({ "STOP_OPTION": ["this script", null] }),
+                    );
+                    let check = self.block(
+                        "control_if",
+                        json!({ "CONDITION": [2, done], "SUBSTACK": [2, stop] }),
+                        json!({}),
+                    );
+                    self.set(&done, "parent", json!(check));
+                    self.set(&stop, "parent", json!(check));
+                    self.set(&count, "next", json!(body));
+                    self.set(&body, "parent", json!(count));
+                    let last = self.last(&body);
+                    // Unless the body already ends the script.
+                    let opcode = &self.blocks[&last]["opcode"];
+                    if opcode != "control_stop" && opcode != "control_forever" {
+                        self.set(&last, "next", json!(check));
+                        self.set(&check, "parent", json!(last));
+                    }
+                    let forever = self.block(
+                        "control_forever",
+                        json!({ "SUBSTACK": [2, count] }),
+                        json!({}),
+                    );
+                    self.set(&count, "parent", json!(forever));
+                    self.set(&reset, "next", json!(forever));
+                    self.set(&forever, "parent", json!(reset));
+                    return (self.parent(&reset), 2 + (n + 1) * (cost + 2));
+                }
+                7 | 8 if nested => {
+                    let condition = self.condition();
+                    let (consequent, a) = self.stack(times, depth + 1);
+                    let mut inputs = json!({ "SUBSTACK": [2, consequent] });
+                    if let Some(condition) = condition {
+                        inputs["CONDITION"] = condition;
+                    }
+                    let (opcode, b) = if self.rng.chance(50) {
+                        let (alternative, b) = self.stack(times, depth + 1);
+                        inputs["SUBSTACK2"] = json!([2, alternative]);
+                        ("control_if_else", b)
+                    } else {
+                        ("control_if", 0)
+                    };
+                    let id = self.block(opcode, inputs, json!({}));
+                    return (self.parent(&id), 1 + a.max(b));
+                }
+                9 | 10 if !self.procedures.is_empty() => {
+                    let p = self.rng.below(self.procedures.len());
+                    let cost = self.procedures[p].cost;
+                    if times * cost > MAX_COST {
+                        continue;
+                    }
+                    let ids = self.procedures[p].args.clone();
+                    let mut inputs = Map::new();
+                    for (id, boolean) in &ids {
+                        let value = if *boolean {
+                            match self.condition() {
+                                Some(condition) => condition,
+                                // An empty slot.
+                                None => continue,
+                            }
+                        } else {
+                            self.stored()
+                        };
+                        inputs.insert(id.clone(), value);
+                    }
+                    let id = self.block("procedures_call", Value::Object(inputs), json!({}));
+                    let p = &self.procedures[p];
+                    let mutation = json!({
+                        "tagName": "mutation",
+                        "children": [],
+                        "proccode": p.proccode,
+                        "argumentids": json!(p.args.iter().map(|(id, _)| id).collect::<Vec<_>>()).to_string(),
+                        "warp": p.warp.to_string(),
+                    });
+                    self.set(&id, "mutation", mutation);
+                    self.reparent(&id);
+                    return (id, 1 + cost);
+                }
+                11 | 12 if !self.is_stage => {
+                    let (opcode, inputs) = match self.rng.below(6) {
+                        0 => (
+                            "motion_gotoxy",
+                            json!({ "X": self.number(), "Y": self.number() }),
+                        ),
+                        1 => ("motion_changexby", json!({ "DX": self.number() })),
+                        2 => ("motion_sety", json!({ "Y": self.number() })),
+                        3 => ("motion_turnright", json!({ "DEGREES": self.number() })),
+                        4 => (
+                            "motion_pointindirection",
+                            json!({ "DIRECTION": self.number() }),
+                        ),
+                        _ => ("motion_movesteps", json!({ "STEPS": self.number() })),
+                    };
+                    let id = self.block(opcode, inputs, json!({}));
+                    return (self.parent(&id), 1);
+                }
+                13 | 14 => {
+                    let (opcode, inputs, fields) = match self.rng.below(5) {
+                        0 if !self.is_stage => (
+                            "looks_changesizeby",
+                            json!({ "CHANGE": self.number() }),
+                            json!({}),
+                        ),
+                        1 if !self.is_stage => (
+                            "looks_switchcostumeto",
+                            json!({ "COSTUME": self.text() }),
+                            json!({}),
+                        ),
+                        2 => (
+                            "looks_changeeffectby",
+                            json!({ "CHANGE": self.number() }),
+                            json!({ "EFFECT": [*self.rng.pick(&["COLOR", "GHOST"]), null] }),
+                        ),
+                        3 if !self.is_stage => ("looks_nextcostume", json!({}), json!({})),
+                        _ => ("looks_nextbackdrop", json!({}), json!({})),
+                    };
+                    let id = self.block(opcode, inputs, fields);
+                    return (self.parent(&id), 1);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The last block in the stack starting at `id`.
+    fn last(&self, id: &str) -> String {
+        let mut last = id.to_owned();
+        while let Some(next) = self.blocks[&last]["next"].as_str() {
+            last = next.to_owned();
+        }
+        last
+    }
+
+    /// The first block in the stack ending at `id`.
+    fn first(&self, id: &str) -> String {
+        let mut first = id.to_owned();
+        while let Some(parent) = self.blocks[&first]["parent"].as_str() {
+            first = parent.to_owned();
+        }
+        first
+    }
+
+    /// Point the blocks in `id`'s inputs back at it.
+    fn parent(&mut self, id: &str) -> String {
+        self.reparent(id);
+        id.to_owned()
+    }
+
+    fn reparent(&mut self, id: &str) {
+        let inputs = self.blocks[id]["inputs"].clone();
+        for input in inputs.as_object().unwrap().values() {
+            if let Some(child) = input[1].as_str() {
+                self.set(child, "parent", json!(id));
+            }
+        }
+    }
+
+    fn variable_field(&mut self) -> Value {
+        let id = self.rng.pick(&self.visible).clone();
+        json!([self.name(&id), id])
+    }
+
+    fn name(&self, id: &str) -> String {
+        // Globals aren't in this target's variables, but they're named after
+        // their ID the same way.
+        match self.variables.get(id) {
+            Some(v) => v[0].as_str().unwrap().to_owned(),
+            None => id.replace("_var", " var "),
+        }
+    }
+
+    /// An input for a number slot.
+    fn number(&mut self) -> Value {
+        match self.reporter(0, true) {
+            Some(id) => json!([3, id, [4, "0"]]),
+            None => json!([1, [4, self.rng.pick(NUMBERS)]]),
+        }
+    }
+
+    /// An input for a text slot.
+    fn text(&mut self) -> Value {
+        self.text_or_boolean(true)
+    }
+
+    /// An input for a text slot that keeps its value, in a variable or an
+    /// argument. Scratch keeps booleans as they are there, so that true is 1
+    /// as a number, but compiled projects only keep the text "true", so
+    /// they're left out.
+    fn stored(&mut self) -> Value {
+        self.text_or_boolean(false)
+    }
+
+    fn text_or_boolean(&mut self, booleans: bool) -> Value {
+        match self.reporter(0, booleans) {
+            Some(id) => json!([3, id, [10, ""]]),
+            None => json!([1, [10, self.rng.pick(STRINGS)]]),
+        }
+    }
+
+    /// An input for a boolean slot, or none for an empty one.
+    fn condition(&mut self) -> Option<Value> {
+        if self.rng.chance(5) {
+            return None;
+        }
+        let id = self.boolean(0);
+        Some(json!([2, id]))
+    }
+
+    /// A reporter block, or none for a literal. Variables are saved inline,
+    /// as an ID that's actually an array.
+    fn reporter(&mut self, depth: usize, booleans: bool) -> Option<Value> {
+        let chance = if depth == 0 { 50 } else { 25 };
+        if !self.rng.chance(chance) {
+            return None;
+        }
+        let string_args: Vec<_> = self
+            .args
+            .iter()
+            .filter(|(_, boolean)| !boolean)
+            .map(|(name, _)| name.clone())
+            .collect();
+        loop {
+            let id = match self.rng.below(9) {
+                0 | 1 => {
+                    let id = self.rng.pick(&self.visible).clone();
+                    return Some(json!([12, self.name(&id), id]));
+                }
+                2 | 3 if depth < 2 => {
+                    let opcode = *self.rng.pick(&["operator_add", "operator_subtract"]);
+                    let left = self.nested_number(depth);
+                    let right = self.nested_number(depth);
+                    self.block(opcode, json!({ "NUM1": left, "NUM2": right }), json!({}))
+                }
+                4 if depth < 2 && booleans => self.boolean(depth + 1),
+                5 if !string_args.is_empty() => {
+                    let name = self.rng.pick(&string_args).clone();
+                    self.block(
+                        "argument_reporter_string_number",
+                        json!({}),
+                        json!({ "VALUE": [name, null] }),
+                    )
+                }
+                6 if !self.is_stage => {
+                    let opcode = *self.rng.pick(&[
+                        "motion_xposition",
+                        "motion_yposition",
+                        "motion_direction",
+                        "looks_size",
+                    ]);
+                    self.block(opcode, json!({}), json!({}))
+                }
+                7 if !self.is_stage => {
+                    let field = *self.rng.pick(&["number", "name"]);
+                    self.block(
+                        "looks_costumenumbername",
+                        json!({}),
+                        json!({ "NUMBER_NAME": [field, null] }),
+                    )
+                }
+                8 => {
+                    let field = *self.rng.pick(&["number", "name"]);
+                    self.block(
+                        "looks_backdropnumbername",
+                        json!({}),
+                        json!({ "NUMBER_NAME": [field, null] }),
+                    )
+                }
+                _ => continue,
+            };
+            self.reparent(&id);
+            return Some(json!(id));
+        }
+    }
+
+    fn nested_number(&mut self, depth: usize) -> Value {
+        match self.reporter(depth + 1, true) {
+            Some(id) => json!([3, id, [4, "0"]]),
+            None => json!([1, [4, self.rng.pick(NUMBERS)]]),
+        }
+    }
+
+    fn nested_text(&mut self, depth: usize) -> Value {
+        match self.reporter(depth + 1, true) {
+            Some(id) => json!([3, id, [10, ""]]),
+            None => json!([1, [10, self.rng.pick(STRINGS)]]),
+        }
+    }
+
+    /// A boolean reporter block.
+    fn boolean(&mut self, depth: usize) -> String {
+        let boolean_args: Vec<_> = self
+            .args
+            .iter()
+            .filter(|(_, boolean)| *boolean)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let id = if !boolean_args.is_empty() && self.rng.chance(30) {
+            let name = self.rng.pick(&boolean_args).clone();
+            self.block(
+                "argument_reporter_boolean",
+                json!({}),
+                json!({ "VALUE": [name, null] }),
+            )
+        } else {
+            let opcode = *self.rng.pick(&["operator_equals", "operator_gt"]);
+            let left = self.nested_text(depth);
+            let right = self.nested_text(depth);
+            self.block(
+                opcode,
+                json!({ "OPERAND1": left, "OPERAND2": right }),
+                json!({}),
+            )
+        };
+        self.reparent(&id);
+        id
+    }
+}
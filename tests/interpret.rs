@@ -0,0 +1,75 @@
+#[macro_use]
+extern crate pretty_assertions;
+
+/// Every project must do the same whether it's compiled or interpreted: the
+/// interpreter keeps Scratch's dynamic values, so this catches the compiler
+/// getting a type or conversion wrong.
+#[test_generator::test_resources("tests/out/*.sb3")]
+fn test(test: &str) {
+    let test = std::path::PathBuf::from(test);
+    let tmp = std::env::temp_dir()
+        .join(format!(
+            "{}-compiled",
+            test.file_name().unwrap().to_str().unwrap()
+        ))
+        .to_str()
+        .unwrap()
+        .to_owned();
+    // The interpreter always limits how deep custom blocks nest.
+    scratchc::compile_native(
+        std::fs::File::open(&test).unwrap(),
+        &tmp,
+        &scratchc::Options {
+            guard: Some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let compiled = run(&test, std::process::Command::new(&tmp));
+    let mut interpreted = std::process::Command::new(env!("CARGO_BIN_EXE_scratchc"));
+    interpreted.arg("--interpret").arg(&test);
+    let interpreted = run(&test, interpreted);
+
+    assert_eq!(
+        String::from_utf8(interpreted.stdout).unwrap(),
+        String::from_utf8(compiled.stdout).unwrap()
+    );
+    // The interpreter loads the project as it starts, so it also repeats
+    // the warnings scratchc gives when compiling.
+    let stderr = String::from_utf8(interpreted.stderr).unwrap();
+    let stderr: String = stderr
+        .split_inclusive('\n')
+        .filter(|l| !l.starts_with("warning: "))
+        .collect();
+    assert_eq!(stderr, String::from_utf8(compiled.stderr).unwrap());
+    assert_eq!(interpreted.status.code(), compiled.status.code());
+}
+
+fn run(test: &std::path::Path, mut command: std::process::Command) -> std::process::Output {
+    let env = test.with_extension("env");
+    if env.exists() {
+        for line in std::fs::read_to_string(&env).unwrap().lines() {
+            let mut parts = line.splitn(2, '=');
+            command.env(parts.next().unwrap(), parts.next().unwrap());
+        }
+    }
+
+    let input = test.with_extension("in");
+    if input.exists() {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(
+            child.stdin.as_mut().unwrap(),
+            &std::fs::read(&input).unwrap(),
+        )
+        .unwrap();
+        child.wait_with_output().unwrap()
+    } else {
+        command.output().unwrap()
+    }
+}
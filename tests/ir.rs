@@ -0,0 +1,12 @@
+#[macro_use]
+extern crate pretty_assertions;
+
+#[test_generator::test_resources("tests/ir/*.ir")]
+fn test(test: &str) {
+    let test = std::path::PathBuf::from(test);
+    let mut project = std::path::PathBuf::from("tests/out").join(test.file_name().unwrap());
+    project.set_extension("sb3");
+    let file = std::fs::File::open(&project).unwrap();
+
+    assert_eq!(scratchc::ir(file), std::fs::read_to_string(&test).unwrap());
+}
@@ -1,27 +1,149 @@
 #[macro_use]
 extern crate pretty_assertions;
 
+use scratchc::{OptLevel, Target};
+
 #[test_generator::test_resources("tests/out/*.sb3")]
 fn test(test: &str) {
+    // Optimizing must never change what a project does.
+    for opt_level in &[OptLevel::None, OptLevel::Speed, OptLevel::Size] {
+        run(test, *opt_level, None, false);
+    }
+
+    // Projects with a .trace also check the blocks they run, in order.
+    if std::path::Path::new(test).with_extension("trace").exists() {
+        run(test, OptLevel::Speed, None, true);
+    }
+
+    // Other targets are run under qemu-user, when SCRATCHC_TEST_TARGETS lists
+    // them and scratchc was built with their runtimes in SCRATCHC_TARGETS.
+    if let Ok(triples) = std::env::var("SCRATCHC_TEST_TARGETS") {
+        for triple in triples.split(',').filter(|t| !t.is_empty()) {
+            run(test, OptLevel::Speed, Some(triple), false);
+        }
+    }
+}
+
+fn run(test: &str, opt_level: OptLevel, triple: Option<&str>, trace: bool) {
     let test = std::path::PathBuf::from(test);
     let file = std::fs::File::open(&test).unwrap();
 
     let tmp = std::env::temp_dir()
-        .join(test.file_name().unwrap())
+        .join(format!(
+            "{}-{:?}{}{}",
+            test.file_name().unwrap().to_str().unwrap(),
+            opt_level,
+            triple.map(|t| format!("-{}", t)).unwrap_or_default(),
+            if trace { "-trace" } else { "" }
+        ))
         .to_str()
         .unwrap()
         .to_owned();
 
-    scratchc::compile_native(file, &tmp);
+    let target = match triple {
+        Some(triple) => Target::Cross(triple.parse().unwrap()),
+        None => Target::Native,
+    };
+    scratchc::compile_native(
+        file,
+        &tmp,
+        &scratchc::Options {
+            opt_level,
+            target,
+            trace,
+            // Projects that stop with an error rely on the guard, which is
+            // otherwise only on without optimizations.
+            guard: test.with_extension("err").exists().then_some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut command = match triple {
+        Some(triple) => {
+            let arch = triple.split('-').next().unwrap();
+            let mut command = std::process::Command::new(format!("qemu-{}", arch));
+            command.arg(&tmp);
+            command
+        }
+        None => std::process::Command::new(&tmp),
+    };
 
-    let o = std::process::Command::new(&tmp).output().unwrap();
+    let mut env = test.clone();
+    env.set_extension("env");
+    if env.exists() {
+        for line in std::fs::read_to_string(&env).unwrap().lines() {
+            let mut parts = line.splitn(2, '=');
+            command.env(parts.next().unwrap(), parts.next().unwrap());
+        }
+    }
 
-    assert!(o.status.success());
+    let trace_log = format!("{}.log", tmp);
+    if trace {
+        command.env("SCRATCHC_TRACE", &trace_log);
+    }
 
-    let mut out = test;
+    // Projects with a .wav or .mid also check the audio they render.
+    let rendered: Vec<_> = [("wav", "SCRATCHC_AUDIO"), ("mid", "SCRATCHC_MIDI")]
+        .iter()
+        .filter(|(extension, _)| test.with_extension(extension).exists())
+        .map(|(extension, var)| {
+            let path = format!("{}.{}", tmp, extension);
+            command.env(var, &path);
+            (path, test.with_extension(extension))
+        })
+        .collect();
+
+    let mut input = test.clone();
+    input.set_extension("in");
+    let o = if input.exists() {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(
+            child.stdin.as_mut().unwrap(),
+            &std::fs::read(&input).unwrap(),
+        )
+        .unwrap();
+        child.wait_with_output().unwrap()
+    } else {
+        command.output().unwrap()
+    };
+
+    let mut err = test.clone();
+    err.set_extension("err");
+    if err.exists() {
+        assert!(!o.status.success());
+        assert_eq!(
+            String::from_utf8(o.stderr).unwrap(),
+            std::fs::read_to_string(&err).unwrap()
+        );
+    } else {
+        assert!(o.status.success());
+    }
+
+    let mut out = test.clone();
     out.set_extension("out");
     assert_eq!(
         String::from_utf8(o.stdout).unwrap(),
         std::fs::read_to_string(&out).unwrap()
     );
+
+    if trace {
+        assert_eq!(
+            std::fs::read_to_string(&trace_log).unwrap(),
+            std::fs::read_to_string(test.with_extension("trace")).unwrap()
+        );
+    }
+
+    for (path, expected) in rendered {
+        assert!(
+            std::fs::read(&path).unwrap() == std::fs::read(&expected).unwrap(),
+            "{} differs from {}",
+            path,
+            expected.display()
+        );
+    }
 }
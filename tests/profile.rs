@@ -0,0 +1,65 @@
+//! Runs a project compiled with `--profile` and checks what the report counted.
+
+#[test]
+fn arguments() {
+    let file = std::fs::File::open("tests/out/arguments.sb3").unwrap();
+    let exe = std::env::temp_dir()
+        .join("arguments.sb3-profile")
+        .to_str()
+        .unwrap()
+        .to_owned();
+    scratchc::compile_native(
+        file,
+        &exe,
+        &scratchc::Options {
+            profile: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let folded = format!("{}.folded", exe);
+    let o = std::process::Command::new(&exe)
+        .env("SCRATCHC_PROFILE", &folded)
+        .output()
+        .unwrap();
+    assert!(o.status.success());
+    assert_eq!(
+        String::from_utf8(o.stdout).unwrap(),
+        std::fs::read_to_string("tests/out/arguments.out").unwrap()
+    );
+
+    // Times vary, so only the counts are checked.
+    let report = String::from_utf8(o.stderr).unwrap();
+    let count = |name: &str| -> u64 {
+        let row = report
+            .lines()
+            .find(|line| line.starts_with(name))
+            .unwrap_or_else(|| panic!("no {:?} in\n{}", name, report));
+        row.split_whitespace()
+            .rev()
+            .nth(1)
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(count("Sprite1: greet %s %b "), 3);
+    assert_eq!(count("Sprite1: procedures_definition b1 "), 3);
+    assert_eq!(count("Sprite1: looks_say b7 "), 2);
+    assert_eq!(count("Sprite1: event_whenflagclicked b11 "), 1);
+
+    let stacks: Vec<_> = std::fs::read_to_string(&folded)
+        .unwrap()
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0.to_owned())
+        .collect();
+    let script = "Sprite1: when flag clicked (b11)";
+    for stack in &[
+        format!("{};event_whenflagclicked b11", script),
+        format!("{};procedures_call b12", script),
+        format!("{};Sprite1: greet %s %b;procedures_definition b1", script),
+        format!("{};Sprite1: greet %s %b;looks_say b6", script),
+    ] {
+        assert!(stacks.contains(stack), "no {:?} in {:#?}", stack, stacks);
+    }
+}
@@ -0,0 +1,24 @@
+//! Compiling the same project twice gives the same bytes. The compiles run
+//! in separate processes, so that nothing depends on the order of a map
+//! that's seeded differently every time.
+
+#[test_generator::test_resources("tests/out/*.sb3")]
+fn test(test: &str) {
+    let compile = |n| {
+        let name = std::path::Path::new(test)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let out = std::env::temp_dir().join(format!("{}-reproducible-{}.o", name, n));
+        let status = std::process::Command::new(env!("CARGO_BIN_EXE_scratchc"))
+            .arg("--emit=obj")
+            .arg(test)
+            .arg(&out)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::read(&out).unwrap()
+    };
+    assert!(compile(1) == compile(2), "{} compiled differently", test);
+}
@@ -0,0 +1,436 @@
+//! Runs the projects in tests/out that only use what the WebAssembly runtime
+//! supports, compiled to WebAssembly, with just enough of WASI for them.
+
+#[macro_use]
+extern crate pretty_assertions;
+
+use serde_json::{json, Value};
+use std::convert::TryInto;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+macro_rules! tests {
+    ($($name:ident),*) => {
+        $(
+            #[test]
+            fn $name() {
+                run(stringify!($name), false);
+                if std::path::Path::new(concat!("tests/out/", stringify!($name), ".trace")).exists() {
+                    run(stringify!($name), true);
+                }
+            }
+        )*
+    };
+}
+
+tests!(
+    arguments,
+    dialogue,
+    fib_10_iter,
+    fib_10_recr,
+    forever_stop,
+    loop_if,
+    multiscript,
+    namespaces,
+    nan,
+    reachability,
+    recursion_limit,
+    repeat_counts,
+    shared_variables,
+    stop_all,
+    stop_script,
+    strings,
+    variables,
+    warp
+);
+
+/// A project whose sprite runs `blocks` one after another, starting with a hat.
+fn project(blocks: &[Value]) -> Vec<u8> {
+    let mut script = serde_json::Map::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let mut block = block.clone();
+        block["parent"] = if i == 0 {
+            json!(null)
+        } else {
+            json!((i - 1).to_string())
+        };
+        block["next"] = if i + 1 < blocks.len() {
+            json!((i + 1).to_string())
+        } else {
+            json!(null)
+        };
+        block["topLevel"] = json!(i == 0);
+        block["shadow"] = json!(false);
+        script.insert(i.to_string(), block);
+    }
+    let target = |name: &str, costume: &str, blocks: Value| {
+        json!({
+            "isStage": name == "Stage",
+            "name": name,
+            "variables": {},
+            "lists": {},
+            "broadcasts": {},
+            "blocks": blocks,
+            "comments": {},
+            "currentCostume": 0,
+            "costumes": [{
+                "assetId": "blank",
+                "name": costume,
+                "md5ext": "blank.svg",
+                "dataFormat": "svg",
+                "rotationCenterX": 0,
+                "rotationCenterY": 0,
+            }],
+            "sounds": [],
+            "volume": 100,
+            "layerOrder": if name == "Stage" { 0 } else { 1 },
+            "visible": true,
+            "x": 0,
+            "y": 0,
+            "size": 100,
+            "direction": 90,
+            "draggable": false,
+            "rotationStyle": "all around",
+        })
+    };
+    let project = json!({
+        "targets": [
+            target("Stage", "backdrop1", json!({})),
+            target("Sprite1", "costume1", Value::Object(script)),
+        ],
+        "monitors": [],
+        "extensions": [],
+        "meta": { "semver": "3.0.0", "vm": "0.2.0", "agent": "" },
+    });
+
+    let mut out = std::io::Cursor::new(vec![]);
+    let mut zip = zip::ZipWriter::new(&mut out);
+    zip.start_file("project.json", Default::default()).unwrap();
+    zip.write_all(project.to_string().as_bytes()).unwrap();
+    zip.finish().unwrap();
+    drop(zip);
+    out.into_inner()
+}
+
+fn block(opcode: &str, inputs: Value, fields: Value) -> Value {
+    json!({ "opcode": opcode, "inputs": inputs, "fields": fields })
+}
+
+fn flag() -> Value {
+    block("event_whenflagclicked", json!({}), json!({}))
+}
+
+/// Why `blocks` don't compile to WebAssembly with `options`.
+fn rejected(blocks: &[Value], options: scratchc::Options) -> String {
+    scratchc::compile(
+        std::io::Cursor::new(project(blocks)),
+        &scratchc::Options {
+            target: scratchc::Target::Wasm,
+            ..options
+        },
+    )
+    .unwrap_err()
+}
+
+#[test]
+fn key_hat() {
+    assert_eq!(
+        rejected(
+            &[block(
+                "event_whenkeypressed",
+                json!({}),
+                json!({ "KEY_OPTION": ["space", null] })
+            )],
+            Default::default()
+        ),
+        "Sprite1: when key \"space\" pressed: the WebAssembly backend doesn't support `when key pressed` hats yet"
+    );
+}
+
+#[test]
+fn backdrop_hat() {
+    assert_eq!(
+        rejected(
+            &[block(
+                "event_whenbackdropswitchesto",
+                json!({}),
+                json!({ "BACKDROP": ["backdrop1", null] })
+            )],
+            Default::default()
+        ),
+        "Sprite1: when backdrop switches to \"backdrop1\": the WebAssembly backend doesn't support `when backdrop switches to` hats yet"
+    );
+}
+
+#[test]
+fn looks() {
+    assert_eq!(
+        rejected(
+            &[flag(), block("looks_nextcostume", json!({}), json!({}))],
+            Default::default()
+        ),
+        "Sprite1: `next costume` needs `next_costume`, which the WebAssembly backend doesn't support yet"
+    );
+}
+
+#[test]
+fn sound() {
+    assert_eq!(
+        rejected(
+            &[
+                flag(),
+                block(
+                    "sound_changevolumeby",
+                    json!({ "VOLUME": [1, [4, "10"]] }),
+                    json!({})
+                )
+            ],
+            Default::default()
+        ),
+        "Sprite1: `change volume by (10)` needs `set_volume`, which the WebAssembly backend doesn't support yet"
+    );
+}
+
+#[test]
+fn motion() {
+    assert_eq!(
+        rejected(
+            &[
+                flag(),
+                block(
+                    "motion_movesteps",
+                    json!({ "STEPS": [1, [4, "10"]] }),
+                    json!({})
+                )
+            ],
+            Default::default()
+        ),
+        "Sprite1: `move (10) steps` needs `move_steps`, which the WebAssembly backend doesn't support yet"
+    );
+}
+
+#[test]
+fn profile() {
+    assert_eq!(
+        rejected(
+            &[flag()],
+            scratchc::Options {
+                profile: true,
+                ..Default::default()
+            }
+        ),
+        "the WebAssembly backend doesn't support profiling yet"
+    );
+}
+
+#[test]
+fn debug_info() {
+    assert_eq!(
+        rejected(
+            &[flag()],
+            scratchc::Options {
+                debug_info: Some("project.sb3".into()),
+                ..Default::default()
+            }
+        ),
+        "the WebAssembly backend doesn't support debug info yet"
+    );
+}
+
+#[test]
+fn clif() {
+    assert_eq!(
+        rejected(
+            &[flag()],
+            scratchc::Options {
+                emit: scratchc::Emit::Clif,
+                ..Default::default()
+            }
+        ),
+        "WebAssembly isn't compiled with Cranelift, use --emit=asm"
+    );
+}
+
+struct Wasi {
+    env: Vec<String>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    start: Instant,
+}
+
+fn memory<'a>(caller: &'a mut Caller<Wasi>) -> (&'a mut [u8], &'a mut Wasi) {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .unwrap();
+    memory.data_and_store_mut(caller)
+}
+
+fn u32_at(memory: &[u8], address: i32) -> u32 {
+    let address = address as usize;
+    u32::from_le_bytes(memory[address..address + 4].try_into().unwrap())
+}
+
+fn run(name: &str, trace: bool) {
+    let test = std::path::PathBuf::from(format!("tests/out/{}.sb3", name));
+    let file = std::fs::File::open(&test).unwrap();
+    let wasm = scratchc::compile(
+        file,
+        &scratchc::Options {
+            target: scratchc::Target::Wasm,
+            trace,
+            // recursion_limit relies on it.
+            guard: Some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut env = test.clone();
+    env.set_extension("env");
+    let env = match std::fs::read_to_string(&env) {
+        Ok(env) => env.lines().map(|line| format!("{}\0", line)).collect(),
+        Err(_) => vec![],
+    };
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm[..]).unwrap();
+    let mut store = Store::new(
+        &engine,
+        Wasi {
+            env,
+            stdout: vec![],
+            stderr: vec![],
+            start: Instant::now(),
+        },
+    );
+    let mut linker = <Linker<Wasi>>::new(&engine);
+    let wasi = "wasi_snapshot_preview1";
+    linker
+        .func_wrap(
+            wasi,
+            "fd_write",
+            |mut caller: Caller<Wasi>, fd: i32, iovs: i32, len: i32, written: i32| {
+                let (memory, wasi) = memory(&mut caller);
+                let mut total = 0;
+                for i in 0..len {
+                    let iov = iovs + 8 * i;
+                    let start = u32_at(memory, iov) as usize;
+                    let end = start + u32_at(memory, iov + 4) as usize;
+                    let out = if fd == 1 {
+                        &mut wasi.stdout
+                    } else {
+                        &mut wasi.stderr
+                    };
+                    out.extend_from_slice(&memory[start..end]);
+                    total += (end - start) as u32;
+                }
+                let written = written as usize;
+                memory[written..written + 4].copy_from_slice(&total.to_le_bytes());
+                0
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            wasi,
+            "clock_time_get",
+            |mut caller: Caller<Wasi>, _clock: i32, _precision: i64, time: i32| {
+                let (memory, wasi) = memory(&mut caller);
+                let now = wasi.start.elapsed().as_nanos() as u64;
+                let time = time as usize;
+                memory[time..time + 8].copy_from_slice(&now.to_le_bytes());
+                0
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            wasi,
+            "poll_oneoff",
+            |mut caller: Caller<Wasi>, subscriptions: i32, _events: i32, _len: i32, count: i32| {
+                // The runtime only ever waits for one relative timeout.
+                let (memory, _) = memory(&mut caller);
+                let timeout = subscriptions as usize + 24;
+                let timeout = u64::from_le_bytes(memory[timeout..timeout + 8].try_into().unwrap());
+                std::thread::sleep(Duration::from_nanos(timeout));
+                let count = count as usize;
+                memory[count..count + 4].copy_from_slice(&1u32.to_le_bytes());
+                0
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            wasi,
+            "environ_sizes_get",
+            |mut caller: Caller<Wasi>, count: i32, size: i32| {
+                let (memory, wasi) = memory(&mut caller);
+                let total: usize = wasi.env.iter().map(String::len).sum();
+                let (count, size) = (count as usize, size as usize);
+                memory[count..count + 4].copy_from_slice(&(wasi.env.len() as u32).to_le_bytes());
+                memory[size..size + 4].copy_from_slice(&(total as u32).to_le_bytes());
+                0
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            wasi,
+            "environ_get",
+            |mut caller: Caller<Wasi>, pointers: i32, buffer: i32| {
+                let (memory, wasi) = memory(&mut caller);
+                let mut p = buffer as usize;
+                for (i, var) in wasi.env.iter().enumerate() {
+                    let pointer = pointers as usize + 4 * i;
+                    memory[pointer..pointer + 4].copy_from_slice(&(p as u32).to_le_bytes());
+                    memory[p..p + var.len()].copy_from_slice(var.as_bytes());
+                    p += var.len();
+                }
+                0
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            wasi,
+            "proc_exit",
+            |_: Caller<Wasi>, status: i32| -> Result<(), wasmi::Error> {
+                Err(wasmi::Error::i32_exit(status))
+            },
+        )
+        .unwrap();
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let start = instance.get_typed_func::<(), ()>(&store, "_start").unwrap();
+    let status = match start.call(&mut store, ()) {
+        Ok(()) => 0,
+        Err(e) => e.i32_exit_status().unwrap_or_else(|| panic!("{}", e)),
+    };
+
+    let mut err = test.clone();
+    err.set_extension("err");
+    if err.exists() {
+        assert_ne!(status, 0);
+        assert_eq!(
+            String::from_utf8(store.data().stderr.clone()).unwrap(),
+            std::fs::read_to_string(&err).unwrap()
+        );
+    } else if trace {
+        // The log goes to stderr.
+        assert_eq!(status, 0);
+        assert_eq!(
+            String::from_utf8(store.data().stderr.clone()).unwrap(),
+            std::fs::read_to_string(test.with_extension("trace")).unwrap()
+        );
+    } else {
+        assert_eq!(status, 0);
+    }
+
+    let mut out = test;
+    out.set_extension("out");
+    assert_eq!(
+        String::from_utf8(store.data().stdout.clone()).unwrap(),
+        std::fs::read_to_string(&out).unwrap()
+    );
+}